@@ -0,0 +1,103 @@
+//! The synth as a CLAP plugin. Build it with `cargo build --release -p shapes-clap` and
+//! copy `libshapes_clap.so` to `~/.clap/shapes.clap`
+
+mod params;
+mod plugin;
+mod ports;
+mod state;
+
+use clap_sys::entry::clap_plugin_entry;
+use clap_sys::factory::plugin_factory::{clap_plugin_factory, CLAP_PLUGIN_FACTORY_ID};
+use clap_sys::host::clap_host;
+use clap_sys::plugin::{clap_plugin, clap_plugin_descriptor};
+use clap_sys::plugin_features::{
+    CLAP_PLUGIN_FEATURE_INSTRUMENT, CLAP_PLUGIN_FEATURE_STEREO, CLAP_PLUGIN_FEATURE_SYNTHESIZER,
+};
+use clap_sys::version::CLAP_VERSION;
+use std::ffi::{c_void, CStr};
+use std::os::raw::c_char;
+use std::ptr;
+
+/// The descriptor needs a pointer to these, which can't be shared between threads by itself
+struct Features([*const c_char; 4]);
+unsafe impl Sync for Features {}
+
+static FEATURES: Features = Features([
+    CLAP_PLUGIN_FEATURE_INSTRUMENT.as_ptr(),
+    CLAP_PLUGIN_FEATURE_SYNTHESIZER.as_ptr(),
+    CLAP_PLUGIN_FEATURE_STEREO.as_ptr(),
+    ptr::null(),
+]);
+
+const fn c_str(s: &'static str) -> *const c_char {
+    s.as_ptr() as *const c_char
+}
+
+static DESCRIPTOR: clap_plugin_descriptor = clap_plugin_descriptor {
+    clap_version: CLAP_VERSION,
+    id: c_str("io.github.johnbotris.shapes\0"),
+    name: c_str("shapes\0"),
+    vendor: c_str("John Botris\0"),
+    url: c_str("https://github.com/johnbotris/shapes\0"),
+    manual_url: c_str("\0"),
+    support_url: c_str("\0"),
+    version: c_str(concat!(env!("CARGO_PKG_VERSION"), "\0")),
+    description: c_str("A synth made of shapes\0"),
+    features: &FEATURES.0 as *const *const c_char,
+};
+
+unsafe extern "C" fn init(_plugin_path: *const c_char) -> bool {
+    true
+}
+
+unsafe extern "C" fn deinit() {}
+
+unsafe extern "C" fn get_factory(factory_id: *const c_char) -> *const c_void {
+    if CStr::from_ptr(factory_id) == CLAP_PLUGIN_FACTORY_ID {
+        &FACTORY as *const clap_plugin_factory as *const c_void
+    } else {
+        ptr::null()
+    }
+}
+
+unsafe extern "C" fn get_plugin_count(_factory: *const clap_plugin_factory) -> u32 {
+    1
+}
+
+unsafe extern "C" fn get_plugin_descriptor(
+    _factory: *const clap_plugin_factory,
+    index: u32,
+) -> *const clap_plugin_descriptor {
+    if index == 0 {
+        &DESCRIPTOR
+    } else {
+        ptr::null()
+    }
+}
+
+unsafe extern "C" fn create_plugin(
+    _factory: *const clap_plugin_factory,
+    host: *const clap_host,
+    plugin_id: *const c_char,
+) -> *const clap_plugin {
+    if CStr::from_ptr(plugin_id) != CStr::from_ptr(DESCRIPTOR.id) {
+        return ptr::null();
+    }
+    plugin::Plugin::create(host, &DESCRIPTOR)
+}
+
+static FACTORY: clap_plugin_factory = clap_plugin_factory {
+    get_plugin_count: Some(get_plugin_count),
+    get_plugin_descriptor: Some(get_plugin_descriptor),
+    create_plugin: Some(create_plugin),
+};
+
+/// What the host looks for when it loads the library
+#[no_mangle]
+#[allow(non_upper_case_globals)]
+pub static clap_entry: clap_plugin_entry = clap_plugin_entry {
+    clap_version: CLAP_VERSION,
+    init: Some(init),
+    deinit: Some(deinit),
+    get_factory: Some(get_factory),
+};
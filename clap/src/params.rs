@@ -0,0 +1,138 @@
+use crate::plugin::{parameter, Plugin};
+
+use shapes::parameter::PARAMETERS;
+
+use clap_sys::events::{clap_input_events, clap_output_events};
+use clap_sys::ext::params::{clap_param_info, clap_plugin_params, CLAP_PARAM_IS_AUTOMATABLE};
+use clap_sys::id::clap_id;
+use clap_sys::plugin::clap_plugin;
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::ptr;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+
+/// Every parameter the standalone program has, between 0 and 1 like a MIDI controller
+pub static PARAMS: clap_plugin_params = clap_plugin_params {
+    count: Some(count),
+    get_info: Some(get_info),
+    get_value: Some(get_value),
+    value_to_text: Some(value_to_text),
+    text_to_value: Some(text_to_value),
+    flush: Some(flush),
+};
+
+/// As much of `s` as fits, always null terminated
+pub fn copy_str(dst: &mut [c_char], s: &str) {
+    let len = s.len().min(dst.len().saturating_sub(1));
+    for (dst, src) in dst.iter_mut().zip(&s.as_bytes()[..len]) {
+        *dst = *src as c_char;
+    }
+    if let Some(end) = dst.get_mut(len) {
+        *end = 0;
+    }
+}
+
+unsafe extern "C" fn count(_plugin: *const clap_plugin) -> u32 {
+    PARAMETERS.len() as u32
+}
+
+unsafe extern "C" fn get_info(
+    plugin: *const clap_plugin,
+    param_index: u32,
+    param_info: *mut clap_param_info,
+) -> bool {
+    let plugin = Plugin::from_clap(plugin);
+    let parameter = match PARAMETERS.get(param_index as usize) {
+        Some(parameter) => parameter,
+        None => return false,
+    };
+    let info = &mut *param_info;
+    info.id = parameter.id();
+    info.flags = CLAP_PARAM_IS_AUTOMATABLE;
+    info.cookie = ptr::null_mut();
+    copy_str(&mut info.name, parameter.name());
+    copy_str(&mut info.module, "");
+    info.min_value = 0.0;
+    info.max_value = 1.0;
+    info.default_value = plugin.defaults[param_index as usize] as f64;
+    true
+}
+
+unsafe extern "C" fn get_value(
+    plugin: *const clap_plugin,
+    param_id: clap_id,
+    out_value: *mut f64,
+) -> bool {
+    match parameter(param_id) {
+        Some(parameter) => {
+            *out_value = Plugin::from_clap(plugin).values[parameter.index()].load() as f64;
+            true
+        }
+        None => false,
+    }
+}
+
+/// In the parameter's own units, e.g. Hz for the cutoff, or the name of the choice
+unsafe extern "C" fn value_to_text(
+    _plugin: *const clap_plugin,
+    param_id: clap_id,
+    value: f64,
+    out_buffer: *mut c_char,
+    out_buffer_capacity: u32,
+) -> bool {
+    let parameter = match parameter(param_id) {
+        Some(parameter) => parameter,
+        None => return false,
+    };
+    let value = parameter.from_normalized(value as f32);
+    let text = match parameter.choices() {
+        Some(choices) => choices[value as usize].to_string(),
+        None if parameter.is_integer() => format!("{}", value),
+        None => format!("{:.3}", value),
+    };
+    let buffer = std::slice::from_raw_parts_mut(out_buffer, out_buffer_capacity as usize);
+    copy_str(buffer, &text);
+    true
+}
+
+unsafe extern "C" fn text_to_value(
+    _plugin: *const clap_plugin,
+    param_id: clap_id,
+    param_value_text: *const c_char,
+    out_value: *mut f64,
+) -> bool {
+    let parameter = match parameter(param_id) {
+        Some(parameter) => parameter,
+        None => return false,
+    };
+    let text = CStr::from_ptr(param_value_text).to_string_lossy();
+    let choice = parameter
+        .choices()
+        .and_then(|choices| choices.iter().position(|name| *name == text.trim()))
+        .map(|idx| idx as f32);
+    match choice.map(Ok).unwrap_or_else(|| f32::from_str(text.trim())) {
+        Ok(value) => {
+            *out_value = parameter.to_normalized(value) as f64;
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Parameter changes while we aren't processing. This can be on the audio thread so it never
+/// waits for the synth, if something else has it the next process picks the values up
+unsafe extern "C" fn flush(
+    plugin: *const clap_plugin,
+    in_events: *const clap_input_events,
+    _out_events: *const clap_output_events,
+) {
+    let plugin = Plugin::from_clap(plugin);
+    match plugin.synth.try_lock() {
+        Ok(mut synth) => plugin.handle_events(in_events, synth.as_mut()),
+        Err(_) => {
+            plugin.handle_events(in_events, None);
+            plugin.values_missed.store(true, Ordering::Relaxed);
+        }
+    }
+}
@@ -0,0 +1,295 @@
+use crate::{params, ports, state};
+
+use shapes::channel::{self, Receiver, Sender};
+use shapes::engine::{self, Config, Message, Synth};
+use shapes::parameter::{Parameter, PARAMETERS};
+use shapes::patch::Patch;
+use shapes::util::AtomicF32;
+
+use anyhow::Result;
+use clap_sys::events::*;
+use clap_sys::ext::audio_ports::CLAP_EXT_AUDIO_PORTS;
+use clap_sys::ext::note_ports::CLAP_EXT_NOTE_PORTS;
+use clap_sys::ext::params::{clap_host_params, CLAP_EXT_PARAMS, CLAP_PARAM_RESCAN_VALUES};
+use clap_sys::ext::state::CLAP_EXT_STATE;
+use clap_sys::host::clap_host;
+use clap_sys::plugin::{clap_plugin, clap_plugin_descriptor};
+use clap_sys::process::{clap_process, clap_process_status, CLAP_PROCESS_CONTINUE};
+use std::convert::TryFrom;
+use std::ffi::{c_void, CStr};
+use std::os::raw::c_char;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use wmidi::Note;
+
+pub struct Plugin {
+    /// What the host holds on to, its plugin_data points back here
+    clap: clap_plugin,
+    host: *const clap_host,
+    /// What the patch was made from, for everything that isn't a parameter
+    pub options: Mutex<Vec<String>>,
+    /// Every parameter between 0 and 1, as the host sees them
+    pub values: Vec<AtomicF32>,
+    pub defaults: Vec<f32>,
+    /// Only there while the host has us activated and we know the sample rate
+    pub synth: Mutex<Option<Synth>>,
+    /// Set when `values` changed without the synth hearing about it, process catches up
+    pub values_missed: AtomicBool,
+    /// For getting things from the main thread to the synth while it's playing
+    sender: Sender,
+    receiver: Receiver,
+}
+
+// The host promises not to call anything from the wrong thread
+unsafe impl Send for Plugin {}
+unsafe impl Sync for Plugin {}
+
+impl Plugin {
+    pub fn create(
+        host: *const clap_host,
+        descriptor: &'static clap_plugin_descriptor,
+    ) -> *const clap_plugin {
+        let patch = Patch::from_options(&[]).expect("The default patch should always work");
+        let defaults = PARAMETERS
+            .iter()
+            .map(|parameter| parameter.to_normalized(parameter.get(&patch)))
+            .collect::<Vec<f32>>();
+        let (sender, receiver) = channel::channel();
+        let plugin = Box::new(Self {
+            clap: clap_plugin {
+                desc: descriptor,
+                plugin_data: ptr::null_mut(),
+                init: Some(init),
+                destroy: Some(destroy),
+                activate: Some(activate),
+                deactivate: Some(deactivate),
+                start_processing: Some(start_processing),
+                stop_processing: Some(stop_processing),
+                reset: Some(reset),
+                process: Some(process),
+                get_extension: Some(get_extension),
+                on_main_thread: Some(on_main_thread),
+            },
+            host,
+            options: Mutex::new(Vec::new()),
+            values: defaults
+                .iter()
+                .map(|value| AtomicF32::new(*value))
+                .collect(),
+            defaults,
+            synth: Mutex::new(None),
+            values_missed: AtomicBool::new(false),
+            sender,
+            receiver,
+        });
+        let plugin = Box::into_raw(plugin);
+        unsafe {
+            (*plugin).clap.plugin_data = plugin as *mut c_void;
+            &(*plugin).clap
+        }
+    }
+
+    pub unsafe fn from_clap<'a>(plugin: *const clap_plugin) -> &'a Self {
+        &*((*plugin).plugin_data as *const Self)
+    }
+
+    /// The patch with the parameters where the host has them
+    pub fn patch(&self) -> Patch {
+        let mut patch = Patch::from_options(&self.options.lock().unwrap())
+            .expect("Options are checked when they're loaded");
+        for (parameter, value) in PARAMETERS.iter().zip(&self.values) {
+            parameter.apply(&mut patch, value.load());
+        }
+        patch
+    }
+
+    /// Swap in a new patch, whether or not we're playing
+    pub fn load(&self, options: Vec<String>) -> Result<()> {
+        let patch = Patch::from_options(&options)?;
+        for (parameter, value) in PARAMETERS.iter().zip(&self.values) {
+            value.store(parameter.to_normalized(parameter.get(&patch)));
+        }
+        *self.options.lock().unwrap() = options;
+        // Nothing's listening if we aren't activated, activate makes a synth with it anyway
+        let _ = self.sender.send(Message::load_patch(patch));
+        self.rescan_values();
+        Ok(())
+    }
+
+    /// Let the host know the parameters all changed
+    fn rescan_values(&self) {
+        unsafe {
+            let get_extension = match (*self.host).get_extension {
+                Some(get_extension) => get_extension,
+                None => return,
+            };
+            let params =
+                get_extension(self.host, CLAP_EXT_PARAMS.as_ptr()) as *const clap_host_params;
+            if let Some(rescan) = params.as_ref().and_then(|params| params.rescan) {
+                rescan(self.host, CLAP_PARAM_RESCAN_VALUES);
+            }
+        }
+    }
+
+    /// Parameter changes, with or without a synth to send them to
+    pub unsafe fn handle_events(
+        &self,
+        events: *const clap_input_events,
+        synth: Option<&mut Synth>,
+    ) {
+        let events = match events.as_ref() {
+            Some(events) => events,
+            None => return,
+        };
+        let (size, get) = match (events.size, events.get) {
+            (Some(size), Some(get)) => (size, get),
+            _ => return,
+        };
+        let mut synth = synth;
+        for idx in 0..size(events) {
+            let header = get(events, idx);
+            if header.is_null() || (*header).space_id != CLAP_CORE_EVENT_SPACE_ID {
+                continue;
+            }
+            let message = match (*header).type_ {
+                CLAP_EVENT_PARAM_VALUE => {
+                    let event = &*(header as *const clap_event_param_value);
+                    parameter(event.param_id).map(|parameter| {
+                        self.values[parameter.index()].store(event.value as f32);
+                        Message::SetParameter(parameter, event.value as f32)
+                    })
+                }
+                CLAP_EVENT_NOTE_ON => {
+                    note(header).map(|(note, velocity)| Message::NoteOn(note, velocity))
+                }
+                CLAP_EVENT_NOTE_OFF => note(header).map(|(note, _)| Message::NoteOff(note)),
+                // Every note when there isn't one given
+                CLAP_EVENT_NOTE_CHOKE => Some(
+                    note(header)
+                        .map(|(note, _)| Message::NoteOff(note))
+                        .unwrap_or(Message::AllSoundOff),
+                ),
+                CLAP_EVENT_MIDI => engine::plugin_midi(&(*(header as *const clap_event_midi)).data),
+                _ => None,
+            };
+            // None of these need freeing, so nothing comes back
+            if let (Some(message), Some(synth)) = (message, synth.as_mut()) {
+                synth.handle_event(message, (*header).time as usize);
+            }
+        }
+    }
+}
+
+unsafe fn note(header: *const clap_event_header) -> Option<(Note, f32)> {
+    let event = &*(header as *const clap_event_note);
+    // -1 is a wildcard for every key
+    let note = u8::try_from(event.key)
+        .ok()
+        .and_then(|key| Note::try_from(key).ok())?;
+    Some((note, event.velocity as f32))
+}
+
+unsafe extern "C" fn init(_plugin: *const clap_plugin) -> bool {
+    true
+}
+
+unsafe extern "C" fn destroy(plugin: *const clap_plugin) {
+    drop(Box::from_raw((*plugin).plugin_data as *mut Plugin));
+}
+
+unsafe extern "C" fn activate(
+    plugin: *const clap_plugin,
+    sample_rate: f64,
+    _min_frames_count: u32,
+    _max_frames_count: u32,
+) -> bool {
+    let plugin = Plugin::from_clap(plugin);
+    // Anything waiting is already in the patch
+    plugin.receiver.receive(|_| None);
+    *plugin.synth.lock().unwrap() = Some(Synth::new(Config {
+        sample_rate: sample_rate as u32,
+        voices: 0,
+        patch: plugin.patch(),
+    }));
+    true
+}
+
+unsafe extern "C" fn deactivate(plugin: *const clap_plugin) {
+    *Plugin::from_clap(plugin).synth.lock().unwrap() = None;
+}
+
+unsafe extern "C" fn start_processing(_plugin: *const clap_plugin) -> bool {
+    true
+}
+
+unsafe extern "C" fn stop_processing(_plugin: *const clap_plugin) {}
+
+unsafe extern "C" fn reset(plugin: *const clap_plugin) {
+    if let Ok(mut synth) = Plugin::from_clap(plugin).synth.try_lock() {
+        if let Some(synth) = synth.as_mut() {
+            synth.handle_event(Message::Reset, 0);
+        }
+    }
+}
+
+unsafe extern "C" fn process(
+    plugin: *const clap_plugin,
+    process: *const clap_process,
+) -> clap_process_status {
+    let plugin = Plugin::from_clap(plugin);
+    let process = &*process;
+    // Only activate and deactivate take these, and never while we're processing
+    let mut synth = match plugin.synth.try_lock() {
+        Ok(synth) => synth,
+        Err(_) => return CLAP_PROCESS_CONTINUE,
+    };
+    let synth = match synth.as_mut() {
+        Some(synth) => synth,
+        None => return CLAP_PROCESS_CONTINUE,
+    };
+    plugin
+        .receiver
+        .receive(|message| synth.handle_event(message, 0));
+    if plugin.values_missed.swap(false, Ordering::Relaxed) {
+        for (parameter, value) in PARAMETERS.iter().zip(&plugin.values) {
+            synth.handle_event(Message::SetParameter(*parameter, value.load()), 0);
+        }
+    }
+    plugin.handle_events(process.in_events, Some(&mut *synth));
+
+    let frames = process.frames_count as usize;
+    let outputs = match process.audio_outputs.as_ref() {
+        Some(outputs) if process.audio_outputs_count > 0 && outputs.channel_count >= 2 => outputs,
+        _ => return CLAP_PROCESS_CONTINUE,
+    };
+    let left = std::slice::from_raw_parts_mut(*outputs.data32, frames);
+    let right = std::slice::from_raw_parts_mut(*outputs.data32.add(1), frames);
+    synth.process_planar(left, right);
+    CLAP_PROCESS_CONTINUE
+}
+
+unsafe extern "C" fn get_extension(
+    _plugin: *const clap_plugin,
+    id: *const c_char,
+) -> *const c_void {
+    let id = CStr::from_ptr(id);
+    if id == CLAP_EXT_PARAMS {
+        &params::PARAMS as *const _ as *const c_void
+    } else if id == CLAP_EXT_STATE {
+        &state::STATE as *const _ as *const c_void
+    } else if id == CLAP_EXT_AUDIO_PORTS {
+        &ports::AUDIO_PORTS as *const _ as *const c_void
+    } else if id == CLAP_EXT_NOTE_PORTS {
+        &ports::NOTE_PORTS as *const _ as *const c_void
+    } else {
+        ptr::null()
+    }
+}
+
+unsafe extern "C" fn on_main_thread(_plugin: *const clap_plugin) {}
+
+/// Where a parameter's id points, see `Parameter::id`
+pub fn parameter(id: u32) -> Option<Parameter> {
+    Parameter::from_id(id)
+}
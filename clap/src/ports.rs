@@ -0,0 +1,74 @@
+use crate::params::copy_str;
+
+use clap_sys::ext::audio_ports::{
+    clap_audio_port_info, clap_plugin_audio_ports, CLAP_AUDIO_PORT_IS_MAIN, CLAP_PORT_STEREO,
+};
+use clap_sys::ext::note_ports::{
+    clap_note_port_info, clap_plugin_note_ports, CLAP_NOTE_DIALECT_CLAP, CLAP_NOTE_DIALECT_MIDI,
+};
+use clap_sys::id::CLAP_INVALID_ID;
+use clap_sys::plugin::clap_plugin;
+
+/// One stereo output and nothing coming in
+pub static AUDIO_PORTS: clap_plugin_audio_ports = clap_plugin_audio_ports {
+    count: Some(audio_port_count),
+    get: Some(audio_port_get),
+};
+
+/// Notes in, as either CLAP note events or MIDI
+pub static NOTE_PORTS: clap_plugin_note_ports = clap_plugin_note_ports {
+    count: Some(note_port_count),
+    get: Some(note_port_get),
+};
+
+unsafe extern "C" fn audio_port_count(_plugin: *const clap_plugin, is_input: bool) -> u32 {
+    if is_input {
+        0
+    } else {
+        1
+    }
+}
+
+unsafe extern "C" fn audio_port_get(
+    _plugin: *const clap_plugin,
+    index: u32,
+    is_input: bool,
+    info: *mut clap_audio_port_info,
+) -> bool {
+    if is_input || index != 0 {
+        return false;
+    }
+    let info = &mut *info;
+    info.id = 0;
+    copy_str(&mut info.name, "Output");
+    info.flags = CLAP_AUDIO_PORT_IS_MAIN;
+    info.channel_count = 2;
+    info.port_type = CLAP_PORT_STEREO.as_ptr();
+    info.in_place_pair = CLAP_INVALID_ID;
+    true
+}
+
+unsafe extern "C" fn note_port_count(_plugin: *const clap_plugin, is_input: bool) -> u32 {
+    if is_input {
+        1
+    } else {
+        0
+    }
+}
+
+unsafe extern "C" fn note_port_get(
+    _plugin: *const clap_plugin,
+    index: u32,
+    is_input: bool,
+    info: *mut clap_note_port_info,
+) -> bool {
+    if !is_input || index != 0 {
+        return false;
+    }
+    let info = &mut *info;
+    info.id = 0;
+    info.supported_dialects = CLAP_NOTE_DIALECT_CLAP | CLAP_NOTE_DIALECT_MIDI;
+    info.preferred_dialect = CLAP_NOTE_DIALECT_CLAP;
+    copy_str(&mut info.name, "Notes");
+    true
+}
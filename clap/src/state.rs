@@ -0,0 +1,60 @@
+use crate::plugin::Plugin;
+
+use shapes::patch;
+
+use clap_sys::ext::state::clap_plugin_state;
+use clap_sys::plugin::clap_plugin;
+use clap_sys::stream::{clap_istream, clap_ostream};
+use std::ffi::c_void;
+
+/// Saved as a patch file, so it can be loaded into the standalone program too
+pub static STATE: clap_plugin_state = clap_plugin_state {
+    save: Some(save),
+    load: Some(load),
+};
+
+unsafe extern "C" fn save(plugin: *const clap_plugin, stream: *const clap_ostream) -> bool {
+    let plugin = Plugin::from_clap(plugin);
+    let contents = plugin.patch().contents(&plugin.options.lock().unwrap());
+    let write = match (*stream).write {
+        Some(write) => write,
+        None => return false,
+    };
+
+    // The host can take less than all of it at once
+    let mut bytes = contents.as_bytes();
+    while !bytes.is_empty() {
+        let written = write(stream, bytes.as_ptr() as *const c_void, bytes.len() as u64);
+        if written <= 0 {
+            return false;
+        }
+        bytes = &bytes[written as usize..];
+    }
+    true
+}
+
+unsafe extern "C" fn load(plugin: *const clap_plugin, stream: *const clap_istream) -> bool {
+    let plugin = Plugin::from_clap(plugin);
+    let read = match (*stream).read {
+        Some(read) => read,
+        None => return false,
+    };
+
+    let mut contents = Vec::new();
+    let mut buffer = [0u8; 4096];
+    loop {
+        let count = read(
+            stream,
+            buffer.as_mut_ptr() as *mut c_void,
+            buffer.len() as u64,
+        );
+        match count {
+            0 => break,
+            count if count < 0 => return false,
+            count => contents.extend_from_slice(&buffer[..count as usize]),
+        }
+    }
+
+    let contents = String::from_utf8_lossy(&contents);
+    plugin.load(patch::parse_options(&contents)).is_ok()
+}
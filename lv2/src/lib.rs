@@ -0,0 +1,268 @@
+//! The synth as an LV2 plugin. Build it with `cargo build --release -p shapes-lv2`, copy
+//! `libshapes_lv2.so` into `shapes.lv2` and the bundle to `~/.lv2`
+
+mod sys;
+
+use shapes::engine::{self, Config, Message, Synth};
+use shapes::parameter::PARAMETERS;
+use shapes::patch::{self, Patch};
+
+use std::ffi::{c_void, CStr};
+use std::os::raw::c_char;
+use std::{mem, ptr, slice};
+use sys::*;
+
+const URI: &[u8] = b"https://github.com/johnbotris/shapes\0";
+/// Where the state keeps the patch
+const PATCH_KEY: &[u8] = b"https://github.com/johnbotris/shapes#patch\0";
+
+const MIDI_PORT: u32 = 0;
+const LEFT_PORT: u32 = 1;
+const RIGHT_PORT: u32 = 2;
+/// The rest are control ports, one for each parameter in the same order
+const FIRST_CONTROL_PORT: u32 = 3;
+
+struct Plugin {
+    synth: Synth,
+    midi_event: Urid,
+    atom_string: Urid,
+    patch_key: Urid,
+    midi_in: *const AtomSequence,
+    left: *mut f32,
+    right: *mut f32,
+    controls: Vec<*const f32>,
+    /// Where the control ports were last time, so the synth only hears about changes
+    values: Vec<f32>,
+    /// What the patch was made from, for everything that isn't a control port
+    options: Vec<String>,
+}
+
+impl Plugin {
+    /// The patch with the parameters where the control ports have them
+    fn patch(&self) -> Patch {
+        let mut patch =
+            Patch::from_options(&self.options).expect("Options are checked when they're loaded");
+        for (parameter, value) in PARAMETERS.iter().zip(&self.values) {
+            parameter.set(&mut patch, *value);
+        }
+        patch
+    }
+
+    unsafe fn read_midi(&mut self) {
+        let sequence = match self.midi_in.as_ref() {
+            Some(sequence) => sequence,
+            None => return,
+        };
+        // The size counts from the end of the atom header, events start after the unit and pad
+        let start =
+            (sequence as *const AtomSequence as *const u8).add(mem::size_of::<AtomSequence>());
+        let end = (sequence as *const AtomSequence as *const u8)
+            .add(mem::size_of::<Atom>() + sequence.atom.size as usize);
+        let mut event = start;
+        while event < end {
+            let header = &*(event as *const AtomEvent);
+            let body = event.add(mem::size_of::<AtomEvent>());
+            if header.body.type_ == self.midi_event {
+                let data = slice::from_raw_parts(body, header.body.size as usize);
+                if let Some(message) = engine::plugin_midi(data) {
+                    self.synth
+                        .handle_event(message, header.frames.max(0) as usize);
+                }
+            }
+            event = body.add(pad_size(header.body.size));
+        }
+    }
+
+    unsafe fn read_controls(&mut self) {
+        for (idx, control) in self.controls.iter().enumerate() {
+            let value = match control.as_ref() {
+                Some(value) => *value,
+                None => continue,
+            };
+            if value != self.values[idx] {
+                self.values[idx] = value;
+                let parameter = PARAMETERS[idx];
+                self.synth.handle_event(
+                    Message::SetParameter(parameter, parameter.to_normalized(value)),
+                    0,
+                );
+            }
+        }
+    }
+}
+
+unsafe fn feature(features: *const *const Feature, uri: &[u8]) -> Option<*mut c_void> {
+    if features.is_null() {
+        return None;
+    }
+    let uri = CStr::from_bytes_with_nul_unchecked(uri);
+    let mut idx = 0;
+    while let Some(feature) = (*features.add(idx)).as_ref() {
+        if CStr::from_ptr(feature.uri) == uri {
+            return Some(feature.data);
+        }
+        idx += 1;
+    }
+    None
+}
+
+unsafe fn map(map: &UridMap, uri: &[u8]) -> Urid {
+    (map.map)(map.handle, uri.as_ptr() as *const c_char)
+}
+
+unsafe extern "C" fn instantiate(
+    _descriptor: *const Descriptor,
+    sample_rate: f64,
+    _bundle_path: *const c_char,
+    features: *const *const Feature,
+) -> Handle {
+    let urid_map =
+        match feature(features, URID_MAP).and_then(|map| (map as *const UridMap).as_ref()) {
+            Some(urid_map) => urid_map,
+            None => return ptr::null_mut(),
+        };
+    let patch = Patch::from_options(&[]).expect("The default patch should always work");
+    let values = PARAMETERS
+        .iter()
+        .map(|parameter| parameter.get(&patch))
+        .collect();
+    let plugin = Box::new(Plugin {
+        synth: Synth::new(Config {
+            sample_rate: sample_rate as u32,
+            voices: 0,
+            patch,
+        }),
+        midi_event: map(urid_map, MIDI_EVENT),
+        atom_string: map(urid_map, ATOM_STRING),
+        patch_key: map(urid_map, PATCH_KEY),
+        midi_in: ptr::null(),
+        left: ptr::null_mut(),
+        right: ptr::null_mut(),
+        controls: vec![ptr::null(); PARAMETERS.len()],
+        values,
+        options: Vec::new(),
+    });
+    Box::into_raw(plugin) as Handle
+}
+
+unsafe extern "C" fn connect_port(instance: Handle, port: u32, data: *mut c_void) {
+    let plugin = &mut *(instance as *mut Plugin);
+    match port {
+        MIDI_PORT => plugin.midi_in = data as *const AtomSequence,
+        LEFT_PORT => plugin.left = data as *mut f32,
+        RIGHT_PORT => plugin.right = data as *mut f32,
+        port => {
+            if let Some(control) = plugin
+                .controls
+                .get_mut((port - FIRST_CONTROL_PORT) as usize)
+            {
+                *control = data as *const f32;
+            }
+        }
+    }
+}
+
+unsafe extern "C" fn activate(instance: Handle) {
+    let plugin = &mut *(instance as *mut Plugin);
+    plugin.synth.handle_event(Message::Reset, 0);
+}
+
+unsafe extern "C" fn run(instance: Handle, sample_count: u32) {
+    let plugin = &mut *(instance as *mut Plugin);
+    if plugin.left.is_null() || plugin.right.is_null() {
+        return;
+    }
+    plugin.read_controls();
+    plugin.read_midi();
+    let left = slice::from_raw_parts_mut(plugin.left, sample_count as usize);
+    let right = slice::from_raw_parts_mut(plugin.right, sample_count as usize);
+    plugin.synth.process_planar(left, right);
+}
+
+unsafe extern "C" fn cleanup(instance: Handle) {
+    drop(Box::from_raw(instance as *mut Plugin));
+}
+
+/// Saved as a patch file, so it can be loaded into the standalone program too
+unsafe extern "C" fn save(
+    instance: Handle,
+    store: StoreFunction,
+    handle: *mut c_void,
+    _flags: u32,
+    _features: *const *const Feature,
+) -> StateStatus {
+    let plugin = &*(instance as *const Plugin);
+    let mut contents = plugin.patch().contents(&plugin.options).into_bytes();
+    contents.push(0);
+    store(
+        handle,
+        plugin.patch_key,
+        contents.as_ptr() as *const c_void,
+        contents.len(),
+        plugin.atom_string,
+        STATE_IS_POD | STATE_IS_PORTABLE,
+    )
+}
+
+unsafe extern "C" fn restore(
+    instance: Handle,
+    retrieve: RetrieveFunction,
+    handle: *mut c_void,
+    _flags: u32,
+    _features: *const *const Feature,
+) -> StateStatus {
+    let plugin = &mut *(instance as *mut Plugin);
+    let mut size = 0;
+    let mut type_ = 0;
+    let mut flags = 0;
+    let value = retrieve(handle, plugin.patch_key, &mut size, &mut type_, &mut flags);
+    if value.is_null() || type_ != plugin.atom_string {
+        return STATE_ERR_NO_PROPERTY;
+    }
+    let contents = slice::from_raw_parts(value as *const u8, size);
+    let contents = String::from_utf8_lossy(contents);
+    let options = patch::parse_options(contents.trim_end_matches('\0'));
+    let patch = match Patch::from_options(&options) {
+        Ok(patch) => patch,
+        Err(_) => return STATE_ERR_UNKNOWN,
+    };
+    // The host puts the control ports back too, anything different from the patch wins
+    for (parameter, value) in PARAMETERS.iter().zip(plugin.values.iter_mut()) {
+        *value = parameter.get(&patch);
+    }
+    plugin.options = options;
+    // The host never restores while we're running, so the old patch can be dropped here
+    plugin.synth.handle_event(Message::load_patch(patch), 0);
+    STATE_SUCCESS
+}
+
+static STATE: StateInterface = StateInterface { save, restore };
+
+unsafe extern "C" fn extension_data(uri: *const c_char) -> *const c_void {
+    if CStr::from_ptr(uri).to_bytes_with_nul() == STATE_INTERFACE {
+        &STATE as *const StateInterface as *const c_void
+    } else {
+        ptr::null()
+    }
+}
+
+static DESCRIPTOR: Descriptor = Descriptor {
+    uri: URI.as_ptr() as *const c_char,
+    instantiate,
+    connect_port,
+    activate: Some(activate),
+    run,
+    deactivate: None,
+    cleanup,
+    extension_data,
+};
+
+/// What the host looks for when it loads the library
+#[no_mangle]
+pub extern "C" fn lv2_descriptor(index: u32) -> *const Descriptor {
+    if index == 0 {
+        &DESCRIPTOR
+    } else {
+        ptr::null()
+    }
+}
@@ -0,0 +1,111 @@
+//! The bits of the LV2 headers we use
+
+use std::ffi::c_void;
+use std::os::raw::c_char;
+
+pub type Handle = *mut c_void;
+pub type Urid = u32;
+
+pub const URID_MAP: &[u8] = b"http://lv2plug.in/ns/ext/urid#map\0";
+pub const MIDI_EVENT: &[u8] = b"http://lv2plug.in/ns/ext/midi#MidiEvent\0";
+pub const ATOM_STRING: &[u8] = b"http://lv2plug.in/ns/ext/atom#String\0";
+pub const STATE_INTERFACE: &[u8] = b"http://lv2plug.in/ns/ext/state#interface\0";
+
+#[repr(C)]
+pub struct Descriptor {
+    pub uri: *const c_char,
+    pub instantiate: unsafe extern "C" fn(
+        descriptor: *const Descriptor,
+        sample_rate: f64,
+        bundle_path: *const c_char,
+        features: *const *const Feature,
+    ) -> Handle,
+    pub connect_port: unsafe extern "C" fn(instance: Handle, port: u32, data: *mut c_void),
+    pub activate: Option<unsafe extern "C" fn(instance: Handle)>,
+    pub run: unsafe extern "C" fn(instance: Handle, sample_count: u32),
+    pub deactivate: Option<unsafe extern "C" fn(instance: Handle)>,
+    pub cleanup: unsafe extern "C" fn(instance: Handle),
+    pub extension_data: unsafe extern "C" fn(uri: *const c_char) -> *const c_void,
+}
+
+unsafe impl Sync for Descriptor {}
+
+#[repr(C)]
+pub struct Feature {
+    pub uri: *const c_char,
+    pub data: *mut c_void,
+}
+
+#[repr(C)]
+pub struct UridMap {
+    pub handle: *mut c_void,
+    pub map: unsafe extern "C" fn(handle: *mut c_void, uri: *const c_char) -> Urid,
+}
+
+#[repr(C)]
+pub struct Atom {
+    pub size: u32,
+    pub type_: u32,
+}
+
+#[repr(C)]
+pub struct AtomSequence {
+    pub atom: Atom,
+    pub unit: u32,
+    pub pad: u32,
+}
+
+/// Followed by `body.size` bytes of whatever the event is
+#[repr(C)]
+pub struct AtomEvent {
+    pub frames: i64,
+    pub body: Atom,
+}
+
+pub type StateStatus = u32;
+pub const STATE_SUCCESS: StateStatus = 0;
+pub const STATE_ERR_UNKNOWN: StateStatus = 1;
+pub const STATE_ERR_NO_PROPERTY: StateStatus = 5;
+
+pub const STATE_IS_POD: u32 = 1;
+pub const STATE_IS_PORTABLE: u32 = 2;
+
+pub type StoreFunction = unsafe extern "C" fn(
+    handle: *mut c_void,
+    key: Urid,
+    value: *const c_void,
+    size: usize,
+    type_: Urid,
+    flags: u32,
+) -> StateStatus;
+
+pub type RetrieveFunction = unsafe extern "C" fn(
+    handle: *mut c_void,
+    key: Urid,
+    size: *mut usize,
+    type_: *mut Urid,
+    flags: *mut u32,
+) -> *const c_void;
+
+#[repr(C)]
+pub struct StateInterface {
+    pub save: unsafe extern "C" fn(
+        instance: Handle,
+        store: StoreFunction,
+        handle: *mut c_void,
+        flags: u32,
+        features: *const *const Feature,
+    ) -> StateStatus,
+    pub restore: unsafe extern "C" fn(
+        instance: Handle,
+        retrieve: RetrieveFunction,
+        handle: *mut c_void,
+        flags: u32,
+        features: *const *const Feature,
+    ) -> StateStatus,
+}
+
+/// Atoms are padded out to 8 bytes
+pub fn pad_size(size: u32) -> usize {
+    (size as usize + 7) & !7
+}
@@ -0,0 +1,390 @@
+use crate::engine::Message;
+use crate::transport::CLOCKS_PER_BEAT;
+use crate::util::Rng;
+
+use anyhow::{anyhow, Result};
+use std::convert::TryFrom;
+use std::str::FromStr;
+use wmidi::Note;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArpMode {
+    Up,
+    Down,
+    UpDown,
+    Random,
+    AsPlayed,
+}
+
+impl FromStr for ArpMode {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<ArpMode> {
+        match s.to_lowercase().as_str() {
+            "up" => Ok(ArpMode::Up),
+            "down" => Ok(ArpMode::Down),
+            "updown" | "up-down" => Ok(ArpMode::UpDown),
+            "random" => Ok(ArpMode::Random),
+            "played" | "as-played" => Ok(ArpMode::AsPlayed),
+            _ => Err(anyhow!("Invalid value \"{}\" for ArpMode", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArpSync {
+    /// Follow the transport tempo
+    Internal,
+    /// Step on incoming MIDI clock ticks
+    MidiClock,
+}
+
+impl FromStr for ArpSync {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<ArpSync> {
+        match s.to_lowercase().as_str() {
+            "internal" => Ok(ArpSync::Internal),
+            "midi" | "clock" => Ok(ArpSync::MidiClock),
+            _ => Err(anyhow!("Invalid value \"{}\" for ArpSync", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ArpParams {
+    /// None when the arpeggiator is off
+    pub mode: Option<ArpMode>,
+    pub octaves: u8,
+    /// Fraction of a step the note is held for
+    pub gate: f32,
+    /// Length of a step in beats
+    pub rate: f32,
+    /// Keep playing after the keys are released, until a new chord is played
+    pub latch: bool,
+    pub sync: ArpSync,
+}
+
+pub struct Arpeggiator {
+    /// In the order they were played
+    held: Vec<(Note, f32)>,
+    /// How many of the held notes are still physically held down
+    pressed: usize,
+    /// Scratch space for the notes of the current pattern
+    pattern: Vec<(Note, f32)>,
+    step: usize,
+    /// Samples or clock ticks since the last step, depending on the sync
+    elapsed: f32,
+    /// Play the next step on the next tick instead of waiting for it, for starting a chord
+    pending: bool,
+    playing: Option<Note>,
+    clock_ticks: u32,
+}
+
+impl Arpeggiator {
+    pub fn new() -> Self {
+        Self {
+            held: Vec::with_capacity(128),
+            pressed: 0,
+            pattern: Vec::with_capacity(128 * 10),
+            step: 0,
+            elapsed: 0.0,
+            pending: false,
+            playing: None,
+            clock_ticks: 0,
+        }
+    }
+
+    pub fn note_on(&mut self, params: &ArpParams, note: Note, level: f32) {
+        if self.pressed == 0 {
+            if params.latch {
+                // Starting a new chord
+                self.held.clear();
+            }
+            if self.held.is_empty() {
+                // Play the first step right away
+                self.step = 0;
+                self.pending = true;
+            }
+        }
+        if !self.held.iter().any(|(n, _)| *n == note) && self.held.len() < self.held.capacity() {
+            self.held.push((note, level));
+        }
+        self.pressed += 1;
+    }
+
+    pub fn note_off(&mut self, params: &ArpParams, note: Note) {
+        self.pressed = self.pressed.saturating_sub(1);
+        if !params.latch {
+            self.held.retain(|(n, _)| *n != note);
+        }
+    }
+
+    /// Forget everything that's held, the note currently playing gets released on the next tick
+    pub fn clear(&mut self) {
+        self.held.clear();
+        self.pressed = 0;
+        self.pending = false;
+    }
+
+    pub fn clock(&mut self) {
+        self.clock_ticks += 1;
+    }
+
+    /// Advance by one sample, sending any notes that start or end
+    pub fn tick(
+        &mut self,
+        params: &ArpParams,
+        tempo: f32,
+        samplerate: f32,
+        rng: &mut Rng,
+        mut send: impl FnMut(Message),
+    ) {
+        let (step_length, advance) = match params.sync {
+            ArpSync::Internal => (params.rate * 60.0 / tempo * samplerate, 1.0),
+            ArpSync::MidiClock => (
+                params.rate * CLOCKS_PER_BEAT as f32,
+                self.clock_ticks as f32,
+            ),
+        };
+        self.clock_ticks = 0;
+        self.elapsed += advance;
+
+        if let Some(note) = self.playing {
+            if self.pending
+                || self.elapsed >= step_length * params.gate.clamp(0.0, 1.0)
+                || self.held.is_empty()
+            {
+                send(Message::NoteOff(note));
+                self.playing = None;
+            }
+        }
+
+        if self.held.is_empty() || (!self.pending && self.elapsed < step_length) {
+            return;
+        }
+        self.elapsed = if self.pending {
+            0.0
+        } else {
+            self.elapsed - step_length
+        };
+        self.pending = false;
+
+        if let Some((note, level)) = self.next_note(params, rng) {
+            if let Some(playing) = self.playing.take() {
+                send(Message::NoteOff(playing));
+            }
+            send(Message::NoteOn(note, level));
+            self.playing = Some(note);
+        }
+    }
+
+    fn next_note(&mut self, params: &ArpParams, rng: &mut Rng) -> Option<(Note, f32)> {
+        let mode = params.mode?;
+
+        self.pattern.clear();
+        self.pattern.extend_from_slice(&self.held);
+        if mode != ArpMode::AsPlayed {
+            self.pattern.sort_by_key(|(note, _)| *note);
+        }
+
+        let notes = self.pattern.len();
+        for octave in 1..params.octaves.clamp(1, 10) {
+            for idx in 0..notes {
+                let (note, level) = self.pattern[idx];
+                if let Ok(note) = Note::try_from(u8::from(note) + 12 * octave) {
+                    self.pattern.push((note, level));
+                }
+            }
+        }
+
+        let len = self.pattern.len();
+        let idx = match mode {
+            ArpMode::Up | ArpMode::AsPlayed => self.step % len,
+            ArpMode::Down => len - 1 - self.step % len,
+            ArpMode::UpDown if len < 2 => 0,
+            ArpMode::UpDown => {
+                // Don't repeat the top and bottom notes
+                let cycle = 2 * len - 2;
+                let position = self.step % cycle;
+                if position < len {
+                    position
+                } else {
+                    cycle - position
+                }
+            }
+            ArpMode::Random => rng.below(len),
+        };
+        self.step = self.step.wrapping_add(1);
+
+        Some(self.pattern[idx])
+    }
+}
+
+impl Default for Arpeggiator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Samples in a step, at 60 bpm and a step of a beat
+    const STEP: usize = 100;
+
+    fn params(mode: ArpMode) -> ArpParams {
+        ArpParams {
+            mode: Some(mode),
+            octaves: 1,
+            gate: 0.5,
+            rate: 1.0,
+            latch: false,
+            sync: ArpSync::Internal,
+        }
+    }
+
+    fn note(note: u8) -> Note {
+        Note::try_from(note).unwrap()
+    }
+
+    /// Notes starting and ending over `samples` samples, with the sample they happen on
+    fn run(
+        arpeggiator: &mut Arpeggiator,
+        params: &ArpParams,
+        rng: &mut Rng,
+        samples: usize,
+    ) -> Vec<(usize, bool, u8)> {
+        let mut notes = Vec::new();
+        for sample in 0..samples {
+            arpeggiator.tick(params, 60.0, STEP as f32, rng, |message| match message {
+                Message::NoteOn(note, _) => notes.push((sample, true, u8::from(note))),
+                Message::NoteOff(note) => notes.push((sample, false, u8::from(note))),
+                _ => panic!("The arpeggiator only plays notes"),
+            });
+        }
+        notes
+    }
+
+    /// Just the notes that start
+    fn played(notes: &[(usize, bool, u8)]) -> Vec<u8> {
+        notes
+            .iter()
+            .filter(|(_, on, _)| *on)
+            .map(|(_, _, note)| *note)
+            .collect()
+    }
+
+    /// The notes played over `steps` steps, holding a chord played out of order
+    fn pattern(params: &ArpParams, steps: usize) -> Vec<u8> {
+        let mut arpeggiator = Arpeggiator::new();
+        for key in &[67, 60, 64] {
+            arpeggiator.note_on(params, note(*key), 1.0);
+        }
+        played(&run(
+            &mut arpeggiator,
+            params,
+            &mut Rng::new(1),
+            steps * STEP,
+        ))
+    }
+
+    #[test]
+    fn modes() {
+        assert_eq!(pattern(&params(ArpMode::Up), 4), vec![60, 64, 67, 60]);
+        assert_eq!(pattern(&params(ArpMode::Down), 4), vec![67, 64, 60, 67]);
+        assert_eq!(
+            pattern(&params(ArpMode::UpDown), 6),
+            vec![60, 64, 67, 64, 60, 64]
+        );
+        assert_eq!(pattern(&params(ArpMode::AsPlayed), 4), vec![67, 60, 64, 67]);
+
+        let random = pattern(&params(ArpMode::Random), 32);
+        assert_eq!(random.len(), 32);
+        assert!(random.iter().all(|note| [60, 64, 67].contains(note)));
+        assert!(random.iter().any(|note| *note != random[0]));
+    }
+
+    #[test]
+    fn octaves() {
+        let params = ArpParams {
+            octaves: 2,
+            ..params(ArpMode::Up)
+        };
+        assert_eq!(pattern(&params, 7), vec![60, 64, 67, 72, 76, 79, 60]);
+    }
+
+    #[test]
+    fn gate() {
+        let params = ArpParams {
+            gate: 0.25,
+            ..params(ArpMode::Up)
+        };
+        let mut arpeggiator = Arpeggiator::new();
+        arpeggiator.note_on(&params, note(60), 1.0);
+        let notes = run(&mut arpeggiator, &params, &mut Rng::new(1), 2 * STEP);
+        assert_eq!(
+            notes,
+            vec![
+                (0, true, 60),
+                (STEP / 4, false, 60),
+                (STEP, true, 60),
+                (STEP + STEP / 4, false, 60),
+            ]
+        );
+    }
+
+    #[test]
+    fn first_note_plays_straight_away() {
+        let params = params(ArpMode::Up);
+        let mut arpeggiator = Arpeggiator::new();
+        let mut rng = Rng::new(1);
+        // Time passing with nothing held doesn't count towards the first step
+        run(&mut arpeggiator, &params, &mut rng, STEP / 3);
+        arpeggiator.note_on(&params, note(60), 1.0);
+        assert_eq!(
+            run(&mut arpeggiator, &params, &mut rng, 1),
+            vec![(0, true, 60)]
+        );
+        // Adding to the chord doesn't start it over
+        run(&mut arpeggiator, &params, &mut rng, STEP / 3);
+        arpeggiator.note_on(&params, note(64), 1.0);
+        assert_eq!(run(&mut arpeggiator, &params, &mut rng, 1), vec![]);
+    }
+
+    #[test]
+    fn release_stops_without_latch() {
+        let params = params(ArpMode::Up);
+        let mut arpeggiator = Arpeggiator::new();
+        let mut rng = Rng::new(1);
+        arpeggiator.note_on(&params, note(60), 1.0);
+        run(&mut arpeggiator, &params, &mut rng, STEP / 4);
+        arpeggiator.note_off(&params, note(60));
+        assert_eq!(
+            run(&mut arpeggiator, &params, &mut rng, 4 * STEP),
+            vec![(0, false, 60)]
+        );
+    }
+
+    #[test]
+    fn latch() {
+        let params = ArpParams {
+            latch: true,
+            ..params(ArpMode::Up)
+        };
+        let mut arpeggiator = Arpeggiator::new();
+        let mut rng = Rng::new(1);
+        arpeggiator.note_on(&params, note(60), 1.0);
+        arpeggiator.note_on(&params, note(64), 1.0);
+        arpeggiator.note_off(&params, note(60));
+        arpeggiator.note_off(&params, note(64));
+        let notes = run(&mut arpeggiator, &params, &mut rng, 2 * STEP + STEP / 4);
+        assert_eq!(played(&notes), vec![60, 64, 60]);
+
+        // A new chord once everything's let go replaces the old one
+        arpeggiator.note_on(&params, note(67), 1.0);
+        arpeggiator.note_off(&params, note(67));
+        let notes = run(&mut arpeggiator, &params, &mut rng, 2 * STEP);
+        assert_eq!(notes[..2], [(0, false, 60), (0, true, 67)]);
+        assert_eq!(played(&notes), vec![67, 67]);
+    }
+}
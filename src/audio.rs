@@ -0,0 +1,292 @@
+use shapes::channel::Receiver;
+use shapes::engine::Synth;
+use shapes::opts::{HostKind, Opts};
+
+use anyhow::{anyhow, Result};
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{Device, Host, HostId, StreamConfig, SupportedBufferSize};
+
+fn host_id(kind: HostKind) -> HostId {
+    match kind {
+        HostKind::Alsa => HostId::Alsa,
+        HostKind::Jack => HostId::Jack,
+    }
+}
+
+pub fn host(kind: Option<HostKind>) -> Result<Host> {
+    match kind {
+        Some(kind) => Ok(cpal::host_from_id(host_id(kind))?),
+        None => Ok(cpal::default_host()),
+    }
+}
+
+pub fn list_hosts() {
+    let default = cpal::default_host().id();
+    println!("Available audio hosts:");
+    for id in cpal::available_hosts() {
+        let name = id.name().to_lowercase();
+        if id == default {
+            println!("{} (default)", name);
+        } else {
+            println!("{}", name);
+        }
+    }
+}
+
+/// Where the sound goes
+pub enum Output {
+    Device {
+        device: Device,
+        config: StreamConfig,
+    },
+    /// Our own client rather than cpal's, which always calls itself "cpal_client_out" and
+    /// connects to the system ports
+    Jack(jack::Client),
+}
+
+/// Keeps the sound going until it's dropped
+pub enum Playing {
+    Device(cpal::Stream),
+    Jack(jack::AsyncClient<JackNotifications, JackProcess>),
+}
+
+impl Output {
+    pub fn sample_rate(&self) -> u32 {
+        match self {
+            Output::Device { config, .. } => config.sample_rate.0,
+            Output::Jack(client) => client.sample_rate() as u32,
+        }
+    }
+
+    pub fn log_info(&self) {
+        match self {
+            Output::Device { device, config } => {
+                log::info!(
+                    "Outputting to \"{}\"",
+                    device.name().unwrap_or(String::from("unknown"))
+                );
+                log::info!("channels: {}", config.channels);
+                log::info!("sample rate: {}", config.sample_rate.0);
+                log::info!(
+                    "buffer size: {}",
+                    match (&config.buffer_size, device.default_output_config()) {
+                        (cpal::BufferSize::Fixed(frames), _) => frames.to_string(),
+                        (_, Ok(supported)) => match supported.buffer_size() {
+                            SupportedBufferSize::Range { min, max } if min == max => {
+                                min.to_string()
+                            }
+                            _ => "default".to_string(),
+                        },
+                        _ => "default".to_string(),
+                    }
+                );
+            }
+            Output::Jack(client) => {
+                log::info!("Outputting to JACK as \"{}\"", client.name());
+                log::info!("channels: 2");
+                log::info!("sample rate: {}", client.sample_rate());
+                log::info!("buffer size: {}", client.buffer_size());
+            }
+        }
+    }
+
+    /// Start playing. JACK outputs get connected to the ports matching `jack_connect`, or the
+    /// system playback ports if it's not given
+    pub fn start(
+        self,
+        synth: Synth,
+        receiver: Receiver,
+        jack_connect: Option<&str>,
+    ) -> Result<Playing> {
+        match self {
+            Output::Device { device, config } => {
+                let stream =
+                    device.build_output_stream(&config, render(synth, receiver), stream_error)?;
+                stream.play()?;
+                Ok(Playing::Device(stream))
+            }
+            Output::Jack(client) => {
+                let left = client.register_port("out_left", jack::AudioOut)?;
+                let right = client.register_port("out_right", jack::AudioOut)?;
+                let ours = [left.name()?, right.name()?];
+                let notifications = JackNotifications {
+                    sample_rate: client.sample_rate() as jack::Frames,
+                };
+                let process = JackProcess {
+                    synth,
+                    receiver,
+                    left,
+                    right,
+                };
+                let client = client.activate_async(notifications, process)?;
+                // Connecting only works once the client is active
+                connect_jack_ports(
+                    client.as_client(),
+                    &ours,
+                    jack_connect.unwrap_or("system:playback_.*"),
+                );
+                Ok(Playing::Jack(client))
+            }
+        }
+    }
+}
+
+impl Playing {
+    pub fn stop(self) {
+        match self {
+            Playing::Device(stream) => drop(stream),
+            Playing::Jack(client) => {
+                if let Err(e) = client.deactivate() {
+                    log::warn!("Couldn't close the JACK client: {}", e);
+                }
+            }
+        }
+    }
+}
+
+pub fn open_output(opts: &Opts) -> Result<Output> {
+    match opts.host {
+        Some(HostKind::Jack) => open_jack(opts),
+        kind => open_device(host(kind)?, opts),
+    }
+}
+
+fn open_device(host: Host, opts: &Opts) -> Result<Output> {
+    let device = {
+        let _alsa_gag = gag::Gag::stderr().unwrap();
+        if opts.device == "default" {
+            host.default_output_device()
+        } else {
+            host.output_devices()?
+                .find(|d| d.name().map(|name| name == opts.device).unwrap_or(false))
+        }
+        .ok_or(anyhow!(
+            "Couldn't connect to output device \"{}\"",
+            opts.device
+        ))?
+    };
+
+    // TODO make fallback options? or just error out if not supported config
+    // TODO Ok yeah we definitely should validate before this point, alsa just spits out "INVALID ARGUMENT >:("
+    let config = StreamConfig {
+        channels: 2,
+        sample_rate: cpal::SampleRate(opts.sample_rate),
+        buffer_size: match opts.buffer_size {
+            Some(frames) => cpal::BufferSize::Fixed(frames),
+            None => cpal::BufferSize::Default,
+        },
+    };
+
+    Ok(Output::Device { device, config })
+}
+
+/// The JACK server decides the sample rate and buffer size, whatever was asked for on the
+/// command line doesn't matter
+fn open_jack(opts: &Opts) -> Result<Output> {
+    let (client, _status) =
+        jack::Client::new(&opts.jack_name, jack::ClientOptions::NO_START_SERVER)
+            .map_err(|e| anyhow!("Couldn't connect to the JACK server: {}", e))?;
+    if client.name() != opts.jack_name {
+        log::info!(
+            "JACK client \"{}\" was taken, using \"{}\"",
+            opts.jack_name,
+            client.name()
+        );
+    }
+
+    if client.sample_rate() as u32 != opts.sample_rate {
+        log::info!(
+            "JACK is running at {} Hz, not {}",
+            client.sample_rate(),
+            opts.sample_rate
+        );
+    }
+    if let Some(frames) = opts.buffer_size {
+        if frames != client.buffer_size() {
+            log::info!(
+                "JACK buffer size is {} frames, not {}",
+                client.buffer_size(),
+                frames
+            );
+        }
+    }
+
+    Ok(Output::Jack(client))
+}
+
+/// Connect our output ports to the input ports matching `pattern`, in order
+fn connect_jack_ports(client: &jack::Client, ours: &[String], pattern: &str) {
+    if pattern == "none" {
+        return;
+    }
+
+    let theirs = client.ports(Some(pattern), None, jack::PortFlags::IS_INPUT);
+    if theirs.is_empty() {
+        log::warn!("No JACK input ports match \"{}\"", pattern);
+    }
+
+    for (source, destination) in ours.iter().zip(&theirs) {
+        match client.connect_ports_by_name(source, destination) {
+            Ok(_) => log::info!("Connected {} to {}", source, destination),
+            Err(e) => log::warn!("Couldn't connect {} to {}: {}", source, destination, e),
+        }
+    }
+}
+
+/// The output stream callback, passing on whatever's come in since the last buffer
+fn render(
+    mut synth: Synth,
+    receiver: Receiver,
+) -> impl FnMut(&mut [f32], &cpal::OutputCallbackInfo) {
+    move |data: &mut [f32], _info: &cpal::OutputCallbackInfo| {
+        receiver.receive(|message| synth.handle_event(message, 0));
+        synth.process(data);
+    }
+}
+
+fn stream_error(err: cpal::StreamError) {
+    log::error!("Audio output stream error: {}", err);
+}
+
+/// Same as `render`, for JACK
+pub struct JackProcess {
+    synth: Synth,
+    receiver: Receiver,
+    left: jack::Port<jack::AudioOut>,
+    right: jack::Port<jack::AudioOut>,
+}
+
+impl jack::ProcessHandler for JackProcess {
+    fn process(&mut self, _client: &jack::Client, scope: &jack::ProcessScope) -> jack::Control {
+        let synth = &mut self.synth;
+        self.receiver
+            .receive(|message| synth.handle_event(message, 0));
+        self.synth.process_planar(
+            self.left.as_mut_slice(scope),
+            self.right.as_mut_slice(scope),
+        );
+        jack::Control::Continue
+    }
+}
+
+pub struct JackNotifications {
+    sample_rate: jack::Frames,
+}
+
+impl jack::NotificationHandler for JackNotifications {
+    fn sample_rate(&mut self, _client: &jack::Client, frames: jack::Frames) -> jack::Control {
+        // This also gets called once when the client is activated
+        if frames != self.sample_rate {
+            log::error!(
+                "JACK sample rate changed to {}, restart to keep playing",
+                frames
+            );
+        }
+        jack::Control::Continue
+    }
+
+    fn buffer_size(&mut self, _client: &jack::Client, frames: jack::Frames) -> jack::Control {
+        log::info!("JACK buffer size changed to {}", frames);
+        jack::Control::Continue
+    }
+}
@@ -0,0 +1,141 @@
+//! Logging from the audio thread. Formatting and writing out a log line allocates and locks,
+//! so the audio thread pushes small records onto a ring instead and another thread logs them
+
+use ringbuf::{Consumer, Producer, RingBuffer};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use wmidi::Note;
+
+/// Records waiting to be logged, any more get dropped
+const CAPACITY: usize = 256;
+/// How often the logging thread looks for records
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Something worth knowing that happened on the audio thread
+#[derive(Debug, Clone, Copy)]
+pub enum Record {
+    /// Filling a buffer took longer than playing it, so there'll have been a gap. Load is the
+    /// fraction of the buffer's length it took
+    Xrun {
+        load: f32,
+    },
+    /// A note took over a voice that was still playing another one
+    VoiceStolen {
+        old: Note,
+        new: Note,
+    },
+    /// Frames in a buffer past full scale, and how far past
+    Clipped {
+        frames: u32,
+        peak: f32,
+    },
+    /// An event came in when there were already MAX_EVENTS waiting
+    EventDropped,
+    FollowingClock,
+    LostClock,
+}
+
+/// The audio thread's end
+pub struct Writer {
+    producer: Producer<Record>,
+    lost: Arc<AtomicUsize>,
+}
+
+impl Writer {
+    pub fn push(&mut self, record: Record) {
+        if self.producer.push(record).is_err() {
+            self.lost.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// The end that does the logging
+pub struct Reader {
+    consumer: Consumer<Record>,
+    lost: Arc<AtomicUsize>,
+}
+
+impl Reader {
+    /// Log everything waiting. Clipping and dropped events are added up rather than logged
+    /// for every buffer
+    pub fn log_pending(&mut self) {
+        let mut clipped: Option<(u32, f32)> = None;
+        let mut dropped = 0;
+        while let Some(record) = self.consumer.pop() {
+            match record {
+                Record::Xrun { load } => log::warn!(
+                    "Audio took {:.0}% of the time it had, there'll have been a glitch",
+                    load * 100.0
+                ),
+                Record::VoiceStolen { old, new } => {
+                    log::debug!("{} took the voice playing {}", new, old)
+                }
+                Record::Clipped { frames, peak } => {
+                    let (total, loudest) = clipped.unwrap_or((0, 0.0));
+                    clipped = Some((total + frames, loudest.max(peak)));
+                }
+                Record::EventDropped => dropped += 1,
+                Record::FollowingClock => log::debug!("Following external MIDI clock"),
+                Record::LostClock => log::debug!("Lost external MIDI clock, using internal clock"),
+            }
+        }
+        if let Some((frames, peak)) = clipped {
+            log::info!(
+                "Clipped {} frames, peaking at +{:.1} dB",
+                frames,
+                20.0 * peak.log10()
+            );
+        }
+        if dropped > 0 {
+            log::warn!("Too much came in at once, dropped {} events", dropped);
+        }
+        let lost = self.lost.swap(0, Ordering::Relaxed);
+        if lost > 0 {
+            log::warn!("Lost {} log records from the audio thread", lost);
+        }
+    }
+
+    /// Keep logging on a thread of its own
+    pub fn spawn(mut self) -> LogThread {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let thread = thread::spawn(move || {
+            while !stopped.load(Ordering::Relaxed) {
+                self.log_pending();
+                thread::sleep(POLL_INTERVAL);
+            }
+            self.log_pending();
+        });
+        LogThread { stop, thread }
+    }
+}
+
+/// Handle to the thread logging for the audio thread
+pub struct LogThread {
+    stop: Arc<AtomicBool>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl LogThread {
+    /// Log whatever's left and stop
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if self.thread.join().is_err() {
+            log::error!("Audio log thread panicked");
+        }
+    }
+}
+
+pub fn ring() -> (Writer, Reader) {
+    let (producer, consumer) = RingBuffer::new(CAPACITY).split();
+    let lost = Arc::new(AtomicUsize::new(0));
+    (
+        Writer {
+            producer,
+            lost: lost.clone(),
+        },
+        Reader { consumer, lost },
+    )
+}
@@ -0,0 +1,77 @@
+//! Getting messages to the audio thread. Like `std::sync::mpsc` but the audio thread never
+//! allocates, frees or waits for anything, the senders clean up after it instead
+
+use crate::engine::Message;
+use crate::queue::{self, Queue};
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::SendError;
+use std::sync::{Arc, Mutex};
+
+/// Emptied once the message is taken out, then sent back to be freed
+type Node = Option<Message>;
+
+#[derive(Clone)]
+pub struct Sender {
+    messages: queue::Sender<Node>,
+    returned: Arc<Mutex<queue::Receiver<Node>>>,
+    connected: Arc<AtomicBool>,
+}
+
+impl Sender {
+    /// Fails once the receiver is gone, e.g. when audio has stopped
+    pub fn send(&self, message: Message) -> Result<(), SendError<Message>> {
+        if !self.connected.load(Ordering::Relaxed) {
+            return Err(SendError(message));
+        }
+        // Whatever the audio thread has finished with gets freed here
+        for _ in self.returned.lock().unwrap().recv() {}
+        self.messages.send(Some(message));
+        Ok(())
+    }
+}
+
+pub struct Receiver {
+    messages: queue::Receiver<Node>,
+    returned: queue::Sender<Node>,
+    connected: Arc<AtomicBool>,
+}
+
+impl Receiver {
+    /// Hand everything waiting to `f`, in the order each sender sent it. Anything `f` gives
+    /// back, like a patch that's been replaced, goes back with the node to be freed by the
+    /// next send
+    pub fn receive(&self, mut f: impl FnMut(Message) -> Option<Message>) {
+        for mut node in self.messages.recv_items() {
+            if let Some(message) = node.take() {
+                *node = f(message);
+            }
+            self.returned.send_item(node);
+        }
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        self.connected.store(false, Ordering::Relaxed);
+        for _ in self.messages.recv() {}
+    }
+}
+
+pub fn channel() -> (Sender, Receiver) {
+    let (message_sender, message_receiver) = Queue::new();
+    let (return_sender, return_receiver) = Queue::new();
+    let connected = Arc::new(AtomicBool::new(true));
+    (
+        Sender {
+            messages: message_sender,
+            returned: Arc::new(Mutex::new(return_receiver)),
+            connected: connected.clone(),
+        },
+        Receiver {
+            messages: message_receiver,
+            returned: return_sender,
+            connected,
+        },
+    )
+}
@@ -1 +1,6 @@
 pub const MAX_VOICES: usize = 24;
+pub const MAX_LFOS: usize = 8;
+/// Frames of output kept around for drawing
+pub const SCOPE_LENGTH: usize = 1024;
+/// Events waiting for the next buffer, any more get dropped so the audio thread never allocates
+pub const MAX_EVENTS: usize = 1024;
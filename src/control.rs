@@ -0,0 +1,247 @@
+use shapes::channel::Sender;
+use shapes::engine::{Message, Status};
+use shapes::midi_map::MidiMap;
+use shapes::parameter::{Parameter, PARAMETERS};
+use shapes::patch::Patch;
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// How long to wait between looking for new connections
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// One per line, e.g. {"command": "set", "parameter": "cutoff", "value": 800}
+#[derive(Debug, Deserialize)]
+#[serde(tag = "command", rename_all = "lowercase")]
+enum Command {
+    /// Current value of a parameter in its own units
+    Get {
+        parameter: String,
+    },
+    /// Set a parameter in its own units, e.g. Hz for the cutoff
+    Set {
+        parameter: String,
+        value: f32,
+    },
+    /// Every parameter and its value
+    Parameters,
+    /// Swap in a patch file, the same options as the command line
+    Load {
+        path: PathBuf,
+    },
+    /// What every voice is playing
+    Voices,
+    /// Silence everything and reset the controllers
+    Panic,
+    Stats,
+    /// Bind the next MIDI controller that moves to a parameter
+    Learn {
+        parameter: String,
+    },
+}
+
+/// Everything a client can get at
+#[derive(Clone)]
+struct Shared {
+    sender: Sender,
+    status: Arc<Status>,
+    midi_map: Arc<Mutex<MidiMap>>,
+}
+
+impl Shared {
+    fn run(&self, command: Command) -> Result<Value> {
+        match command {
+            Command::Get { parameter } => {
+                let parameter = Parameter::from_str(&parameter)?;
+                Ok(
+                    json!({ "parameter": parameter.name(), "value": self.status.parameter(parameter) }),
+                )
+            }
+            Command::Set { parameter, value } => {
+                let parameter = Parameter::from_str(&parameter)?;
+                // Goes through the same path as a controller, which clamps it to the range
+                self.send(Message::SetParameter(
+                    parameter,
+                    parameter.to_normalized(value),
+                ))?;
+                Ok(json!({}))
+            }
+            Command::Parameters => {
+                let parameters = PARAMETERS
+                    .iter()
+                    .map(|parameter| {
+                        (
+                            parameter.name().to_string(),
+                            json!(self.status.parameter(*parameter)),
+                        )
+                    })
+                    .collect::<serde_json::Map<String, Value>>();
+                Ok(json!({ "parameters": parameters }))
+            }
+            Command::Load { path } => {
+                let patch = Patch::load(&path)?;
+                self.send(Message::load_patch(patch))?;
+                log::info!("Loaded patch {}", path.display());
+                Ok(json!({}))
+            }
+            Command::Voices => {
+                let voices = self
+                    .status
+                    .voices
+                    .iter()
+                    .filter(|voice| voice.level.load() > 0.0)
+                    .map(|voice| {
+                        json!({
+                            "note": voice.note.load(Ordering::Relaxed),
+                            "level": voice.level.load(),
+                            "stage": voice.stage().name(),
+                        })
+                    })
+                    .collect::<Vec<Value>>();
+                Ok(json!({ "voices": voices }))
+            }
+            Command::Panic => {
+                self.send(Message::Reset)?;
+                Ok(json!({}))
+            }
+            Command::Stats => Ok(json!({
+                "active_voices": self.status.active_voices.load(Ordering::Relaxed),
+                "peak": [self.status.peak[0].load(), self.status.peak[1].load()],
+                "load": self.status.load.load(),
+                "tempo": self.status.tempo.load(),
+                "external_clock": self.status.external_clock.load(Ordering::Relaxed),
+            })),
+            Command::Learn { parameter } => {
+                let parameter = Parameter::from_str(&parameter)?;
+                self.midi_map.lock().unwrap().learn(parameter);
+                Ok(json!({}))
+            }
+        }
+    }
+
+    fn send(&self, message: Message) -> Result<()> {
+        self.sender
+            .send(message)
+            .map_err(|_| anyhow!("Audio isn't running"))
+    }
+
+    /// Answer every line until the client hangs up
+    fn handle(&self, stream: UnixStream) -> Result<()> {
+        let reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            log::trace!("Control command: {}", line);
+
+            let result = serde_json::from_str::<Command>(&line)
+                .map_err(anyhow::Error::from)
+                .and_then(|command| self.run(command));
+            let reply = match result {
+                Ok(Value::Object(mut fields)) => {
+                    fields.insert(String::from("ok"), json!(true));
+                    Value::Object(fields)
+                }
+                Ok(value) => json!({ "ok": true, "result": value }),
+                Err(e) => json!({ "ok": false, "error": format!("{:#}", e) }),
+            };
+            writeln!(writer, "{}", reply)?;
+        }
+        Ok(())
+    }
+}
+
+/// Handle to the thread accepting control connections
+pub struct ControlServer {
+    stop: Arc<AtomicBool>,
+    thread: thread::JoinHandle<()>,
+    path: PathBuf,
+}
+
+impl ControlServer {
+    /// Stop taking new connections and remove the socket. Clients already connected keep
+    /// going until they hang up or audio stops
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if self.thread.join().is_err() {
+            log::error!("Control server thread panicked");
+        }
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            log::warn!("Couldn't remove {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Listen for JSON commands on a Unix socket, one per line, each answered with one line
+pub fn serve(
+    path: &Path,
+    sender: Sender,
+    status: Arc<Status>,
+    midi_map: Arc<Mutex<MidiMap>>,
+) -> Result<ControlServer> {
+    if path.exists() {
+        // Left behind by something that didn't shut down properly, unless it's still running
+        if UnixStream::connect(path).is_ok() {
+            return Err(anyhow!(
+                "Something is already listening on {}",
+                path.display()
+            ));
+        }
+        std::fs::remove_file(path)
+            .with_context(|| format!("Couldn't remove old socket {}", path.display()))?;
+    }
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Couldn't listen on {}", path.display()))?;
+    listener.set_nonblocking(true)?;
+    log::info!("Listening for control commands on {}", path.display());
+
+    let shared = Shared {
+        sender,
+        status,
+        midi_map,
+    };
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = stop.clone();
+    let thread = thread::spawn(move || {
+        while !stopped.load(Ordering::Relaxed) {
+            match listener.accept() {
+                Ok((stream, _address)) => {
+                    if let Err(e) = stream.set_nonblocking(false) {
+                        log::warn!("Couldn't set up control connection: {}", e);
+                        continue;
+                    }
+                    let shared = shared.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = shared.handle(stream) {
+                            log::warn!("Control connection closed: {}", e);
+                        }
+                    });
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    thread::sleep(POLL_INTERVAL)
+                }
+                Err(e) => {
+                    log::error!("Couldn't accept control connection: {}", e);
+                    thread::sleep(POLL_INTERVAL);
+                }
+            }
+        }
+    });
+
+    Ok(ControlServer {
+        stop,
+        thread,
+        path: path.to_path_buf(),
+    })
+}
@@ -0,0 +1,411 @@
+use crate::maths;
+use crate::util::{format_note_division, parse_note_division};
+use crate::vec2::{self, Vec2};
+
+use anyhow::{anyhow, Result};
+use core::f32::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+
+const MAX_DELAY_SECONDS: f32 = 4.0;
+const CHORUS_BASE_DELAY_MS: f32 = 15.0;
+const CHORUS_MAX_DEPTH_MS: f32 = 15.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EffectKind {
+    Delay,
+    Chorus,
+    Reverb,
+}
+
+impl FromStr for EffectKind {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<EffectKind> {
+        match s.to_lowercase().as_str() {
+            "delay" => Ok(EffectKind::Delay),
+            "chorus" => Ok(EffectKind::Chorus),
+            "reverb" => Ok(EffectKind::Reverb),
+            _ => Err(anyhow!("Invalid value \"{}\" for effect", s)),
+        }
+    }
+}
+
+/// The order the effects get applied in, e.g. "chorus,delay,reverb"
+#[derive(Debug, Clone, Default)]
+pub struct EffectOrder(pub Vec<EffectKind>);
+
+impl FromStr for EffectOrder {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<EffectOrder> {
+        if s.trim().is_empty() || s == "none" {
+            return Ok(EffectOrder(Vec::new()));
+        }
+        s.split(',')
+            .map(|effect| EffectKind::from_str(effect.trim()))
+            .collect::<Result<Vec<EffectKind>>>()
+            .map(EffectOrder)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DelayTime {
+    Seconds(f32),
+    Beats(f32),
+}
+
+impl DelayTime {
+    pub fn seconds(&self, tempo: f32) -> f32 {
+        match self {
+            DelayTime::Seconds(seconds) => *seconds,
+            DelayTime::Beats(beats) => beats * 60.0 / tempo,
+        }
+    }
+}
+
+impl FromStr for DelayTime {
+    type Err = anyhow::Error;
+    /// Either seconds ("0.375") or a note division ("1/8d")
+    fn from_str(s: &str) -> Result<DelayTime> {
+        match parse_note_division(s)? {
+            Some(beats) => Ok(DelayTime::Beats(beats)),
+            None => Ok(DelayTime::Seconds(f32::from_str(s)?)),
+        }
+    }
+}
+
+impl fmt::Display for DelayTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DelayTime::Seconds(seconds) => write!(f, "{}", seconds),
+            DelayTime::Beats(beats) => write!(f, "{}", format_note_division(*beats)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DelayParams {
+    pub time: DelayTime,
+    pub feedback: f32,
+    pub ping_pong: bool,
+    pub mix: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChorusParams {
+    /// Hz
+    pub rate: f32,
+    /// Milliseconds
+    pub depth: f32,
+    pub mix: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReverbParams {
+    /// 0 to 1
+    pub size: f32,
+    /// 0 to 1
+    pub damping: f32,
+    pub mix: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct EffectParams {
+    pub order: EffectOrder,
+    pub delay: DelayParams,
+    pub chorus: ChorusParams,
+    pub reverb: ReverbParams,
+    /// Process both channels identically and never mix them into each other,
+    /// so whatever is drawn on an XY scope keeps its shape
+    pub xy_preserve: bool,
+}
+
+/// A delay line for both channels at once
+struct DelayLine {
+    buffer: Vec<Vec2>,
+    write: usize,
+}
+
+impl DelayLine {
+    fn new(length: usize) -> Self {
+        Self {
+            buffer: vec![(0.0, 0.0); length.max(2)],
+            write: 0,
+        }
+    }
+
+    fn clear(&mut self) {
+        for v in &mut self.buffer {
+            *v = (0.0, 0.0);
+        }
+    }
+
+    fn push(&mut self, v: Vec2) {
+        self.buffer[self.write] = v;
+        self.write = (self.write + 1) % self.buffer.len();
+    }
+
+    /// Linearly interpolated, `delay` in samples since the last push
+    fn read(&self, delay: f32) -> Vec2 {
+        let len = self.buffer.len();
+        let delay = delay.clamp(1.0, (len - 1) as f32);
+        let whole = delay.floor();
+        let frac = delay - whole;
+        let a = self.buffer[(self.write + len - whole as usize) % len];
+        let b = self.buffer[(self.write + len - whole as usize - 1) % len];
+        (maths::lerp(a.0, b.0, frac), maths::lerp(a.1, b.1, frac))
+    }
+}
+
+struct Delay {
+    line: DelayLine,
+}
+
+impl Delay {
+    fn process(
+        &mut self,
+        params: &DelayParams,
+        xy_preserve: bool,
+        input: Vec2,
+        tempo: f32,
+        samplerate: f32,
+    ) -> Vec2 {
+        let delay = params.time.seconds(tempo).min(MAX_DELAY_SECONDS) * samplerate;
+        let delayed = self.line.read(delay);
+        let feedback = params.feedback.clamp(0.0, 0.99);
+
+        if params.ping_pong && !xy_preserve {
+            // Input goes in on the left, then every repeat bounces to the other side
+            let mono = (input.0 + input.1) / 2.0;
+            self.line
+                .push((mono + delayed.1 * feedback, delayed.0 * feedback));
+        } else {
+            self.line
+                .push(vec2::add(input, vec2::scale(delayed, feedback)));
+        }
+
+        mix(input, delayed, params.mix)
+    }
+}
+
+struct Chorus {
+    line: DelayLine,
+    phase: f32,
+}
+
+impl Chorus {
+    fn process(
+        &mut self,
+        params: &ChorusParams,
+        xy_preserve: bool,
+        input: Vec2,
+        samplerate: f32,
+    ) -> Vec2 {
+        self.line.push(input);
+
+        let depth = params.depth.clamp(0.0, CHORUS_MAX_DEPTH_MS);
+        let delay_at = |phase: f32| {
+            let ms = CHORUS_BASE_DELAY_MS + depth * f32::sin(2.0 * PI * phase);
+            ms / 1000.0 * samplerate
+        };
+
+        let left = self.line.read(delay_at(self.phase)).0;
+        let right = if xy_preserve {
+            self.line.read(delay_at(self.phase)).1
+        } else {
+            // A quarter cycle apart for some stereo width
+            self.line.read(delay_at(self.phase + 0.25)).1
+        };
+
+        self.phase = (self.phase + params.rate / samplerate).fract();
+
+        mix(input, (left, right), params.mix)
+    }
+}
+
+// Freeverb, tunings are for 44.1kHz and get scaled to the actual samplerate
+const COMB_TUNINGS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const ALLPASS_TUNINGS: [usize; 4] = [556, 441, 341, 225];
+const STEREO_SPREAD: usize = 23;
+const FIXED_GAIN: f32 = 0.015;
+
+struct Comb {
+    buffer: Vec<f32>,
+    idx: usize,
+    filter_store: f32,
+}
+
+impl Comb {
+    fn new(length: usize) -> Self {
+        Self {
+            buffer: vec![0.0; length.max(1)],
+            idx: 0,
+            filter_store: 0.0,
+        }
+    }
+
+    fn clear(&mut self) {
+        for sample in &mut self.buffer {
+            *sample = 0.0;
+        }
+        self.filter_store = 0.0;
+    }
+
+    fn process(&mut self, input: f32, feedback: f32, damping: f32) -> f32 {
+        let output = self.buffer[self.idx];
+        self.filter_store = output * (1.0 - damping) + self.filter_store * damping;
+        self.buffer[self.idx] = input + self.filter_store * feedback;
+        self.idx = (self.idx + 1) % self.buffer.len();
+        output
+    }
+}
+
+struct Allpass {
+    buffer: Vec<f32>,
+    idx: usize,
+}
+
+impl Allpass {
+    fn new(length: usize) -> Self {
+        Self {
+            buffer: vec![0.0; length.max(1)],
+            idx: 0,
+        }
+    }
+
+    fn clear(&mut self) {
+        for sample in &mut self.buffer {
+            *sample = 0.0;
+        }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let buffered = self.buffer[self.idx];
+        self.buffer[self.idx] = input + buffered * 0.5;
+        self.idx = (self.idx + 1) % self.buffer.len();
+        buffered - input
+    }
+}
+
+struct ReverbChannel {
+    combs: Vec<Comb>,
+    allpasses: Vec<Allpass>,
+}
+
+impl ReverbChannel {
+    fn new(samplerate: f32, spread: usize) -> Self {
+        let scale = |tuning: usize| ((tuning + spread) as f32 * samplerate / 44100.0) as usize;
+        Self {
+            combs: COMB_TUNINGS.iter().map(|t| Comb::new(scale(*t))).collect(),
+            allpasses: ALLPASS_TUNINGS
+                .iter()
+                .map(|t| Allpass::new(scale(*t)))
+                .collect(),
+        }
+    }
+
+    fn clear(&mut self) {
+        self.combs.iter_mut().for_each(Comb::clear);
+        self.allpasses.iter_mut().for_each(Allpass::clear);
+    }
+
+    fn process(&mut self, input: f32, feedback: f32, damping: f32) -> f32 {
+        let input = input * FIXED_GAIN;
+        let mut output = self
+            .combs
+            .iter_mut()
+            .map(|comb| comb.process(input, feedback, damping))
+            .sum();
+        for allpass in &mut self.allpasses {
+            output = allpass.process(output);
+        }
+        output
+    }
+}
+
+struct Reverb {
+    left: ReverbChannel,
+    right: ReverbChannel,
+    /// Same tunings on both sides, for XY preserving mode
+    right_linked: ReverbChannel,
+}
+
+impl Reverb {
+    fn process(&mut self, params: &ReverbParams, xy_preserve: bool, input: Vec2) -> Vec2 {
+        let feedback = params.size.clamp(0.0, 1.0) * 0.28 + 0.7;
+        let damping = params.damping.clamp(0.0, 1.0) * 0.4;
+
+        let wet = if xy_preserve {
+            (
+                self.left.process(input.0, feedback, damping),
+                self.right_linked.process(input.1, feedback, damping),
+            )
+        } else {
+            let mono = input.0 + input.1;
+            (
+                self.left.process(mono, feedback, damping),
+                self.right.process(mono, feedback, damping),
+            )
+        };
+
+        mix(input, wet, params.mix)
+    }
+}
+
+fn mix(dry: Vec2, wet: Vec2, mix: f32) -> Vec2 {
+    let mix = mix.clamp(0.0, 1.0);
+    vec2::add(vec2::scale(dry, 1.0 - mix), vec2::scale(wet, mix))
+}
+
+/// State for the master effects chain, everything gets allocated up front
+pub struct Effects {
+    delay: Delay,
+    chorus: Chorus,
+    reverb: Reverb,
+    samplerate: f32,
+}
+
+impl Effects {
+    pub fn new(samplerate: u32) -> Self {
+        let samplerate = samplerate as f32;
+        let chorus_length = (CHORUS_BASE_DELAY_MS + CHORUS_MAX_DEPTH_MS) / 1000.0 * samplerate;
+        Self {
+            delay: Delay {
+                line: DelayLine::new((MAX_DELAY_SECONDS * samplerate) as usize + 2),
+            },
+            chorus: Chorus {
+                line: DelayLine::new(chorus_length as usize + 2),
+                phase: 0.0,
+            },
+            reverb: Reverb {
+                left: ReverbChannel::new(samplerate, 0),
+                right: ReverbChannel::new(samplerate, STEREO_SPREAD),
+                right_linked: ReverbChannel::new(samplerate, 0),
+            },
+            samplerate,
+        }
+    }
+
+    /// Cut off anything still ringing
+    pub fn clear(&mut self) {
+        self.delay.line.clear();
+        self.chorus.line.clear();
+        self.reverb.left.clear();
+        self.reverb.right.clear();
+        self.reverb.right_linked.clear();
+    }
+
+    pub fn process(&mut self, params: &EffectParams, input: Vec2, tempo: f32) -> Vec2 {
+        let xy_preserve = params.xy_preserve;
+        params.order.0.iter().fold(input, |v, effect| match effect {
+            EffectKind::Delay => {
+                self.delay
+                    .process(&params.delay, xy_preserve, v, tempo, self.samplerate)
+            }
+            EffectKind::Chorus => {
+                self.chorus
+                    .process(&params.chorus, xy_preserve, v, self.samplerate)
+            }
+            EffectKind::Reverb => self.reverb.process(&params.reverb, xy_preserve, v),
+        })
+    }
+}
@@ -1,22 +1,220 @@
+use crate::arpeggiator::Arpeggiator;
+use crate::audio_log::{self, Record};
+use crate::channel::Sender;
 use crate::constants::*;
-use crate::opts::Opts;
-use crate::util::SampleTimer;
-use crate::vec2;
+use crate::effects::Effects;
+use crate::filter::Filter;
+use crate::lfo::{self, Lfo};
+use crate::maths;
+use crate::midi_map::{Controller, MidiMap};
+use crate::modulation::{Modulation, Sources};
+use crate::parameter::{Parameter, PARAMETERS};
+use crate::patch::Patch;
+use crate::synthesis::*;
+use crate::transform::Transform;
+use crate::transport::Transport;
+use crate::tuning;
+use crate::util::{AtomicF32, Rng, SampleTimer};
+use crate::vec2::{self, Vec2};
+use crate::wireframe::{project, Solids, Spin};
 
 use std::convert::TryFrom;
-use std::sync::mpsc;
-use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
-use cpal::{Sample, SampleRate};
-use wmidi::{MidiMessage, Note, U7};
+use wmidi::{ControlFunction, MidiMessage, Note, U7};
 
 pub enum Message {
     /// Note, Velocity except velocity is a value between 0 and 1
     NoteOn(wmidi::Note, f32),
     NoteOff(wmidi::Note),
+    /// -1 to 1
+    PitchBend(f32),
+    /// Channel pressure, 0 to 1
+    Aftertouch(f32),
+    /// Polyphonic key pressure, 0 to 1
+    PolyAftertouch(wmidi::Note, f32),
+    /// 0 to 1
+    ModWheel(f32),
+    /// New frequency for a note, from MIDI Tuning Standard messages
+    RetuneNote(wmidi::Note, f32),
+    /// MIDI timing clock tick, 24 per beat, with the timestamp in microseconds
+    Clock(u64),
+    /// Start playing from the beginning of the song
+    Start,
+    /// Carry on from wherever we stopped
+    Continue,
+    Stop,
+    /// Song position in sixteenth notes
+    SongPosition(u16),
+    /// 0 to 1, scaled to the parameter's range
+    SetParameter(Parameter, f32),
+    /// Swap in a whole new patch, cutting off whatever's playing. Make it with `load_patch`,
+    /// the old patch comes back out of `handle_event` in the same box
+    LoadPatch(Box<LoadedPatch>),
+    /// Release every voice and forget what the arpeggiator is holding
+    AllNotesOff,
+    /// Like AllNotesOff but cut everything off right away, effect tails included
+    AllSoundOff,
+    /// Pitch bend, aftertouch and the mod wheel back to rest
+    ResetControllers,
+    /// All sound off and reset controllers, for when things go wrong
+    Reset,
 }
 
-pub fn handle_midi_input(timestamp: u64, message: &[u8], sender: &mut mpsc::Sender<Message>) {
+impl Message {
+    /// Everything a new patch needs gets built here rather than on the audio thread
+    pub fn load_patch(patch: Patch) -> Self {
+        Message::LoadPatch(Box::new(LoadedPatch::new(patch)))
+    }
+}
+
+/// A patch along with everything the synth makes from it that needs allocating
+pub struct LoadedPatch {
+    patch: Patch,
+    /// One for every voice there could be
+    voice_lfos: Vec<Vec<Lfo>>,
+    global_lfos: Vec<Lfo>,
+}
+
+impl LoadedPatch {
+    pub fn new(patch: Patch) -> Self {
+        Self {
+            voice_lfos: (0..MAX_VOICES).map(|_| lfos(&patch)).collect(),
+            global_lfos: lfos(&patch),
+            patch,
+        }
+    }
+}
+
+/// What the audio thread is up to, for anything else that wants to know
+pub struct Status {
+    /// Voices that are making any sound
+    pub active_voices: AtomicUsize,
+    /// Loudest sample in the last buffer, left and right
+    pub peak: [AtomicF32; 2],
+    /// Time spent on the last buffer over how long it plays for
+    pub load: AtomicF32,
+    pub tempo: AtomicF32,
+    /// Following MIDI clock
+    pub external_clock: AtomicBool,
+    /// Current value of every parameter, in the order of PARAMETERS
+    parameters: Vec<AtomicF32>,
+    pub voices: Vec<VoiceStatus>,
+    /// The last SCOPE_LENGTH frames of output, wrapping around at scope_position
+    scope: Vec<[AtomicF32; 2]>,
+    scope_position: AtomicUsize,
+}
+
+#[derive(Default)]
+pub struct VoiceStatus {
+    pub note: AtomicU8,
+    /// Amp envelope level
+    pub level: AtomicF32,
+    /// Amp envelope stage, as u8
+    stage: AtomicU8,
+}
+
+impl VoiceStatus {
+    pub fn stage(&self) -> Stage {
+        Stage::from_u8(self.stage.load(Ordering::Relaxed))
+    }
+}
+
+impl Status {
+    pub fn new(patch: &Patch) -> Self {
+        Self {
+            active_voices: AtomicUsize::new(0),
+            peak: [AtomicF32::new(0.0), AtomicF32::new(0.0)],
+            load: AtomicF32::new(0.0),
+            tempo: AtomicF32::new(patch.tempo),
+            external_clock: AtomicBool::new(false),
+            parameters: PARAMETERS
+                .iter()
+                .map(|parameter| AtomicF32::new(parameter.get(patch)))
+                .collect(),
+            voices: (0..MAX_VOICES).map(|_| VoiceStatus::default()).collect(),
+            scope: (0..SCOPE_LENGTH).map(|_| Default::default()).collect(),
+            scope_position: AtomicUsize::new(0),
+        }
+    }
+
+    /// Loudest of both channels
+    pub fn peak(&self) -> f32 {
+        self.peak[0].load().max(self.peak[1].load())
+    }
+
+    /// Recent output, oldest first
+    pub fn scope(&self) -> Vec<Vec2> {
+        let position = self.scope_position.load(Ordering::Relaxed);
+        (0..SCOPE_LENGTH)
+            .map(|idx| &self.scope[(position + idx) % SCOPE_LENGTH])
+            .map(|[l, r]| (l.load(), r.load()))
+            .collect()
+    }
+
+    fn push_scope(&self, (l, r): Vec2) {
+        let position = self.scope_position.load(Ordering::Relaxed);
+        self.scope[position][0].store(l);
+        self.scope[position][1].store(r);
+        self.scope_position
+            .store((position + 1) % SCOPE_LENGTH, Ordering::Relaxed);
+    }
+
+    pub fn parameter(&self, parameter: Parameter) -> f32 {
+        self.parameters[parameter.index()].load()
+    }
+
+    fn update_parameters(&self, patch: &Patch) {
+        for parameter in PARAMETERS {
+            self.parameters[parameter.index()].store(parameter.get(patch));
+        }
+    }
+
+    /// Nothing playing and nothing left ringing out of the effects
+    pub fn is_silent(&self) -> bool {
+        self.active_voices.load(Ordering::Relaxed) == 0 && self.peak() < 1e-4
+    }
+}
+
+/// Per connection state for turning MIDI input into messages
+pub struct MidiHandler {
+    sender: Sender,
+    map: Arc<Mutex<MidiMap>>,
+    /// Selected NRPN, None after an RPN is selected so its data entry gets ignored
+    nrpn: Option<u16>,
+    nrpn_msb: u8,
+    data_msb: u8,
+}
+
+impl MidiHandler {
+    pub fn new(sender: Sender, map: Arc<Mutex<MidiMap>>) -> Self {
+        Self {
+            sender,
+            map,
+            nrpn: None,
+            nrpn_msb: 0,
+            data_msb: 0,
+        }
+    }
+
+    /// Send the value to the bound parameter if there is one, returns whether there was
+    fn set_parameter(&self, controller: Controller, value: f32) -> bool {
+        let parameter = self.map.lock().unwrap().parameter(controller);
+        match parameter {
+            Some(parameter) => {
+                self.sender
+                    .send(Message::SetParameter(parameter, value))
+                    .unwrap();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+pub fn handle_midi_input(timestamp: u64, message: &[u8], handler: &mut MidiHandler) {
     log::trace!(
         "Midi input received: timstamp: {}, message: {:?}",
         timestamp,
@@ -31,8 +229,14 @@ pub fn handle_midi_input(timestamp: u64, message: &[u8], sender: &mut mpsc::Send
         }
     };
 
+    let sender = &handler.sender;
+
     // TODO check if channel is right channel?
     match midi {
+        // Some keyboards send a note on with no velocity instead of note off
+        MidiMessage::NoteOn(_channel, note, velocity) if u8::from(velocity) == 0 => {
+            sender.send(Message::NoteOff(note)).unwrap();
+        }
         MidiMessage::NoteOn(channel, note, velocity) => {
             // TODO We should get the level as the logarithm cause i think linearly mapping velocity doesn't sound right
             let level = u8::from(velocity) as f32 / 127.0;
@@ -41,105 +245,709 @@ pub fn handle_midi_input(timestamp: u64, message: &[u8], sender: &mut mpsc::Send
         MidiMessage::NoteOff(channel, note, velocity) => {
             sender.send(Message::NoteOff(note)).unwrap();
         }
+        MidiMessage::PitchBendChange(_channel, bend) => {
+            let bend: u16 = bend.into();
+            if !handler.set_parameter(Controller::PitchBend, bend as f32 / 16383.0) {
+                let bend = (bend as f32 - 8192.0) / 8192.0;
+                sender.send(Message::PitchBend(bend.max(-1.0))).unwrap();
+            }
+        }
+        MidiMessage::ChannelPressure(_channel, pressure) => {
+            let pressure = u8::from(pressure) as f32 / 127.0;
+            sender.send(Message::Aftertouch(pressure)).unwrap();
+        }
+        MidiMessage::PolyphonicKeyPressure(_channel, note, pressure) => {
+            let pressure = u8::from(pressure) as f32 / 127.0;
+            sender
+                .send(Message::PolyAftertouch(note, pressure))
+                .unwrap();
+        }
+        MidiMessage::ControlChange(_channel, ControlFunction::ALL_SOUND_OFF, _) => {
+            sender.send(Message::AllSoundOff).unwrap();
+        }
+        MidiMessage::ControlChange(_channel, ControlFunction::RESET_ALL_CONTROLLERS, _) => {
+            sender.send(Message::ResetControllers).unwrap();
+        }
+        // Changing modes turns all notes off too
+        MidiMessage::ControlChange(_channel, ControlFunction::ALL_NOTES_OFF, _)
+        | MidiMessage::ControlChange(_channel, ControlFunction::OMNI_MODE_OFF, _)
+        | MidiMessage::ControlChange(_channel, ControlFunction::OMNI_MODE_ON, _)
+        | MidiMessage::ControlChange(_channel, ControlFunction::MONO_OPERATION, _)
+        | MidiMessage::ControlChange(_channel, ControlFunction::POLY_OPERATION, _) => {
+            sender.send(Message::AllNotesOff).unwrap();
+        }
+        MidiMessage::ControlChange(
+            _channel,
+            ControlFunction::NON_REGISTERED_PARAMETER_NUMBER_MSB,
+            value,
+        ) => {
+            handler.nrpn_msb = value.into();
+        }
+        MidiMessage::ControlChange(
+            _channel,
+            ControlFunction::NON_REGISTERED_PARAMETER_NUMBER_LSB,
+            value,
+        ) => {
+            handler.nrpn = Some((handler.nrpn_msb as u16) << 7 | u8::from(value) as u16);
+        }
+        MidiMessage::ControlChange(
+            _channel,
+            ControlFunction::REGISTERED_PARAMETER_NUMBER_MSB,
+            _,
+        )
+        | MidiMessage::ControlChange(
+            _channel,
+            ControlFunction::REGISTERED_PARAMETER_NUMBER_LSB,
+            _,
+        ) => {
+            handler.nrpn = None;
+        }
+        MidiMessage::ControlChange(_channel, ControlFunction::DATA_ENTRY_MSB, value) => {
+            handler.data_msb = value.into();
+            if let Some(nrpn) = handler.nrpn {
+                let value = ((handler.data_msb as u16) << 7) as f32 / 16383.0;
+                handler.set_parameter(Controller::Nrpn(nrpn), value);
+            }
+        }
+        MidiMessage::ControlChange(_channel, ControlFunction::DATA_ENTRY_LSB, value) => {
+            if let Some(nrpn) = handler.nrpn {
+                let value =
+                    ((handler.data_msb as u16) << 7 | u8::from(value) as u16) as f32 / 16383.0;
+                handler.set_parameter(Controller::Nrpn(nrpn), value);
+            }
+        }
+        MidiMessage::ControlChange(_channel, function, value) => {
+            let value = u8::from(value) as f32 / 127.0;
+            let handled = handler.set_parameter(Controller::Cc(u8::from(function.0)), value);
+            if !handled && function == ControlFunction::MODULATION_WHEEL {
+                sender.send(Message::ModWheel(value)).unwrap();
+            }
+        }
+        MidiMessage::TimingClock => sender.send(Message::Clock(timestamp)).unwrap(),
+        MidiMessage::Start => sender.send(Message::Start).unwrap(),
+        MidiMessage::Continue => sender.send(Message::Continue).unwrap(),
+        MidiMessage::Stop => sender.send(Message::Stop).unwrap(),
+        MidiMessage::Reset => sender.send(Message::Reset).unwrap(),
+        MidiMessage::SongPositionPointer(position) => {
+            let position: u16 = position.into();
+            sender.send(Message::SongPosition(position)).unwrap();
+        }
+        MidiMessage::SysEx(data) => {
+            if let Some(retunings) = tuning::parse_mts(U7::data_to_bytes(data)) {
+                log::debug!("Retuning {} notes", retunings.len());
+                for (note, freq) in retunings {
+                    sender.send(Message::RetuneNote(note, freq)).unwrap();
+                }
+            }
+        }
         _ => {}
     }
 }
 
-pub fn do_audio<T: Sample>(
-    channel_count: usize, // TODO
-    samplerate: SampleRate,
-    opts: &Opts,
-    receiver: mpsc::Receiver<Message>,
-) -> impl FnMut(&mut [T], &cpal::OutputCallbackInfo) -> () {
-    use crate::synthesis::*;
+/// MIDI from a plugin host. Only what's needed to play, MIDI learn and the clock are for
+/// the standalone program
+pub fn plugin_midi(data: &[u8]) -> Option<Message> {
+    match MidiMessage::try_from(data).ok()? {
+        MidiMessage::NoteOn(_, note, velocity) if u8::from(velocity) == 0 => {
+            Some(Message::NoteOff(note))
+        }
+        MidiMessage::NoteOn(_, note, velocity) => {
+            Some(Message::NoteOn(note, u8::from(velocity) as f32 / 127.0))
+        }
+        MidiMessage::NoteOff(_, note, _) => Some(Message::NoteOff(note)),
+        MidiMessage::PitchBendChange(_, bend) => {
+            let bend = (u16::from(bend) as f32 - 8192.0) / 8192.0;
+            Some(Message::PitchBend(bend.max(-1.0)))
+        }
+        MidiMessage::ChannelPressure(_, pressure) => {
+            Some(Message::Aftertouch(u8::from(pressure) as f32 / 127.0))
+        }
+        MidiMessage::PolyphonicKeyPressure(_, note, pressure) => Some(Message::PolyAftertouch(
+            note,
+            u8::from(pressure) as f32 / 127.0,
+        )),
+        MidiMessage::ControlChange(_, ControlFunction::MODULATION_WHEEL, value) => {
+            Some(Message::ModWheel(u8::from(value) as f32 / 127.0))
+        }
+        MidiMessage::ControlChange(_, ControlFunction::ALL_SOUND_OFF, _) => {
+            Some(Message::AllSoundOff)
+        }
+        MidiMessage::ControlChange(_, ControlFunction::ALL_NOTES_OFF, _) => {
+            Some(Message::AllNotesOff)
+        }
+        MidiMessage::ControlChange(_, ControlFunction::RESET_ALL_CONTROLLERS, _) => {
+            Some(Message::ResetControllers)
+        }
+        _ => None,
+    }
+}
 
-    let envelope_duration = Duration::from_secs(1);
+/// How to set up a Synth
+pub struct Config {
+    pub sample_rate: u32,
+    /// 0 for as many as there can be
+    pub voices: usize,
+    pub patch: Patch,
+}
 
-    let num_voices = if opts.voices == 0 {
-        MAX_VOICES
-    } else {
-        (opts.voices as usize).clamp(1, MAX_VOICES)
-    };
+/// The whole synth. Tell it what's happening with `handle_event` and it fills buffers with
+/// stereo audio in `process` or `process_planar`
+pub struct Synth {
+    patch: Patch,
+    samplerate: f32,
+    timer: SampleTimer,
+    voices: Vec<Voice>,
+    next_voice_idx: usize,
+    /// Only the entries for global LFOs are used, the rest are there to keep the indices lined up
+    global_lfos: Vec<Lfo>,
+    global_modulation: Modulation,
+    global_sources: Sources,
+    solids: Solids,
+    effects: Effects,
+    master_transform: Transform,
+    transport: Transport,
+    arpeggiator: Arpeggiator,
+    /// For everything random on the audio thread
+    rng: Rng,
+    /// Events for the next buffer and the frame they happen on, in order
+    events: Vec<(usize, Message)>,
+    /// Notes coming out of the arpeggiator go through here, along with everything else. No
+    /// more than MAX_EVENTS either
+    messages: Vec<Message>,
+    status: Arc<Status>,
+    log: audio_log::Writer,
+    /// Until someone takes it to do the logging
+    log_reader: Option<audio_log::Reader>,
+    /// Frames in this buffer that went past full scale
+    clipped: u32,
+}
+
+impl Synth {
+    pub fn new(config: Config) -> Self {
+        let patch = config.patch;
+        let num_voices = if config.voices == 0 {
+            MAX_VOICES
+        } else {
+            config.voices.clamp(1, MAX_VOICES)
+        };
+
+        let voices = (0..num_voices)
+            .map(|_| Voice {
+                note: Note::C0,
+                level: 0.0,
+                envelope: amp_envelope(&patch),
+                filter: Filter::new(),
+                transform: Transform::new(),
+                spin: Spin::new(),
+                superformula: Superformula::default(),
+                filter_envelope: filter_envelope(&patch),
+                lfos: lfos(&patch),
+                aftertouch: 0.0,
+                random: 0.0,
+                modulation: Modulation::default(),
+                phase: 0.0,
+            })
+            .collect::<Vec<Voice>>();
+
+        let global_sources = Sources {
+            global_lfos: global_lfo_flags(&patch),
+            ..Sources::default()
+        };
+        let (log, log_reader) = audio_log::ring();
+
+        Self {
+            samplerate: config.sample_rate as f32,
+            timer: SampleTimer::new(config.sample_rate),
+            voices,
+            next_voice_idx: 0,
+            global_lfos: lfos(&patch),
+            global_modulation: Modulation::default(),
+            global_sources,
+            solids: Solids::new(),
+            effects: Effects::new(config.sample_rate),
+            master_transform: Transform::new(),
+            transport: Transport::new(patch.tempo),
+            arpeggiator: Arpeggiator::new(),
+            rng: Rng::new(rand::random()),
+            events: Vec::with_capacity(MAX_EVENTS),
+            messages: Vec::with_capacity(MAX_EVENTS),
+            status: Arc::new(Status::new(&patch)),
+            patch,
+            log,
+            log_reader: Some(log_reader),
+            clipped: 0,
+        }
+    }
+
+    /// What's playing, for other threads to look at
+    pub fn status(&self) -> Arc<Status> {
+        self.status.clone()
+    }
+
+    /// The other end of the audio thread's log, for something to pass on to `log`. There's
+    /// only the one so it's None after the first time
+    pub fn take_log(&mut self) -> Option<audio_log::Reader> {
+        self.log_reader.take()
+    }
 
-    let master_gain = opts.master_gain;
-
-    let attack = opts.attack;
-    let decay = opts.decay;
-    let sustain = opts.sustain;
-    let release = opts.release;
-    let corners = opts.corners;
-    let mod_rate = opts.mod_rate;
-    let mod_amount = opts.mod_amount;
-
-    let mut voices = (0..num_voices)
-        .map(|_| Voice {
-            note: Note::C0,
-            level: 0.0,
-            envelope: Envelope::new(attack, decay, sustain, release),
-            lfo_timer: SampleTimer::new(samplerate.0),
-        })
-        .collect::<Vec<Voice>>();
-
-    let mut next_voice_idx = 0;
-
-    let mut audio = move |timer: &SampleTimer| {
-        while let Ok(message) = receiver.try_recv() {
+    /// Something to happen `frame_offset` frames into the next buffer. Anything past the end
+    /// of it happens at the start of the one after. Dropped if there are already
+    /// MAX_EVENTS waiting, making room would mean allocating. A new patch is swapped in
+    /// straight away. Whatever the synth doesn't keep comes back, to be freed somewhere other
+    /// than the audio thread
+    pub fn handle_event(&mut self, event: Message, frame_offset: usize) -> Option<Message> {
+        if let Message::LoadPatch(mut loaded) = event {
+            self.load_patch(&mut loaded);
+            return Some(Message::LoadPatch(loaded));
+        }
+        if self.events.len() == MAX_EVENTS {
+            self.log.push(Record::EventDropped);
+            return Some(event);
+        }
+        let idx = self
+            .events
+            .iter()
+            .position(|(offset, _)| *offset > frame_offset)
+            .unwrap_or(self.events.len());
+        self.events.insert(idx, (frame_offset, event));
+        None
+    }
+
+    /// Swap everything over to the new patch, leaving the old one in `loaded`
+    fn load_patch(&mut self, loaded: &mut LoadedPatch) {
+        std::mem::swap(&mut self.patch, &mut loaded.patch);
+        std::mem::swap(&mut self.global_lfos, &mut loaded.global_lfos);
+        let patch = &self.patch;
+        self.arpeggiator.clear();
+        self.transport.set_internal_tempo(patch.tempo);
+        // Voices playing the old sound would pop into the new one
+        all_sound_off(&mut self.voices, &mut self.effects);
+        for (voice, lfos) in self.voices.iter_mut().zip(&mut loaded.voice_lfos) {
+            voice.envelope = amp_envelope(patch);
+            voice.filter_envelope = filter_envelope(patch);
+            std::mem::swap(&mut voice.lfos, lfos);
+        }
+        self.global_sources.global_lfos = global_lfo_flags(patch);
+        self.status.update_parameters(patch);
+    }
+
+    /// Fill a buffer of interleaved stereo frames
+    pub fn process(&mut self, output: &mut [f32]) {
+        let started = Instant::now();
+        let mut peak = (0.0f32, 0.0f32);
+        for (idx, frame) in output.chunks_mut(2).enumerate() {
+            let (l, r) = self.frame(idx);
+            peak = (peak.0.max(l.abs()), peak.1.max(r.abs()));
+            for (dst, src) in frame.iter_mut().zip(&[l, r]) {
+                *dst = *src;
+            }
+        }
+        self.finish(started, output.len() / 2, peak);
+    }
+
+    /// Fill separate left and right buffers
+    pub fn process_planar(&mut self, left: &mut [f32], right: &mut [f32]) {
+        let started = Instant::now();
+        let mut peak = (0.0f32, 0.0f32);
+        for (idx, (left, right)) in left.iter_mut().zip(right.iter_mut()).enumerate() {
+            let (l, r) = self.frame(idx);
+            peak = (peak.0.max(l.abs()), peak.1.max(r.abs()));
+            *left = l;
+            *right = r;
+        }
+        self.finish(started, left.len().min(right.len()), peak);
+    }
+
+    fn frame(&mut self, idx: usize) -> Vec2 {
+        self.receive_until(idx);
+        let output = self.next_frame();
+        if output.0.abs() > 1.0 || output.1.abs() > 1.0 {
+            self.clipped += 1;
+        }
+        self.status.push_scope(output);
+        self.timer += 1;
+        output
+    }
+
+    fn finish(&mut self, started: Instant, frames: usize, peak: Vec2) {
+        self.receive_until(usize::MAX);
+        self.status.peak[0].store(peak.0);
+        self.status.peak[1].store(peak.1);
+        // Only for showing people, once a buffer is plenty
+        let timer = &self.timer;
+        for (voice, voice_status) in self.voices.iter().zip(&self.status.voices) {
+            voice_status
+                .note
+                .store(u8::from(voice.note), Ordering::Relaxed);
+            voice_status.level.store(voice.envelope.get(timer));
+            voice_status
+                .stage
+                .store(voice.envelope.stage(timer) as u8, Ordering::Relaxed);
+        }
+        // How much of the time we've got to fill the buffer was spent doing it
+        let load = started.elapsed().as_secs_f32() * self.samplerate / (frames as f32).max(1.0);
+        self.status.load.store(load);
+        if load > 1.0 {
+            self.log.push(Record::Xrun { load });
+        }
+        if self.clipped > 0 {
+            self.log.push(Record::Clipped {
+                frames: self.clipped,
+                peak: peak.0.max(peak.1),
+            });
+            self.clipped = 0;
+        }
+    }
+
+    /// Take in the events up to and including this frame
+    fn receive_until(&mut self, frame: usize) {
+        let due = self
+            .events
+            .iter()
+            .take_while(|(offset, _)| *offset <= frame)
+            .count();
+        // Taken out while receiving, putting it back keeps its capacity
+        let mut events = std::mem::take(&mut self.events);
+        for (_, message) in events.drain(..due) {
+            self.receive(message);
+        }
+        self.events = events;
+    }
+
+    fn receive(&mut self, message: Message) {
+        let arp = &self.patch.arpeggiator;
+        match message {
+            Message::NoteOn(note, level) if arp.mode.is_some() => {
+                self.arpeggiator.note_on(arp, note, level)
+            }
+            Message::NoteOff(note) if arp.mode.is_some() => self.arpeggiator.note_off(arp, note),
+            Message::Clock(timestamp) => {
+                if !self.transport.is_external() {
+                    self.log.push(Record::FollowingClock);
+                }
+                self.transport.clock(timestamp);
+                self.arpeggiator.clock();
+            }
+            Message::Start => self.transport.start(),
+            Message::Continue => self.transport.resume(),
+            Message::Stop => self.transport.stop(),
+            Message::SongPosition(position) => self.transport.song_position(position),
+            Message::SetParameter(parameter, value) => {
+                let arp_mode = self.patch.arpeggiator.mode;
+                parameter.apply(&mut self.patch, value);
+                self.transport.set_internal_tempo(self.patch.tempo);
+                self.status.parameters[parameter.index()].store(parameter.get(&self.patch));
+                if parameter.is_envelope() {
+                    let patch = &self.patch;
+                    for voice in &mut self.voices {
+                        voice.envelope.set_adsr(
+                            patch.attack,
+                            patch.decay,
+                            patch.sustain,
+                            patch.release,
+                        );
+                    }
+                }
+                if parameter.is_filter_envelope() {
+                    let patch = &self.patch;
+                    for voice in &mut self.voices {
+                        voice.filter_envelope.set_adsr(
+                            patch.filter_attack,
+                            patch.filter_decay,
+                            patch.filter_sustain,
+                            patch.filter_release,
+                        );
+                    }
+                }
+                // Notes held from before would never get their note off
+                if self.patch.arpeggiator.mode != arp_mode {
+                    self.arpeggiator.clear();
+                    push_message(&mut self.messages, &mut self.log, Message::AllNotesOff);
+                }
+            }
+            Message::AllNotesOff | Message::AllSoundOff | Message::Reset => {
+                self.arpeggiator.clear();
+                push_message(&mut self.messages, &mut self.log, message);
+            }
+            message => push_message(&mut self.messages, &mut self.log, message),
+        }
+    }
+
+    fn next_frame(&mut self) -> Vec2 {
+        let Synth {
+            patch,
+            samplerate,
+            timer,
+            voices,
+            next_voice_idx,
+            global_lfos,
+            global_modulation,
+            global_sources,
+            solids,
+            effects,
+            master_transform,
+            transport,
+            arpeggiator,
+            rng,
+            messages,
+            status,
+            log,
+            ..
+        } = self;
+        let samplerate_f = *samplerate;
+        let timer: &SampleTimer = timer;
+        let num_voices = voices.len();
+
+        let was_external = transport.is_external();
+        transport.tick(samplerate_f);
+        if was_external && !transport.is_external() {
+            log.push(Record::LostClock);
+        }
+        let tempo = transport.tempo();
+        // Free running tempo synced LFOs stay in time with whatever's sending us clock
+        let beat = if transport.is_external() {
+            Some(transport.beat(samplerate_f))
+        } else {
+            None
+        };
+
+        arpeggiator.tick(&patch.arpeggiator, tempo, samplerate_f, rng, |message| {
+            push_message(messages, log, message)
+        });
+
+        for message in messages.drain(..) {
             match message {
+                Message::NoteOn(note, _) if !patch.tuning.is_mapped(note) => {}
                 Message::NoteOn(note, level) => {
                     let voice: &mut Voice = match voices.iter_mut().find(|v| v.note == note) {
                         Some(voice) => voice,
                         None => {
-                            let ref mut voice = voices[next_voice_idx % num_voices];
-                            next_voice_idx += 1;
+                            let voice = &mut voices[*next_voice_idx % num_voices];
+                            *next_voice_idx += 1;
+                            if voice.envelope.stage(timer) != Stage::Off {
+                                log.push(Record::VoiceStolen {
+                                    old: voice.note,
+                                    new: note,
+                                });
+                            }
                             voice
                         }
                     };
 
                     voice.note = note;
                     voice.level = level;
+                    voice.aftertouch = 0.0;
+                    voice.random = rng.bipolar();
                     voice.envelope.hold(timer);
-                    voice.lfo_timer.reset();
-                    next_voice_idx += 1;
+                    voice.filter_envelope.hold(timer);
+                    voice.filter.reset();
+                    voice.transform.reset();
+                    voice.spin.reset();
+                    for (lfo, params) in voice.lfos.iter_mut().zip(&patch.lfos) {
+                        if params.trigger == lfo::Trigger::Retrigger {
+                            lfo.trigger(params, rng);
+                        }
+                    }
+                    for (lfo, params) in global_lfos.iter_mut().zip(&patch.lfos) {
+                        if params.scope == lfo::Scope::Global
+                            && params.trigger == lfo::Trigger::Retrigger
+                        {
+                            lfo.trigger(params, rng);
+                        }
+                    }
+                    *next_voice_idx += 1;
                 }
                 Message::NoteOff(note) => {
-                    for voice in &mut voices {
+                    for voice in voices.iter_mut() {
                         if voice.note == note {
                             voice.envelope.release(timer);
+                            voice.filter_envelope.release(timer);
+                        }
+                    }
+                }
+                Message::PitchBend(bend) => global_sources.pitch_bend = bend,
+                Message::Aftertouch(pressure) => global_sources.aftertouch = pressure,
+                Message::PolyAftertouch(note, pressure) => {
+                    for voice in voices.iter_mut() {
+                        if voice.note == note {
+                            voice.aftertouch = pressure;
                         }
                     }
                 }
+                Message::ModWheel(value) => global_sources.mod_wheel = value,
+                Message::RetuneNote(note, freq) => patch.tuning.retune(note, freq),
+                Message::AllNotesOff => {
+                    for voice in voices.iter_mut() {
+                        voice.envelope.release(timer);
+                        voice.filter_envelope.release(timer);
+                    }
+                }
+                Message::AllSoundOff => all_sound_off(voices, effects),
+                Message::ResetControllers => reset_controllers(voices, global_sources),
+                Message::Reset => {
+                    all_sound_off(voices, effects);
+                    reset_controllers(voices, global_sources);
+                    master_transform.reset();
+                }
+                Message::Clock(_)
+                | Message::Start
+                | Message::Continue
+                | Message::Stop
+                | Message::SongPosition(_)
+                | Message::SetParameter(_, _)
+                | Message::LoadPatch(_) => {}
             };
         }
 
+        // LFO rate modulation uses the modulation amounts from the previous sample
+        for (idx, (lfo, params)) in global_lfos.iter_mut().zip(&patch.lfos).enumerate() {
+            if params.scope == lfo::Scope::Global {
+                if let Some(beat) = beat {
+                    lfo.sync(params, beat, rng);
+                }
+                let freq =
+                    params.rate.freq(tempo) * f32::powf(2.0, global_modulation.lfo_rate[idx]);
+                global_sources.lfos[idx] = lfo.next(params, freq, samplerate_f, rng);
+            }
+        }
+        *global_modulation = patch.matrix.apply_global(global_sources);
+
         let (mut left, mut right) = (0.0, 0.0);
+        let mut active_voices = 0;
 
         for voice in voices.iter_mut() {
             let level = voice.envelope.get(timer);
             if level > 0.0 {
-                let lfo = f32::sin(2.0 * core::f32::consts::PI * phase(mod_rate, &voice.lfo_timer))
-                    * mod_amount;
-                let (l, r) = vec2::scale(
-                    polygon(corners + lfo, phase(voice.note.to_freq_f32(), timer)),
-                    level * voice.level,
+                active_voices += 1;
+                let mut sources = *global_sources;
+                for (idx, (lfo, params)) in voice.lfos.iter_mut().zip(&patch.lfos).enumerate() {
+                    if params.scope == lfo::Scope::Voice {
+                        if let Some(beat) = beat {
+                            lfo.sync(params, beat, rng);
+                        }
+                        let freq = params.rate.freq(tempo)
+                            * f32::powf(2.0, voice.modulation.lfo_rate[idx]);
+                        sources.lfos[idx] = lfo.next(params, freq, samplerate_f, rng);
+                    }
+                }
+                sources.envelope = level;
+                sources.filter_envelope = voice.filter_envelope.get(timer);
+                sources.velocity = voice.level;
+                sources.note = u8::from(voice.note) as f32 / 127.0;
+                sources.aftertouch = sources.aftertouch.max(voice.aftertouch);
+                sources.random = voice.random;
+
+                let mods = patch.matrix.apply(&sources);
+                voice.modulation = mods;
+
+                let shape_params = patch.shape_params.add(&mods.shape);
+                let freq = patch.tuning.freq(voice.note) * f32::powf(2.0, mods.pitch / 12.0);
+                let gain = (1.0 + mods.level).max(0.0);
+                let pan = mods.pan.clamp(-1.0, 1.0);
+
+                let key_tracking = patch.key_tracking * (u8::from(voice.note) as f32 - 60.0) / 12.0;
+                let cutoff = patch.cutoff
+                    * f32::powf(
+                        2.0,
+                        key_tracking
+                            + patch.filter_env_amount * sources.filter_envelope
+                            + mods.cutoff,
+                    );
+
+                let p = voice.phase;
+                voice.phase = advance_phase(p, freq, samplerate_f);
+                let shape = match solids.point(patch.shape, patch.knot, p) {
+                    Some(point) => {
+                        let spin = maths::vec3::add(patch.spin, mods.spin);
+                        let point = voice.spin.process(point, spin, samplerate_f);
+                        project(point, patch.perspective + mods.perspective)
+                    }
+                    None => flat_shape(patch.shape, &shape_params, &mut voice.superformula, p),
+                };
+                let filtered = voice.filter.process(
+                    patch.filter_mode,
+                    shape,
+                    cutoff,
+                    patch.resonance + mods.resonance,
+                    samplerate_f,
                 );
+                let transformed = voice.transform.process(
+                    &patch.voice_transform,
+                    &mods.transform,
+                    filtered,
+                    samplerate_f,
+                );
+                let (l, r) = vec2::scale(transformed, level * voice.level * gain);
 
-                voice.lfo_timer += 1;
-                left += l;
-                right += r;
+                left += l * (1.0 - pan).min(1.0);
+                right += r * (1.0 + pan).min(1.0);
             }
         }
 
-        (left, right)
-    };
+        status.active_voices.store(active_voices, Ordering::Relaxed);
+        status.tempo.store(tempo);
+        status
+            .external_clock
+            .store(beat.is_some(), Ordering::Relaxed);
 
-    let mut timer = SampleTimer::new(samplerate.0);
-    move |data: &mut [T], _info: &cpal::OutputCallbackInfo| {
-        for frame in data.chunks_mut(2) {
-            let (l, r) = audio(&timer);
-            for (dst, src) in frame.iter_mut().zip(&[l, r]) {
-                *dst = Sample::from(&(src * master_gain))
-            }
-            timer += 1;
-        }
+        let output = effects.process(&patch.effects, (left, right), tempo);
+        let output = master_transform.process(
+            &patch.master_transform,
+            &global_modulation.master_transform,
+            output,
+            samplerate_f,
+        );
+        vec2::scale(output, patch.master_gain)
+    }
+}
+
+/// Events past the end of a buffer wait in here along with the next buffer's, so it gets
+/// bounded the same way as `Synth::handle_event`
+fn push_message(messages: &mut Vec<Message>, log: &mut audio_log::Writer, message: Message) {
+    if messages.len() == MAX_EVENTS {
+        log.push(Record::EventDropped);
+    } else {
+        messages.push(message);
+    }
+}
+
+fn amp_envelope(patch: &Patch) -> Envelope {
+    Envelope::new(patch.attack, patch.decay, patch.sustain, patch.release)
+}
+
+fn filter_envelope(patch: &Patch) -> Envelope {
+    Envelope::new(
+        patch.filter_attack,
+        patch.filter_decay,
+        patch.filter_sustain,
+        patch.filter_release,
+    )
+}
+
+fn lfos(patch: &Patch) -> Vec<Lfo> {
+    patch.lfos.iter().map(Lfo::new).collect()
+}
+
+fn global_lfo_flags(patch: &Patch) -> [bool; MAX_LFOS] {
+    let mut flags = [false; MAX_LFOS];
+    for (idx, params) in patch.lfos.iter().enumerate() {
+        flags[idx] = params.scope == lfo::Scope::Global;
+    }
+    flags
+}
+
+fn all_sound_off(voices: &mut [Voice], effects: &mut Effects) {
+    for voice in voices {
+        voice.reset();
+    }
+    effects.clear();
+}
+
+fn reset_controllers(voices: &mut [Voice], sources: &mut Sources) {
+    sources.pitch_bend = 0.0;
+    sources.aftertouch = 0.0;
+    sources.mod_wheel = 0.0;
+    for voice in voices {
+        voice.aftertouch = 0.0;
     }
 }
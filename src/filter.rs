@@ -0,0 +1,97 @@
+use crate::vec2::Vec2;
+
+use anyhow::{anyhow, Result};
+use core::f32::consts::PI;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilterMode {
+    Off,
+    LowPass,
+    BandPass,
+    HighPass,
+    Notch,
+}
+
+impl std::str::FromStr for FilterMode {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<FilterMode> {
+        match s.to_lowercase().as_str() {
+            "off" | "none" => Ok(FilterMode::Off),
+            "lp" | "lowpass" => Ok(FilterMode::LowPass),
+            "bp" | "bandpass" => Ok(FilterMode::BandPass),
+            "hp" | "highpass" => Ok(FilterMode::HighPass),
+            "notch" => Ok(FilterMode::Notch),
+            _ => Err(anyhow!("Invalid value \"{}\" for FilterMode", s)),
+        }
+    }
+}
+
+/// Resonant state variable filter, the trapezoidal integrated one from Andrew Simper's
+/// "Linear Trap Integrated State Variable Filter" paper. Filters x and y separately
+pub struct Filter {
+    ic1: Vec2,
+    ic2: Vec2,
+}
+
+impl Filter {
+    pub fn new() -> Self {
+        Self {
+            ic1: (0.0, 0.0),
+            ic2: (0.0, 0.0),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.ic1 = (0.0, 0.0);
+        self.ic2 = (0.0, 0.0);
+    }
+
+    /// Resonance goes from 0 to 1, where 1 is just short of self oscillation
+    pub fn process(
+        &mut self,
+        mode: FilterMode,
+        input: Vec2,
+        cutoff: f32,
+        resonance: f32,
+        samplerate: f32,
+    ) -> Vec2 {
+        if mode == FilterMode::Off {
+            return input;
+        }
+
+        let cutoff = cutoff.clamp(20.0, samplerate * 0.49);
+        let k = 2.0 - 2.0 * resonance.clamp(0.0, 0.98);
+        let g = f32::tan(PI * cutoff / samplerate);
+        let a1 = 1.0 / (1.0 + g * (g + k));
+        let a2 = g * a1;
+        let a3 = g * a2;
+
+        let tick = |v0: f32, ic1: &mut f32, ic2: &mut f32| {
+            let v3 = v0 - *ic2;
+            let v1 = a1 * *ic1 + a2 * v3;
+            let v2 = *ic2 + a2 * *ic1 + a3 * v3;
+            *ic1 = 2.0 * v1 - *ic1;
+            *ic2 = 2.0 * v2 - *ic2;
+
+            let (low, band) = (v2, v1);
+            let high = v0 - k * band - low;
+            match mode {
+                FilterMode::LowPass => low,
+                FilterMode::BandPass => band,
+                FilterMode::HighPass => high,
+                FilterMode::Notch => low + high,
+                FilterMode::Off => v0,
+            }
+        };
+
+        let x = tick(input.0, &mut self.ic1.0, &mut self.ic2.0);
+        let y = tick(input.1, &mut self.ic1.1, &mut self.ic2.1);
+        (x, y)
+    }
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
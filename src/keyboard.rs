@@ -0,0 +1,241 @@
+use shapes::channel::Sender;
+use shapes::engine::Message;
+
+use anyhow::{anyhow, Result};
+use crossterm::event::{
+    self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, KeyboardEnhancementFlags,
+    PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+};
+use crossterm::terminal;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::Write;
+use std::time::{Duration, Instant};
+use wmidi::Note;
+
+/// How often to check for notes to let go of and whether to stop
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// Most terminals only say when a key goes down, then repeat it while it's held. A note is
+/// let go once its key hasn't come through for this long, which has to be longer than the
+/// delay before keys start repeating
+const HOLD_TIMEOUT: Duration = Duration::from_millis(700);
+const VELOCITY_STEP: u8 = 16;
+
+/// Tracker style, two rows of keys per octave. The bottom two rows start at the current
+/// octave and the top two an octave up
+const LAYOUT: &[(char, u8)] = &[
+    ('z', 0),
+    ('s', 1),
+    ('x', 2),
+    ('d', 3),
+    ('c', 4),
+    ('v', 5),
+    ('g', 6),
+    ('b', 7),
+    ('h', 8),
+    ('n', 9),
+    ('j', 10),
+    ('m', 11),
+    (',', 12),
+    ('l', 13),
+    ('.', 14),
+    (';', 15),
+    ('/', 16),
+    ('q', 12),
+    ('2', 13),
+    ('w', 14),
+    ('3', 15),
+    ('e', 16),
+    ('r', 17),
+    ('5', 18),
+    ('t', 19),
+    ('6', 20),
+    ('y', 21),
+    ('7', 22),
+    ('u', 23),
+    ('i', 24),
+    ('9', 25),
+    ('o', 26),
+    ('0', 27),
+    ('p', 28),
+    ('[', 29),
+    ('=', 30),
+    (']', 31),
+];
+
+struct Keyboard<'a> {
+    sender: &'a Sender,
+    /// MIDI octave of the z key, C4 is middle C
+    octave: i8,
+    velocity: u8,
+    /// Notes playing for each key, and when the key last came through
+    held: HashMap<char, (Note, Instant)>,
+    /// Whether the terminal tells us when keys are let go
+    releases: bool,
+}
+
+impl<'a> Keyboard<'a> {
+    /// Returns false when it's time to quit
+    fn key(&mut self, key: KeyEvent) -> Result<bool> {
+        match (key.code, key.kind) {
+            (KeyCode::Char('c'), _) if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                return Ok(false)
+            }
+            (KeyCode::Esc, KeyEventKind::Press) => return Ok(false),
+            (KeyCode::Char(c), KeyEventKind::Release) => {
+                if let Some((note, _)) = self.held.remove(&c.to_ascii_lowercase()) {
+                    self.send(Message::NoteOff(note))?;
+                }
+            }
+            (KeyCode::Char(c), _) => {
+                let c = c.to_ascii_lowercase();
+                if let Some((held, _)) = self.held.get(&c) {
+                    // Repeating while held
+                    let held = *held;
+                    self.held.insert(c, (held, Instant::now()));
+                } else if let Some(note) = self.note(c) {
+                    self.send(Message::NoteOn(note, self.velocity as f32 / 127.0))?;
+                    self.held.insert(c, (note, Instant::now()));
+                }
+            }
+            (KeyCode::Up, KeyEventKind::Press) => self.set_octave(self.octave + 1)?,
+            (KeyCode::Down, KeyEventKind::Press) => self.set_octave(self.octave - 1)?,
+            (KeyCode::Right, KeyEventKind::Press) => {
+                self.velocity = self.velocity.saturating_add(VELOCITY_STEP).min(127);
+                self.show()?;
+            }
+            (KeyCode::Left, KeyEventKind::Press) => {
+                self.velocity = self.velocity.saturating_sub(VELOCITY_STEP).max(1);
+                self.show()?;
+            }
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    fn note(&self, c: char) -> Option<Note> {
+        let (_, offset) = LAYOUT.iter().find(|(key, _)| *key == c)?;
+        let note = (self.octave as i16 + 1) * 12 + *offset as i16;
+        u8::try_from(note)
+            .ok()
+            .and_then(|note| Note::try_from(note).ok())
+    }
+
+    fn set_octave(&mut self, octave: i8) -> Result<()> {
+        // Anything held would never get a note off for the right note
+        self.release_all()?;
+        self.octave = octave.clamp(-1, 8);
+        self.show()
+    }
+
+    /// Let go of notes whose keys haven't repeated for a while
+    fn release_stale(&mut self) -> Result<()> {
+        if self.releases {
+            return Ok(());
+        }
+        let stale = self
+            .held
+            .iter()
+            .filter(|(_, (_, seen))| seen.elapsed() > HOLD_TIMEOUT)
+            .map(|(key, _)| *key)
+            .collect::<Vec<char>>();
+        for key in stale {
+            if let Some((note, _)) = self.held.remove(&key) {
+                self.send(Message::NoteOff(note))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn release_all(&mut self) -> Result<()> {
+        for (_, (note, _)) in self.held.drain() {
+            self.sender
+                .send(Message::NoteOff(note))
+                .map_err(|_| anyhow!("Audio isn't running"))?;
+        }
+        Ok(())
+    }
+
+    fn send(&self, message: Message) -> Result<()> {
+        self.sender
+            .send(message)
+            .map_err(|_| anyhow!("Audio isn't running"))
+    }
+
+    /// Raw mode needs the carriage return
+    fn show(&self) -> Result<()> {
+        let mut stdout = std::io::stdout();
+        write!(
+            stdout,
+            "\r\x1b[Koctave {}, velocity {}",
+            self.octave, self.velocity
+        )?;
+        stdout.flush()?;
+        Ok(())
+    }
+}
+
+/// Puts the terminal back however we leave
+struct RawMode {
+    enhanced: bool,
+    log_level: log::LevelFilter,
+}
+
+impl RawMode {
+    fn new() -> Result<Self> {
+        terminal::enable_raw_mode()?;
+        // Only some terminals can report keys being let go
+        let enhanced = terminal::supports_keyboard_enhancement().unwrap_or(false);
+        if enhanced {
+            crossterm::execute!(
+                std::io::stdout(),
+                PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES)
+            )?;
+        }
+        // Log lines come out as a staircase in raw mode
+        let log_level = log::max_level();
+        log::set_max_level(log::LevelFilter::Off);
+        Ok(Self {
+            enhanced,
+            log_level,
+        })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        if self.enhanced {
+            let _ = crossterm::execute!(std::io::stdout(), PopKeyboardEnhancementFlags);
+        }
+        let _ = terminal::disable_raw_mode();
+        println!();
+        log::set_max_level(self.log_level);
+    }
+}
+
+/// Play notes from the computer keyboard until escape or `should_stop` says to
+pub fn run(sender: &Sender, mut should_stop: impl FnMut() -> bool) -> Result<()> {
+    println!("Playing from the computer keyboard: z to / and q to ] are notes, up and down change octave, left and right change velocity, escape quits");
+    let raw_mode = RawMode::new()?;
+    let mut keyboard = Keyboard {
+        sender,
+        octave: 3,
+        velocity: 100,
+        held: HashMap::new(),
+        releases: raw_mode.enhanced,
+    };
+    keyboard.show()?;
+
+    while !should_stop() {
+        keyboard.release_stale()?;
+        if !event::poll(POLL_INTERVAL)? {
+            continue;
+        }
+        if let Event::Key(key) = event::read()? {
+            if !keyboard.key(key)? {
+                break;
+            }
+        }
+    }
+    keyboard.release_all()
+}
@@ -0,0 +1,238 @@
+use crate::util::{parse_note_division, Rng};
+
+use anyhow::{anyhow, Result};
+use core::f32::consts::PI;
+use std::str::FromStr;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Waveform {
+    Sine,
+    Triangle,
+    Saw,
+    Square,
+    /// Holds a new random value every cycle
+    SampleAndHold,
+}
+
+impl Waveform {
+    /// Bipolar output in [-1, 1] for a phase in [0, 1). `held` is only used for sample and hold
+    pub fn sample(&self, p: f32, held: f32) -> f32 {
+        match self {
+            Waveform::Sine => f32::sin(2.0 * PI * p),
+            Waveform::Triangle => 1.0 - 4.0 * (p - 0.5).abs(),
+            Waveform::Saw => 2.0 * p - 1.0,
+            Waveform::Square => {
+                if p < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::SampleAndHold => held,
+        }
+    }
+}
+
+impl FromStr for Waveform {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Waveform> {
+        match s.to_lowercase().as_str() {
+            "sin" | "sine" => Ok(Waveform::Sine),
+            "tri" | "triangle" => Ok(Waveform::Triangle),
+            "saw" => Ok(Waveform::Saw),
+            "sqr" | "square" => Ok(Waveform::Square),
+            "sh" | "s&h" | "random" => Ok(Waveform::SampleAndHold),
+            _ => Err(anyhow!("Invalid value \"{}\" for Waveform", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rate {
+    Hz(f32),
+    /// Length of one cycle in beats, follows the tempo
+    Beats(f32),
+}
+
+impl Rate {
+    pub fn freq(&self, tempo: f32) -> f32 {
+        match self {
+            Rate::Hz(freq) => *freq,
+            Rate::Beats(beats) => tempo / 60.0 / beats,
+        }
+    }
+}
+
+impl FromStr for Rate {
+    type Err = anyhow::Error;
+    /// Either a frequency in Hz ("2.5") or a note division ("1/4", "1/8d", "1/16t")
+    fn from_str(s: &str) -> Result<Rate> {
+        match parse_note_division(s)? {
+            Some(beats) => Ok(Rate::Beats(beats)),
+            None => Ok(Rate::Hz(f32::from_str(s)?)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trigger {
+    /// Phase keeps running regardless of notes
+    Free,
+    /// Phase and fade in restart on every note on
+    Retrigger,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scope {
+    /// Every voice has its own LFO state
+    Voice,
+    /// One LFO state shared by all voices
+    Global,
+}
+
+#[derive(Debug, Clone)]
+pub struct LfoParams {
+    pub waveform: Waveform,
+    pub rate: Rate,
+    pub trigger: Trigger,
+    pub scope: Scope,
+    /// Starting phase, 0 to 1
+    pub phase: f32,
+    pub fade_in: Duration,
+}
+
+impl Default for LfoParams {
+    fn default() -> Self {
+        Self {
+            waveform: Waveform::Sine,
+            rate: Rate::Hz(1.0),
+            trigger: Trigger::Retrigger,
+            scope: Scope::Voice,
+            phase: 0.0,
+            fade_in: Duration::from_secs(0),
+        }
+    }
+}
+
+impl FromStr for LfoParams {
+    type Err = anyhow::Error;
+    /// Comma separated, the waveform first then key=value pairs, e.g.
+    /// "tri,rate=1/8,mode=free,scope=global,phase=0.25,fade=0.5"
+    fn from_str(s: &str) -> Result<LfoParams> {
+        let mut params = LfoParams::default();
+        let mut fields = s.split(',').map(str::trim);
+
+        if let Some(waveform) = fields.next() {
+            params.waveform = Waveform::from_str(waveform)?;
+        }
+
+        for field in fields {
+            let (key, value) = field.split_once('=').ok_or(anyhow!(
+                "Expected key=value in LFO options, got \"{}\"",
+                field
+            ))?;
+            match key {
+                "rate" => params.rate = Rate::from_str(value)?,
+                "phase" => params.phase = f32::from_str(value)?.rem_euclid(1.0),
+                "fade" => {
+                    // Negative, infinite and absurdly long would all panic in Duration
+                    params.fade_in = Duration::try_from_secs_f32(f32::from_str(value)?)
+                        .map_err(|_| anyhow!("Invalid LFO fade \"{}\", it's in seconds", value))?
+                }
+                "mode" => {
+                    params.trigger = match value {
+                        "free" => Trigger::Free,
+                        "retrig" | "retrigger" => Trigger::Retrigger,
+                        _ => return Err(anyhow!("Invalid LFO mode \"{}\"", value)),
+                    }
+                }
+                "scope" => {
+                    params.scope = match value {
+                        "voice" => Scope::Voice,
+                        "global" => Scope::Global,
+                        _ => return Err(anyhow!("Invalid LFO scope \"{}\"", value)),
+                    }
+                }
+                _ => return Err(anyhow!("Unknown LFO option \"{}\"", key)),
+            }
+        }
+
+        Ok(params)
+    }
+}
+
+/// Running state of an LFO, the parameters live in the patch
+pub struct Lfo {
+    phase: f32,
+    held: f32,
+    age: u64,
+}
+
+impl Lfo {
+    pub fn new(params: &LfoParams) -> Self {
+        Self {
+            phase: params.phase,
+            // Made off the audio thread, where rand's own generator is fine
+            held: rand::random::<f32>() * 2.0 - 1.0,
+            age: 0,
+        }
+    }
+
+    pub fn trigger(&mut self, params: &LfoParams, rng: &mut Rng) {
+        self.phase = params.phase;
+        self.held = rng.bipolar();
+        self.age = 0;
+    }
+
+    /// Get the current value and advance by one sample
+    pub fn next(&mut self, params: &LfoParams, freq: f32, samplerate: f32, rng: &mut Rng) -> f32 {
+        let value = params.waveform.sample(self.phase, self.held);
+
+        let fade_in = params.fade_in.as_secs_f32();
+        let fade = if fade_in > 0.0 {
+            (self.age as f32 / samplerate / fade_in).min(1.0)
+        } else {
+            1.0
+        };
+
+        self.phase += freq / samplerate;
+        if !(0.0..1.0).contains(&self.phase) {
+            self.phase = self.phase.rem_euclid(1.0);
+            self.held = rng.bipolar();
+        }
+        self.age = self.age.saturating_add(1);
+
+        value * fade
+    }
+
+    /// Lock the phase to the song position. Only does anything for free running LFOs with a
+    /// rate in beats, and ignores rate modulation
+    pub fn sync(&mut self, params: &LfoParams, beat: f64, rng: &mut Rng) {
+        let beats = match params.rate {
+            Rate::Beats(beats) if params.trigger == Trigger::Free && beats > 0.0 => beats,
+            _ => return,
+        };
+        let phase = ((beat / beats as f64) as f32 + params.phase).rem_euclid(1.0);
+        if phase < self.phase - 0.5 {
+            // Wrapped around
+            self.held = rng.bipolar();
+        }
+        self.phase = phase;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fade() {
+        let params = LfoParams::from_str("sine,fade=0.5").unwrap();
+        assert_eq!(params.fade_in, Duration::from_millis(500));
+        for fade in &["-1", "inf", "NaN", "1e30", "soon"] {
+            let options = format!("sine,fade={}", fade);
+            assert!(LfoParams::from_str(&options).is_err(), "{}", fade);
+        }
+    }
+}
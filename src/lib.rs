@@ -0,0 +1,28 @@
+//! The synth on its own, without anything for talking to sound cards or MIDI devices.
+//! Make a [`Synth`], send it [`Message`]s and ask it for audio
+
+pub mod arpeggiator;
+pub mod audio_log;
+pub mod channel;
+pub mod constants;
+pub mod effects;
+pub mod engine;
+pub mod filter;
+pub mod lfo;
+pub mod maths;
+pub mod midi_map;
+pub mod modulation;
+pub mod opts;
+pub mod parameter;
+pub mod patch;
+mod queue;
+pub mod synthesis;
+pub mod transform;
+pub mod transport;
+pub mod tuning;
+pub mod util;
+pub mod wireframe;
+pub use maths::vec2;
+
+pub use engine::{Config, Message, Synth};
+pub use patch::Patch;
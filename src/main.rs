@@ -1,27 +1,25 @@
-#![feature(unboxed_closures)]
-#![feature(trait_alias)]
-#![feature(never_type)]
-#![feature(box_syntax)]
-#![feature(destructuring_assignment)]
-#![feature(str_split_once)]
-
-mod constants;
-mod engine;
-mod maths;
-mod opts;
-mod queue;
-mod synthesis;
-mod util;
-pub use maths::vec2;
+mod audio;
+mod control;
+mod keyboard;
+mod midi;
+mod osc;
+mod tui;
 
+use shapes::{audio_log, channel, engine, midi_map, opts, patch};
+
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
-use anyhow::{anyhow, Result};
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::Host;
-use midir::{MidiInput, MidiInputPort};
+use anyhow::Result;
+use cpal::traits::{DeviceTrait, HostTrait};
+use midi::MIDI_INPUT_NAME;
+use midir::MidiInput;
+use signal_hook::consts::{SIGINT, SIGTERM, SIGUSR1};
+use signal_hook::iterator::Signals;
 
-const MIDI_INPUT_NAME: &str = env!("CARGO_PKG_NAME");
+/// Longest to wait for notes to finish on shutdown
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
 fn init_logging(opts: &opts::Opts) {
     use log::LevelFilter::*;
@@ -44,10 +42,14 @@ fn init_logging(opts: &opts::Opts) {
 fn main() {
     let opts = opts::getopts();
 
-    let host = cpal::default_host();
+    if opts.list_hosts {
+        audio::list_hosts();
+    }
 
     if opts.list_outputs {
-        let devices = host.output_devices();
+        let devices = audio::host(opts.host)
+            .map_err(|e| e.to_string())
+            .and_then(|host| host.output_devices().map_err(|e| e.to_string()));
         match devices {
             Ok(devices) => {
                 // ALSA generates a fuckton of annoying and useless error output on startup.
@@ -83,117 +85,133 @@ fn main() {
         }
     }
 
-    if opts.list_inputs || opts.list_outputs {
+    if opts.list_inputs || opts.list_outputs || opts.list_hosts {
         return;
     }
 
     init_logging(&opts);
 
-    match run(host, opts) {
-        Ok(_) => {} // unreachable
-        Err(e) => log::error!("Fatal error: {}", e),
+    if let Err(e) = run(opts) {
+        log::error!("Fatal error: {}", e);
+        std::process::exit(1);
     }
 }
 
-fn run(host: Host, opts: opts::Opts) -> Result<!> {
-    let mut input = MidiInput::new(MIDI_INPUT_NAME)?;
-    input.ignore(midir::Ignore::None);
-    let ports: &[MidiInputPort] = &input.ports();
-    let port = if let Some(name) = &opts.midi_port {
-        log::debug!("Connecting to port with name {}", name);
-        ports
-            .iter()
-            .find(|port| {
-                input
-                    .port_name(port)
-                    .map(|port_name| port_name.starts_with(name))
-                    .unwrap_or(false)
-            })
-            .ok_or(anyhow!("No MIDI port named {}", name))?
-    } else if let Some(index) = opts.midi_port_index {
-        log::debug!("Connecting to port with index {}", index);
-        ports
-            .get(index)
-            .ok_or(anyhow!("Port index {} out of range", index))?
-    } else {
-        log::debug!("Connecting to first available port");
-        match ports {
-            [] => Err(anyhow!("No available MIDI ports")),
-            [port, ..] => Ok(port),
-        }?
-    };
+/// Runs until SIGINT or SIGTERM, SIGUSR1 stops all sound
+fn run(opts: opts::Opts) -> Result<()> {
+    let midi_selection = midi::Selection::from_opts(&opts)?;
+    let keyboard = opts.keyboard || matches!(midi_selection, midi::Selection::Keyboard);
 
-    let port_name = input.port_name(&port).unwrap_or(String::from("<unknown>"));
+    let output = audio::open_output(&opts)?;
+    output.log_info();
 
-    log::info!("Reading MIDI input from {}", port_name);
+    let patch = patch::Patch::from_opts(&opts)?;
 
-    let device = {
-        let _alsa_gag = gag::Gag::stderr().unwrap();
-        if opts.device == "default" {
-            host.default_output_device()
-        } else {
-            host.output_devices()?
-                .find(|d| d.name().map(|name| name == opts.device).unwrap_or(false))
+    let mut midi_map = match opts.midi_map.clone().or_else(midi_map::default_path) {
+        Some(path) => midi_map::MidiMap::load(&path)?,
+        None => {
+            log::warn!("Nowhere to keep MIDI bindings, set HOME or pass --midi-map");
+            midi_map::MidiMap::new()
         }
-        .ok_or(anyhow!(
-            "Couldn't connect to output device \"{}\"",
-            opts.device
-        ))?
     };
-
-    log::info!(
-        "Outputting to \"{}\"",
-        device.name().unwrap_or(String::from("unknown"))
-    );
-
-    // TODO make fallback options? or just error out if not supported config
-    // TODO Ok yeah we definitely should validate before this point, alsa just spits out "INVALID ARGUMENT >:("
-    let config = cpal::StreamConfig {
-        channels: 2,
-        sample_rate: opts.sample_rate,
-        buffer_size: match opts.buffer_size {
-            Some(frames) => cpal::BufferSize::Fixed(frames),
-            None => cpal::BufferSize::Default,
-        },
+    if let Some(parameter) = opts.learn {
+        midi_map.learn(parameter);
+    }
+    let midi_map = Arc::new(Mutex::new(midi_map));
+
+    let (sender, receiver) = channel::channel();
+    let virtual_port = opts
+        .virtual_midi
+        .clone()
+        .map(|name| name.unwrap_or(String::from(MIDI_INPUT_NAME)));
+    let midi_watcher = midi::watch(
+        midi_selection,
+        virtual_port,
+        sender.clone(),
+        midi_map.clone(),
+    )?;
+    let osc_server = match opts.osc_port {
+        Some(port) => Some(osc::serve(port, sender.clone())?),
+        None => None,
+    };
+    let mut synth = engine::Synth::new(engine::Config {
+        sample_rate: output.sample_rate(),
+        voices: opts.voices as usize,
+        patch,
+    });
+    let status = synth.status();
+    let audio_log = synth.take_log().map(audio_log::Reader::spawn);
+    let control_server = match &opts.control_socket {
+        Some(path) => Some(control::serve(
+            path,
+            sender.clone(),
+            status.clone(),
+            midi_map.clone(),
+        )?),
+        None => None,
     };
 
-    log::info!("channels: {}", config.channels);
-    log::info!("sample rate: {}", config.sample_rate.0);
-    log::info!(
-        "buffer size: {}",
-        match config.buffer_size {
-            cpal::BufferSize::Fixed(frames) => frames.to_string(),
-            cpal::BufferSize::Default => "default".to_string(),
-        }
-    );
-
-    let (sender, receiver) = std::sync::mpsc::channel();
-
-    let _connection = input
-        .connect(&port, MIDI_INPUT_NAME, engine::handle_midi_input, sender)
-        .map_err(|e| {
-            anyhow!(
-                "Couldn't connect to MIDI output port \"{}\": {}",
-                port_name,
-                e
-            )
+    let mut signals = Signals::new([SIGINT, SIGTERM, SIGUSR1])?;
+
+    let playing = output.start(synth, receiver, opts.jack_connect.as_deref())?;
+
+    if opts.tui {
+        let options = std::env::args().skip(1).collect();
+        tui::run(&sender, &status, &midi_map, options, || {
+            signals
+                .pending()
+                .any(|signal| !keep_running(signal, &sender))
+        })?;
+    } else if keyboard {
+        keyboard::run(&sender, || {
+            signals
+                .pending()
+                .any(|signal| !keep_running(signal, &sender))
         })?;
+    } else {
+        for signal in signals.forever() {
+            if !keep_running(signal, &sender) {
+                break;
+            }
+        }
+    }
 
-    let errfun = |err| log::error!("Audio output stream error: {}", err);
-    let stream = device.build_output_stream(
-        &config,
-        engine::do_audio::<f32>(
-            config.channels as usize,
-            config.sample_rate,
-            &opts,
-            receiver,
-        ),
-        errfun,
-    )?;
+    midi_watcher.stop();
+    if let Some(server) = osc_server {
+        server.stop();
+    }
+    if let Some(server) = control_server {
+        server.stop();
+    }
+    sender.send(engine::Message::AllNotesOff)?;
+
+    // Let the release tails play out, unless we get told to stop again
+    let started = Instant::now();
+    while !status.is_silent() && started.elapsed() < SHUTDOWN_TIMEOUT {
+        if signals.pending().any(|signal| signal != SIGUSR1) {
+            log::info!("Not waiting for notes to finish");
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    if !status.is_silent() {
+        log::debug!("Still making sound after {:?}", SHUTDOWN_TIMEOUT);
+    }
+
+    playing.stop();
+    if let Some(audio_log) = audio_log {
+        audio_log.stop();
+    }
+    Ok(())
+}
 
-    stream.play()?;
-    loop {
-        thread::park();
-        log::trace!("main thread unparked");
+/// SIGUSR1 is the panic button, anything else means stop
+fn keep_running(signal: i32, sender: &channel::Sender) -> bool {
+    if signal == SIGUSR1 {
+        log::info!("Resetting all voices");
+        sender.send(engine::Message::Reset).is_ok()
+    } else {
+        log::info!("Got signal {}, shutting down", signal);
+        false
     }
 }
@@ -57,4 +57,54 @@ pub mod vec2 {
     pub fn scale(v: Vec2, s: f32) -> Vec2 {
         (v.0 * s, v.1 * s)
     }
+
+    /// Component-wise
+    pub fn mul(a: Vec2, b: Vec2) -> Vec2 {
+        (a.0 * b.0, a.1 * b.1)
+    }
+
+    /// `s.0` shears along x, `s.1` along y
+    pub fn shear(v: Vec2, s: Vec2) -> Vec2 {
+        (v.0 + s.0 * v.1, v.1 + s.1 * v.0)
+    }
+
+    /// Counter-clockwise, theta in radians
+    pub fn rotate(v: Vec2, theta: f32) -> Vec2 {
+        let (sin, cos) = theta.sin_cos();
+        (v.0 * cos - v.1 * sin, v.0 * sin + v.1 * cos)
+    }
+}
+
+pub mod vec3 {
+    pub type Vec3 = (f32, f32, f32);
+
+    pub fn lerp(a: Vec3, b: Vec3, alpha: f32) -> Vec3 {
+        add(scale(a, 1.0 - alpha), scale(b, alpha))
+    }
+
+    pub fn add(a: Vec3, b: Vec3) -> Vec3 {
+        (a.0 + b.0, a.1 + b.1, a.2 + b.2)
+    }
+
+    pub fn sub(a: Vec3, b: Vec3) -> Vec3 {
+        (a.0 - b.0, a.1 - b.1, a.2 - b.2)
+    }
+
+    pub fn scale(v: Vec3, s: f32) -> Vec3 {
+        (v.0 * s, v.1 * s, v.2 * s)
+    }
+
+    pub fn length(v: Vec3) -> f32 {
+        (v.0 * v.0 + v.1 * v.1 + v.2 * v.2).sqrt()
+    }
+
+    /// Rotate around the x, then y, then z axis. Angles in radians
+    pub fn rotate(v: Vec3, angles: Vec3) -> Vec3 {
+        let (sin, cos) = angles.0.sin_cos();
+        let v = (v.0, v.1 * cos - v.2 * sin, v.1 * sin + v.2 * cos);
+        let (sin, cos) = angles.1.sin_cos();
+        let v = (v.0 * cos + v.2 * sin, v.1, -v.0 * sin + v.2 * cos);
+        let (sin, cos) = angles.2.sin_cos();
+        (v.0 * cos - v.1 * sin, v.0 * sin + v.1 * cos, v.2)
+    }
 }
@@ -0,0 +1,284 @@
+use shapes::channel::Sender;
+use shapes::engine::{self, Message, MidiHandler};
+use shapes::midi_map::MidiMap;
+use shapes::opts::Opts;
+
+use anyhow::{anyhow, Result};
+use midir::{MidiInput, MidiInputConnection};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+pub const MIDI_INPUT_NAME: &str = env!("CARGO_PKG_NAME");
+
+/// How often to look for ports that appeared or went away
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Which MIDI input ports to read from
+#[derive(Debug, Clone)]
+pub enum Selection {
+    All,
+    Named {
+        /// Ports with names starting with any of these, from --midi-port
+        prefixes: Vec<String>,
+        /// Ports with exactly these names, leaving out the numbers on the end
+        ports: Vec<String>,
+    },
+    /// No ports, the computer keyboard gets played instead
+    Keyboard,
+}
+
+impl Selection {
+    /// Ports picked by index, or the first one when nothing is given, are remembered by name
+    /// so they can be found again if they get unplugged
+    pub fn from_opts(opts: &Opts) -> Result<Self> {
+        if opts.midi_port.iter().any(|name| name == "all") {
+            log::debug!("Connecting to all ports");
+            return Ok(Selection::All);
+        }
+
+        let input = MidiInput::new(MIDI_INPUT_NAME)?;
+        let names = input
+            .ports()
+            .iter()
+            .map(|port| input.port_name(port).unwrap_or(String::from("<unknown>")))
+            .collect::<Vec<String>>();
+
+        let prefixes = opts.midi_port.clone();
+        let mut ports = Vec::new();
+        for index in &opts.midi_port_index {
+            log::debug!("Connecting to port with index {}", index);
+            let name = names
+                .get(*index)
+                .ok_or(anyhow!("Port index {} out of range", index))?;
+            ports.push(without_numbers(name).to_string());
+        }
+
+        let nothing_selected = prefixes.is_empty() && ports.is_empty();
+        if nothing_selected && (opts.virtual_midi.is_some() || opts.keyboard) {
+            // Only reading from the virtual port or the computer keyboard
+            return Ok(Selection::Named { prefixes, ports });
+        }
+
+        if nothing_selected {
+            log::debug!("Connecting to first available port");
+            match names.first() {
+                Some(name) => ports.push(without_numbers(name).to_string()),
+                None if opts.tui => log::warn!("No available MIDI ports"),
+                None => {
+                    log::warn!("No available MIDI ports, playing from the computer keyboard");
+                    return Ok(Selection::Keyboard);
+                }
+            }
+        }
+
+        for name in &opts.midi_port {
+            if !names.iter().any(|port_name| port_name.starts_with(name)) {
+                log::warn!(
+                    "No MIDI port named {} yet, will connect when it shows up",
+                    name
+                );
+            }
+        }
+
+        Ok(Selection::Named { prefixes, ports })
+    }
+
+    fn matches(&self, port_name: &str) -> bool {
+        match self {
+            Selection::All => true,
+            Selection::Named { prefixes, ports } => {
+                prefixes.iter().any(|prefix| port_name.starts_with(prefix))
+                    || ports.iter().any(|name| name == without_numbers(port_name))
+            }
+            Selection::Keyboard => false,
+        }
+    }
+}
+
+/// Port names end in their ALSA client and port numbers, e.g. "Midi Through:Midi Through
+/// Port-0 14:0", which can change when the device is plugged in again
+fn without_numbers(port_name: &str) -> &str {
+    match port_name.rsplit_once(' ') {
+        Some((name, numbers))
+            if !numbers.is_empty() && numbers.chars().all(|c| c.is_ascii_digit() || c == ':') =>
+        {
+            name
+        }
+        _ => port_name,
+    }
+}
+
+/// Connections to every selected port, merging their input into one channel
+struct MidiInputs {
+    /// For looking at what ports there are, connecting uses up a MidiInput so each
+    /// connection gets its own
+    input: MidiInput,
+    selection: Selection,
+    sender: Sender,
+    map: Arc<Mutex<MidiMap>>,
+    connections: Vec<(String, MidiInputConnection<MidiHandler>)>,
+}
+
+impl MidiInputs {
+    fn handler(&self) -> MidiHandler {
+        MidiHandler::new(self.sender.clone(), self.map.clone())
+    }
+}
+
+impl MidiInputs {
+    /// Connect to selected ports that aren't connected yet and drop the ones that went away
+    fn refresh(&mut self) -> Result<()> {
+        let ports = self.input.ports();
+        let names = ports
+            .iter()
+            .map(|port| {
+                self.input
+                    .port_name(port)
+                    .unwrap_or(String::from("<unknown>"))
+            })
+            .collect::<Vec<String>>();
+
+        // Dropping a connection closes it
+        let connected = self.connections.len();
+        self.connections.retain(|(name, _)| {
+            let present = names.contains(name);
+            if !present {
+                log::info!("MIDI port {} went away", name);
+            }
+            present
+        });
+        if self.connections.len() < connected {
+            // Whatever was held down on it isn't getting a note off
+            self.sender.send(Message::AllNotesOff)?;
+        }
+
+        for (port, name) in ports.iter().zip(&names) {
+            if !self.selection.matches(name)
+                || self
+                    .connections
+                    .iter()
+                    .any(|(connected, _)| connected == name)
+            {
+                continue;
+            }
+
+            let mut input = MidiInput::new(MIDI_INPUT_NAME)?;
+            input.ignore(midir::Ignore::None);
+            match input.connect(
+                port,
+                MIDI_INPUT_NAME,
+                engine::handle_midi_input,
+                self.handler(),
+            ) {
+                Ok(connection) => {
+                    log::info!("Reading MIDI input from {}", name);
+                    self.connections.push((name.clone(), connection));
+                }
+                Err(e) => log::warn!("Couldn't connect to MIDI port \"{}\": {}", name, e),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A port other programs can connect to, like sequencers or `aconnect`
+#[cfg(unix)]
+fn create_virtual_port(
+    inputs: &MidiInputs,
+    port_name: &str,
+) -> Result<MidiInputConnection<MidiHandler>> {
+    use midir::os::unix::VirtualInput;
+
+    let mut input = MidiInput::new(MIDI_INPUT_NAME)?;
+    input.ignore(midir::Ignore::None);
+    let connection = input
+        .create_virtual(port_name, engine::handle_midi_input, inputs.handler())
+        .map_err(|e| anyhow!("Couldn't create virtual MIDI port \"{}\": {}", port_name, e))?;
+    log::info!("Reading MIDI input from virtual port {}", port_name);
+    Ok(connection)
+}
+
+#[cfg(not(unix))]
+fn create_virtual_port(
+    _inputs: &MidiInputs,
+    _port_name: &str,
+) -> Result<MidiInputConnection<MidiHandler>> {
+    Err(anyhow!(
+        "Virtual MIDI ports aren't supported on this platform"
+    ))
+}
+
+/// Handle to the thread keeping the MIDI connections
+pub struct MidiWatcher {
+    stop: Arc<AtomicBool>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl MidiWatcher {
+    /// Close every connection, nothing more gets sent after this returns
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        self.thread.thread().unpark();
+        if self.thread.join().is_err() {
+            log::error!("MIDI watcher thread panicked");
+        }
+    }
+}
+
+/// Keep connected to the selected ports on a background thread until stopped, along with
+/// a virtual port if there's a name for one
+pub fn watch(
+    selection: Selection,
+    virtual_port: Option<String>,
+    sender: Sender,
+    map: Arc<Mutex<MidiMap>>,
+) -> Result<MidiWatcher> {
+    // Connections have to stay on the thread that made them, so it reports back whether
+    // the virtual port worked out
+    let (ready_sender, ready) = mpsc::channel();
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = stop.clone();
+
+    let thread = thread::spawn(move || {
+        let input = match MidiInput::new(MIDI_INPUT_NAME) {
+            Ok(input) => input,
+            Err(e) => {
+                ready_sender.send(Err(e.into())).unwrap();
+                return;
+            }
+        };
+        let mut inputs = MidiInputs {
+            input,
+            selection,
+            sender,
+            map,
+            connections: Vec::new(),
+        };
+
+        let _virtual_connection = match virtual_port {
+            Some(name) => match create_virtual_port(&inputs, &name) {
+                Ok(connection) => Some(connection),
+                Err(e) => {
+                    ready_sender.send(Err(e)).unwrap();
+                    return;
+                }
+            },
+            None => None,
+        };
+        ready_sender.send(Ok(())).unwrap();
+
+        while !stopped.load(Ordering::Relaxed) {
+            if let Err(e) = inputs.refresh() {
+                log::error!("Couldn't look for MIDI ports: {}", e);
+            }
+            thread::park_timeout(WATCH_INTERVAL);
+        }
+        log::debug!("Closing MIDI connections");
+    });
+
+    ready.recv()??;
+    Ok(MidiWatcher { stop, thread })
+}
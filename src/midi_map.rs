@@ -0,0 +1,160 @@
+use crate::parameter::Parameter;
+
+use anyhow::{anyhow, Context, Result};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Something on a MIDI controller that can be bound to a parameter
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Controller {
+    /// Control change number
+    Cc(u8),
+    /// Non-registered parameter number, 14 bits
+    Nrpn(u16),
+    PitchBend,
+}
+
+impl fmt::Display for Controller {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Controller::Cc(number) => write!(f, "cc{}", number),
+            Controller::Nrpn(number) => write!(f, "nrpn{}", number),
+            Controller::PitchBend => write!(f, "bend"),
+        }
+    }
+}
+
+impl FromStr for Controller {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Controller> {
+        let s = s.to_lowercase();
+        if let Some(number) = s.strip_prefix("cc") {
+            return match u8::from_str(number)? {
+                number if number < 128 => Ok(Controller::Cc(number)),
+                _ => Err(anyhow!("CC number must be less than 128")),
+            };
+        }
+        if let Some(number) = s.strip_prefix("nrpn") {
+            return match u16::from_str(number)? {
+                number if number < 16384 => Ok(Controller::Nrpn(number)),
+                _ => Err(anyhow!("NRPN number must be less than 16384")),
+            };
+        }
+        match s.as_str() {
+            "bend" => Ok(Controller::PitchBend),
+            _ => Err(anyhow!("Invalid value \"{}\" for controller", s)),
+        }
+    }
+}
+
+/// Which controllers are bound to which parameters. Shared between the MIDI input handlers
+/// and whatever starts learning
+#[derive(Default)]
+pub struct MidiMap {
+    bindings: Vec<(Controller, Parameter)>,
+    /// Bind the next controller that moves to this
+    learning: Option<Parameter>,
+    /// Where the bindings are saved after learning
+    path: Option<PathBuf>,
+}
+
+impl MidiMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A missing file is fine, that just means nothing has been learned yet
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut map = Self::new();
+        map.path = Some(path.to_path_buf());
+        if !path.exists() {
+            return Ok(map);
+        }
+
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Couldn't read MIDI map {}", path.display()))?;
+        for (idx, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let binding = match line.split_whitespace().collect::<Vec<&str>>().as_slice() {
+                [controller, parameter] => (
+                    Controller::from_str(controller),
+                    Parameter::from_str(parameter),
+                ),
+                _ => {
+                    return Err(anyhow!(
+                        "Expected \"controller parameter\" on line {} of {}",
+                        idx + 1,
+                        path.display()
+                    ))
+                }
+            };
+            match binding {
+                (Ok(controller), Ok(parameter)) => map.bindings.push((controller, parameter)),
+                (Err(e), _) | (_, Err(e)) => {
+                    return Err(e.context(format!("Line {} of {}", idx + 1, path.display())))
+                }
+            }
+        }
+
+        log::debug!(
+            "Loaded {} MIDI bindings from {}",
+            map.bindings.len(),
+            path.display()
+        );
+        Ok(map)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let mut contents = String::from("# MIDI bindings, controller then parameter\n");
+        for (controller, parameter) in &self.bindings {
+            contents += &format!("{} {}\n", controller, parameter);
+        }
+        std::fs::write(path, contents)
+            .with_context(|| format!("Couldn't write MIDI map {}", path.display()))
+    }
+
+    /// Bind the next controller that moves to `parameter`
+    pub fn learn(&mut self, parameter: Parameter) {
+        log::info!("Move a controller to bind it to {}", parameter);
+        self.learning = Some(parameter);
+    }
+
+    /// What the next controller that moves gets bound to
+    pub fn learning(&self) -> Option<Parameter> {
+        self.learning
+    }
+
+    /// The parameter a controller is bound to, binding it first if we're learning
+    pub fn parameter(&mut self, controller: Controller) -> Option<Parameter> {
+        if let Some(parameter) = self.learning.take() {
+            self.bindings.retain(|(c, _)| *c != controller);
+            self.bindings.push((controller, parameter));
+            log::info!("Bound {} to {}", controller, parameter);
+            if let Err(e) = self.save() {
+                log::error!("Couldn't save MIDI bindings: {}", e);
+            }
+        }
+
+        self.bindings
+            .iter()
+            .find(|(c, _)| *c == controller)
+            .map(|(_, parameter)| *parameter)
+    }
+}
+
+/// midi-map in the config directory
+pub fn default_path() -> Option<PathBuf> {
+    Some(crate::util::config_dir()?.join("midi-map"))
+}
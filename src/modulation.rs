@@ -0,0 +1,425 @@
+use crate::constants::*;
+use crate::maths::vec3::Vec3;
+use crate::synthesis::ShapeParams;
+use crate::transform::{TransformParam, TRANSFORM_PARAMS};
+
+use anyhow::{anyhow, Result};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    /// Index into the patch's LFOs
+    Lfo(usize),
+    /// The amplitude envelope
+    Envelope,
+    FilterEnvelope,
+    Velocity,
+    Note,
+    Aftertouch,
+    ModWheel,
+    PitchBend,
+    /// New random value on every note on
+    Random,
+}
+
+impl Source {
+    /// Whether the value is the same for every voice
+    pub fn is_global(&self, sources: &Sources) -> bool {
+        match self {
+            Source::Lfo(idx) => sources.global_lfos[*idx],
+            Source::ModWheel | Source::PitchBend => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the source naturally goes from -1 to 1 instead of 0 to 1
+    pub fn is_bipolar(&self) -> bool {
+        matches!(self, Source::Lfo(_) | Source::PitchBend | Source::Random)
+    }
+}
+
+impl FromStr for Source {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Source> {
+        let s = s.to_lowercase();
+        if let Some(idx) = s.strip_prefix("lfo") {
+            return Ok(Source::Lfo(parse_lfo_index(idx)?));
+        }
+        match s.as_str() {
+            "env" | "envelope" => Ok(Source::Envelope),
+            "fenv" | "filter-envelope" => Ok(Source::FilterEnvelope),
+            "vel" | "velocity" => Ok(Source::Velocity),
+            "note" => Ok(Source::Note),
+            "at" | "aftertouch" => Ok(Source::Aftertouch),
+            "mw" | "modwheel" => Ok(Source::ModWheel),
+            "bend" | "pitchbend" => Ok(Source::PitchBend),
+            "rand" | "random" => Ok(Source::Random),
+            _ => Err(anyhow!("Invalid value \"{}\" for modulation source", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Destination {
+    /// Number of corners of the polygon
+    Corners,
+    /// Lissajous frequency ratio of x
+    RatioX,
+    RatioY,
+    /// Lissajous phase offset, in turns
+    ShapePhase,
+    /// Spirograph ring radius
+    Ring,
+    /// Spirograph wheel radius
+    Wheel,
+    /// Spirograph pen distance
+    Pen,
+    /// Superformula parameters
+    M,
+    N1,
+    N2,
+    N3,
+    /// Semitones
+    Pitch,
+    /// Relative to the voice level, -1 silences the voice
+    Level,
+    /// -1 is hard left, 1 is hard right
+    Pan,
+    /// 3D rotation speed around the x axis, in turns per second
+    SpinX,
+    SpinY,
+    SpinZ,
+    Perspective,
+    /// The per-voice transform
+    Transform(TransformParam),
+    /// The master transform, only global sources have any effect on it
+    MasterTransform(TransformParam),
+    /// In octaves
+    LfoRate(usize),
+    /// In octaves
+    Cutoff,
+    Resonance,
+}
+
+impl FromStr for Destination {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Destination> {
+        let s = s.to_lowercase();
+        if let Some(idx) = s.strip_prefix("lfo").and_then(|s| s.strip_suffix("-rate")) {
+            return Ok(Destination::LfoRate(parse_lfo_index(idx)?));
+        }
+        if let Some(param) = s.strip_prefix("master-") {
+            return Ok(Destination::MasterTransform(TransformParam::from_str(
+                param,
+            )?));
+        }
+        if let Ok(param) = TransformParam::from_str(&s) {
+            return Ok(Destination::Transform(param));
+        }
+        match s.as_str() {
+            "corners" => Ok(Destination::Corners),
+            "ratio-x" => Ok(Destination::RatioX),
+            "ratio-y" => Ok(Destination::RatioY),
+            "shape-phase" => Ok(Destination::ShapePhase),
+            "ring" => Ok(Destination::Ring),
+            "wheel" => Ok(Destination::Wheel),
+            "pen" => Ok(Destination::Pen),
+            "m" => Ok(Destination::M),
+            "n1" => Ok(Destination::N1),
+            "n2" => Ok(Destination::N2),
+            "n3" => Ok(Destination::N3),
+            "pitch" => Ok(Destination::Pitch),
+            "level" => Ok(Destination::Level),
+            "pan" => Ok(Destination::Pan),
+            "spin-x" => Ok(Destination::SpinX),
+            "spin-y" => Ok(Destination::SpinY),
+            "spin-z" => Ok(Destination::SpinZ),
+            "perspective" => Ok(Destination::Perspective),
+            "cutoff" => Ok(Destination::Cutoff),
+            "resonance" => Ok(Destination::Resonance),
+            _ => Err(anyhow!(
+                "Invalid value \"{}\" for modulation destination",
+                s
+            )),
+        }
+    }
+}
+
+/// LFOs are numbered from 1 on the command line
+fn parse_lfo_index(s: &str) -> Result<usize> {
+    let idx = usize::from_str(s)?;
+    if idx == 0 || idx > MAX_LFOS {
+        return Err(anyhow!("LFO number must be between 1 and {}", MAX_LFOS));
+    }
+    Ok(idx - 1)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Polarity {
+    /// 0 to 1
+    Unipolar,
+    /// -1 to 1
+    Bipolar,
+}
+
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub source: Source,
+    pub destination: Destination,
+    pub amount: f32,
+    pub polarity: Polarity,
+}
+
+impl FromStr for Route {
+    type Err = anyhow::Error;
+    /// source:destination:amount[:uni|bi], e.g. "lfo1:corners:2" or "vel:level:0.5:bi".
+    /// Polarity defaults to whatever the source naturally is
+    fn from_str(s: &str) -> Result<Route> {
+        let fields = s.split(':').map(str::trim).collect::<Vec<&str>>();
+        let (source, destination, amount, polarity) = match fields.as_slice() {
+            [source, destination, amount] => (source, destination, amount, None),
+            [source, destination, amount, polarity] => {
+                (source, destination, amount, Some(polarity))
+            }
+            _ => {
+                return Err(anyhow!(
+                    "Expected source:destination:amount[:polarity], got \"{}\"",
+                    s
+                ))
+            }
+        };
+
+        let source = Source::from_str(source)?;
+        let polarity = match polarity.map(|p| p.to_lowercase()).as_deref() {
+            Some("u") | Some("uni") | Some("unipolar") => Polarity::Unipolar,
+            Some("b") | Some("bi") | Some("bipolar") => Polarity::Bipolar,
+            Some(other) => return Err(anyhow!("Invalid polarity \"{}\"", other)),
+            None if source.is_bipolar() => Polarity::Bipolar,
+            None => Polarity::Unipolar,
+        };
+
+        Ok(Route {
+            source,
+            destination: Destination::from_str(destination)?,
+            amount: f32::from_str(amount)?,
+            polarity,
+        })
+    }
+}
+
+/// The current value of every modulation source
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sources {
+    pub lfos: [f32; MAX_LFOS],
+    /// Which of the LFOs are shared between voices
+    pub global_lfos: [bool; MAX_LFOS],
+    pub envelope: f32,
+    pub filter_envelope: f32,
+    pub velocity: f32,
+    pub note: f32,
+    pub aftertouch: f32,
+    pub mod_wheel: f32,
+    pub pitch_bend: f32,
+    pub random: f32,
+}
+
+impl Sources {
+    pub fn get(&self, source: Source) -> f32 {
+        match source {
+            Source::Lfo(idx) => self.lfos[idx],
+            Source::Envelope => self.envelope,
+            Source::FilterEnvelope => self.filter_envelope,
+            Source::Velocity => self.velocity,
+            Source::Note => self.note,
+            Source::Aftertouch => self.aftertouch,
+            Source::ModWheel => self.mod_wheel,
+            Source::PitchBend => self.pitch_bend,
+            Source::Random => self.random,
+        }
+    }
+}
+
+/// Summed modulation amounts for every destination
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Modulation {
+    pub shape: ShapeParams,
+    pub pitch: f32,
+    pub level: f32,
+    pub pan: f32,
+    pub spin: Vec3,
+    pub perspective: f32,
+    pub transform: [f32; TRANSFORM_PARAMS],
+    pub master_transform: [f32; TRANSFORM_PARAMS],
+    pub lfo_rate: [f32; MAX_LFOS],
+    pub cutoff: f32,
+    pub resonance: f32,
+}
+
+impl Modulation {
+    pub fn add(&mut self, destination: Destination, amount: f32) {
+        match destination {
+            Destination::Corners => self.shape.corners += amount,
+            Destination::RatioX => self.shape.ratio.0 += amount,
+            Destination::RatioY => self.shape.ratio.1 += amount,
+            Destination::ShapePhase => self.shape.phase += amount,
+            Destination::Ring => self.shape.ring += amount,
+            Destination::Wheel => self.shape.wheel += amount,
+            Destination::Pen => self.shape.pen += amount,
+            Destination::M => self.shape.m += amount,
+            Destination::N1 => self.shape.n1 += amount,
+            Destination::N2 => self.shape.n2 += amount,
+            Destination::N3 => self.shape.n3 += amount,
+            Destination::Pitch => self.pitch += amount,
+            Destination::Level => self.level += amount,
+            Destination::Pan => self.pan += amount,
+            Destination::SpinX => self.spin.0 += amount,
+            Destination::SpinY => self.spin.1 += amount,
+            Destination::SpinZ => self.spin.2 += amount,
+            Destination::Perspective => self.perspective += amount,
+            Destination::Transform(param) => self.transform[param as usize] += amount,
+            Destination::MasterTransform(param) => self.master_transform[param as usize] += amount,
+            Destination::LfoRate(idx) => self.lfo_rate[idx] += amount,
+            Destination::Cutoff => self.cutoff += amount,
+            Destination::Resonance => self.resonance += amount,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ModMatrix {
+    pub routes: Vec<Route>,
+}
+
+impl ModMatrix {
+    /// Sum up every route
+    pub fn apply(&self, sources: &Sources) -> Modulation {
+        self.sum(sources, |_| true)
+    }
+
+    /// Sum up only the routes that are the same for every voice
+    pub fn apply_global(&self, sources: &Sources) -> Modulation {
+        self.sum(sources, |route| route.source.is_global(sources))
+    }
+
+    fn sum(&self, sources: &Sources, filter: impl Fn(&Route) -> bool) -> Modulation {
+        let mut modulation = Modulation::default();
+        for route in self.routes.iter().filter(|route| filter(route)) {
+            let value = sources.get(route.source);
+            let value = match (route.source.is_bipolar(), route.polarity) {
+                (true, Polarity::Unipolar) => (value + 1.0) / 2.0,
+                (false, Polarity::Bipolar) => value * 2.0 - 1.0,
+                _ => value,
+            };
+            modulation.add(route.destination, value * route.amount);
+        }
+        modulation
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(s: &str) -> Route {
+        Route::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn routes() {
+        let lfo = route("lfo1:corners:2");
+        assert_eq!(lfo.source, Source::Lfo(0));
+        assert_eq!(lfo.destination, Destination::Corners);
+        assert_eq!(lfo.amount, 2.0);
+        assert_eq!(lfo.polarity, Polarity::Bipolar);
+
+        let velocity = route("vel:level:0.5");
+        assert_eq!(velocity.source, Source::Velocity);
+        assert_eq!(velocity.polarity, Polarity::Unipolar);
+
+        let route = route(" MW : master-rotation : -0.25 ");
+        assert_eq!(route.source, Source::ModWheel);
+        assert_eq!(
+            route.destination,
+            Destination::MasterTransform(TransformParam::Rotation)
+        );
+        assert_eq!(route.amount, -0.25);
+    }
+
+    #[test]
+    fn destinations() {
+        let destination = |s: &str| Destination::from_str(s).unwrap();
+        assert_eq!(destination("lfo8-rate"), Destination::LfoRate(7));
+        assert_eq!(
+            destination("scale-x"),
+            Destination::Transform(TransformParam::ScaleX)
+        );
+        assert_eq!(destination("Cutoff"), Destination::Cutoff);
+    }
+
+    #[test]
+    fn polarity() {
+        assert_eq!(route("vel:level:1:bi").polarity, Polarity::Bipolar);
+        assert_eq!(route("vel:level:1:b").polarity, Polarity::Bipolar);
+        assert_eq!(route("lfo1:pitch:1:uni").polarity, Polarity::Unipolar);
+        assert_eq!(route("lfo1:pitch:1:Unipolar").polarity, Polarity::Unipolar);
+        assert_eq!(route("bend:pitch:2").polarity, Polarity::Bipolar);
+        assert_eq!(route("rand:pan:1").polarity, Polarity::Bipolar);
+    }
+
+    #[test]
+    fn bad_routes() {
+        for s in &[
+            "lfo0:corners:1",
+            "lfo9:corners:1",
+            "lfox:corners:1",
+            "wobble:corners:1",
+            "vel:wobble:1",
+            "vel:lfo0-rate:1",
+            "vel:master-wobble:1",
+            "vel:level:lots",
+            "vel:level:",
+            "vel:level",
+            "vel:level:1:sideways",
+            "vel:level:1:bi:extra",
+        ] {
+            assert!(Route::from_str(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn routes_add_up() {
+        let matrix = ModMatrix {
+            routes: vec![
+                route("vel:level:0.5"),
+                route("vel:level:0.25:bi"),
+                route("lfo1:pitch:12"),
+                route("lfo1:pitch:2:uni"),
+                route("mw:master-rotation:1"),
+                route("mw:rotation:0.5"),
+            ],
+        };
+        let mut sources = Sources {
+            velocity: 1.0,
+            mod_wheel: 0.5,
+            ..Sources::default()
+        };
+        sources.lfos[0] = -0.5;
+
+        let modulation = matrix.apply(&sources);
+        // 0.5 * 1 + 0.25 * (1 * 2 - 1)
+        assert_eq!(modulation.level, 0.75);
+        // 12 * -0.5 + 2 * (-0.5 + 1) / 2
+        assert_eq!(modulation.pitch, -5.5);
+        let rotation = TransformParam::Rotation as usize;
+        assert_eq!(modulation.master_transform[rotation], 0.5);
+        assert_eq!(modulation.transform[rotation], 0.25);
+
+        // Only the mod wheel is the same for every voice, until the LFO is global
+        let global = matrix.apply_global(&sources);
+        assert_eq!(global.level, 0.0);
+        assert_eq!(global.pitch, 0.0);
+        assert_eq!(global.master_transform[rotation], 0.5);
+        sources.global_lfos[0] = true;
+        assert_eq!(matrix.apply_global(&sources).pitch, -5.5);
+    }
+}
@@ -1,6 +1,5 @@
 use crate::constants::*;
-use anyhow::Result;
-use cpal::{ChannelCount, SampleRate};
+use anyhow::{anyhow, Result};
 use std::convert::TryFrom;
 use std::time::Duration;
 
@@ -12,10 +11,10 @@ use structopt::StructOpt;
 pub struct Opts {
     /// How many output channels. Currently ignored. Always 2
     #[structopt(short, long, default_value = "2")]
-    pub channels: ChannelCount,
+    pub channels: u16,
 
-    #[structopt(short, long, default_value = "44100", parse(try_from_str = parse_sample_rate))]
-    pub sample_rate: SampleRate,
+    #[structopt(short, long, default_value = "44100")]
+    pub sample_rate: u32,
 
     /// Audio buffer size, will use system default if unspecified
     #[structopt(short, long)]
@@ -31,22 +30,80 @@ pub struct Opts {
     #[structopt(short, long, parse(try_from_str), default_value = "poly")]
     pub unison_mode: crate::synthesis::UnisonMode,
 
-    /// Output device to connect to
-    #[structopt(short, long, default_value = "pulse")]
+    /// Audio host to use, the system default if not given. options: alsa, jack
+    #[structopt(long)]
+    pub host: Option<HostKind>,
+
+    /// Output device to connect to, not used with JACK
+    #[structopt(short, long, default_value = "default")]
     pub device: String,
 
-    /// Name of the MIDI input port to connect to
-    #[structopt(short = "p", long)]
-    pub midi_port: Option<String>,
+    /// JACK client name
+    #[structopt(long, default_value = "shapes")]
+    pub jack_name: String,
+
+    /// Connect to the JACK input ports matching this pattern instead of the system playback
+    /// ports, or "none" to leave the outputs unconnected
+    #[structopt(long)]
+    pub jack_connect: Option<String>,
+
+    /// Name of a MIDI input port to connect to, can be passed multiple times. "all" connects to
+    /// every port. Ports that get unplugged are connected again when they come back
+    #[structopt(short = "p", long, number_of_values = 1)]
+    pub midi_port: Vec<String>,
+
+    /// Index of a MIDI input port to connect to, can be passed multiple times
+    #[structopt(long, number_of_values = 1)]
+    pub midi_port_index: Vec<usize>,
+
+    /// Create a virtual MIDI input port other programs can connect to, optionally with a name.
+    /// No other ports are needed when this is given
+    #[structopt(long)]
+    pub virtual_midi: Option<Option<String>>,
+
+    /// Listen for OSC messages on this UDP port, e.g. "/shapes/note/on 60 100" or
+    /// "/shapes/cutoff 0.5". Parameters take the same names as --learn
+    #[structopt(long)]
+    pub osc_port: Option<u16>,
+
+    /// Take JSON commands on this Unix socket, one per line, e.g.
+    /// {"command": "set", "parameter": "cutoff", "value": 800}
+    #[structopt(long, parse(from_os_str))]
+    pub control_socket: Option<std::path::PathBuf>,
 
-    /// Index of the MIDI input port to connect to
+    /// Show a terminal UI for changing parameters and watching what's playing, with
+    /// presets saved in the same format as the command line
     #[structopt(long)]
-    pub midi_port_index: Option<usize>,
+    pub tui: bool,
+
+    /// Play notes from the computer keyboard, tracker style. Used anyway when there are no
+    /// MIDI ports
+    #[structopt(long, conflicts_with = "tui")]
+    pub keyboard: bool,
+
+    /// Bind the next controller that moves (CC, NRPN or pitch bend) to a parameter.
+    ///     parameters: volume, cutoff, resonance, filter-env, key-tracking, corners, ratio-x,
+    ///     ratio-y, shape-phase, ring, wheel, pen, spin-x, spin-y, spin-z, perspective,
+    ///     delay-feedback, delay-mix, chorus-rate, chorus-depth, chorus-mix, reverb-size,
+    ///     reverb-damping, reverb-mix, tempo, arp-gate, attack, decay, sustain, release,
+    ///     mod-rate, mod-amount, filter-attack, filter-decay, filter-sustain, filter-release,
+    ///     bend-range, delay-time, transpose, arp, arp-rate, arp-octaves, shape, filter,
+    ///     superformula-m, superformula-n1, superformula-n2, superformula-n3, knot-p, knot-q
+    #[structopt(long)]
+    pub learn: Option<crate::parameter::Parameter>,
+
+    /// File to keep MIDI bindings in, defaults to ~/.config/shapes/midi-map
+    #[structopt(long, parse(from_os_str))]
+    pub midi_map: Option<std::path::PathBuf>,
 
     /// Master gain factor
     #[structopt(short = "g", long, default_value = "0.5")]
     pub master_gain: f32,
 
+    /// List available audio hosts then exit
+    #[structopt(long)]
+    pub list_hosts: bool,
+
     /// List available audio output devices then exit
     #[structopt(long)]
     pub list_outputs: bool,
@@ -79,14 +136,217 @@ pub struct Opts {
     #[structopt(long, parse(try_from_str = parse_duration), default_value = "1.0")]
     pub release: Duration,
 
+    /// Shape to trace. options: polygon, lissajous, hypotrochoid|spirograph, epitrochoid,
+    /// superformula, tetrahedron, cube, octahedron, icosahedron, torus-knot
+    #[structopt(long, parse(try_from_str), default_value = "polygon")]
+    pub shape: crate::synthesis::Shape,
+
     #[structopt(long, default_value = "4.0")]
     pub corners: f32,
 
+    /// Lissajous frequency ratio of x to y, e.g. "3:2"
+    #[structopt(long, parse(try_from_str = parse_vec2), default_value = "3:2")]
+    pub ratio: crate::vec2::Vec2,
+
+    /// Lissajous phase offset in turns
+    #[structopt(long, default_value = "0.25")]
+    pub shape_phase: f32,
+
+    /// Spirograph ring radius
+    #[structopt(long, default_value = "5.0")]
+    pub ring: f32,
+
+    /// Spirograph wheel radius
+    #[structopt(long, default_value = "3.0")]
+    pub wheel: f32,
+
+    /// Spirograph pen distance from the center of the wheel
+    #[structopt(long, default_value = "5.0")]
+    pub pen: f32,
+
+    /// Superformula parameters m, n1, n2 and n3, e.g. "5:2:7:7"
+    #[structopt(long, parse(try_from_str = parse_vec4), default_value = "5:2:7:7")]
+    pub superformula: (f32, f32, f32, f32),
+
+    /// Rotation speeds of 3D shapes around the x, y and z axes in turns per second, e.g. "0.1:0.2:0"
+    #[structopt(long, parse(try_from_str = parse_vec3), default_value = "0:0:0")]
+    pub spin: crate::maths::vec3::Vec3,
+
+    /// Camera distance for 3D shapes in units of the shape's radius, 0 for no perspective
+    #[structopt(long, default_value = "3.0")]
+    pub perspective: f32,
+
+    /// The p and q of the torus knot, e.g. "2:3"
+    #[structopt(long, parse(try_from_str = parse_vec2), default_value = "2:3")]
+    pub knot: crate::vec2::Vec2,
+
     #[structopt(long, default_value = "0.0")]
     pub mod_rate: f32,
 
     #[structopt(long, default_value = "0.0")]
     pub mod_amount: f32,
+
+    /// Add an LFO, can be passed multiple times. They are numbered from 1 in the order given.
+    ///     Format: waveform[,key=value...] e.g. "tri,rate=1/8,mode=free"
+    ///     waveforms: sine, tri, saw, square, sh
+    ///     keys: rate (Hz or note division like 1/4, 1/8d, 1/16t), mode (retrigger, free),
+    ///     scope (voice, global), phase (0 to 1), fade (seconds)
+    #[structopt(long, number_of_values = 1)]
+    pub lfo: Vec<crate::lfo::LfoParams>,
+
+    /// Add a modulation route, can be passed multiple times.
+    ///     Format: source:destination:amount[:uni|bi] e.g. "lfo1:corners:2"
+    ///     sources: lfoN, env, fenv, vel, note, at, mw, bend, rand
+    ///     destinations: corners, ratio-x, ratio-y, shape-phase, ring, wheel, pen, m, n1, n2, n3,
+    ///     pitch (semitones), level, pan, lfoN-rate (octaves), cutoff (octaves), resonance, spin-x,
+    ///     spin-y, spin-z, perspective, rotation (turns), rotation-speed (turns per second), scale-x,
+    ///     scale-y, shear-x, shear-y, offset-x, offset-y.
+    ///     Prefix the transform ones with master- for the master transform
+    #[structopt(short = "m", long = "mod", number_of_values = 1)]
+    pub modulation: Vec<crate::modulation::Route>,
+
+    /// Pitch bend range in semitones
+    #[structopt(long, default_value = "2.0")]
+    pub bend_range: f32,
+
+    /// Filter mode. options: off, lp|lowpass, bp|bandpass, hp|highpass, notch
+    #[structopt(long, parse(try_from_str), default_value = "off")]
+    pub filter: crate::filter::FilterMode,
+
+    /// Filter cutoff in Hz
+    #[structopt(long, default_value = "2000.0")]
+    pub cutoff: f32,
+
+    /// Filter resonance, 0 to 1
+    #[structopt(long, default_value = "0.0")]
+    pub resonance: f32,
+
+    /// How much the cutoff follows the note, 1 means fully, relative to middle C
+    #[structopt(long, default_value = "0.0")]
+    pub key_tracking: f32,
+
+    /// How far the filter envelope moves the cutoff, in octaves
+    #[structopt(long, default_value = "0.0")]
+    pub filter_env: f32,
+
+    /// Filter envelope attack in seconds
+    #[structopt(long, parse(try_from_str = parse_duration), default_value = "0.05")]
+    pub filter_attack: Duration,
+
+    /// Filter envelope decay in seconds
+    #[structopt(long, parse(try_from_str = parse_duration), default_value = "0.2")]
+    pub filter_decay: Duration,
+
+    /// Filter envelope sustain level
+    #[structopt(long, default_value = "0.0")]
+    pub filter_sustain: f32,
+
+    /// Filter envelope release in seconds
+    #[structopt(long, parse(try_from_str = parse_duration), default_value = "0.5")]
+    pub filter_release: Duration,
+
+    /// Transform applied to every voice.
+    ///     Format: key=value,... e.g. "rotation=0.125,speed=0.5,scale=1:0.5,shear=0.2:0,offset=0:0.1"
+    ///     rotation in turns, speed in turns per second
+    #[structopt(long, default_value = "")]
+    pub transform: crate::transform::TransformParams,
+
+    /// Transform applied to the final output, same format as --transform
+    #[structopt(long, default_value = "")]
+    pub master_transform: crate::transform::TransformParams,
+
+    /// Master effects in the order they are applied, e.g. "chorus,delay,reverb"
+    #[structopt(long, default_value = "none")]
+    pub effects: crate::effects::EffectOrder,
+
+    /// Delay time in seconds or as a note division like 1/8d
+    #[structopt(long, default_value = "0.375")]
+    pub delay_time: crate::effects::DelayTime,
+
+    /// Delay feedback, 0 to 1
+    #[structopt(long, default_value = "0.4")]
+    pub delay_feedback: f32,
+
+    /// Delay wet/dry mix, 0 to 1
+    #[structopt(long, default_value = "0.3")]
+    pub delay_mix: f32,
+
+    /// Don't bounce the delay repeats between left and right
+    #[structopt(long)]
+    pub no_ping_pong: bool,
+
+    /// Chorus rate in Hz
+    #[structopt(long, default_value = "0.8")]
+    pub chorus_rate: f32,
+
+    /// Chorus depth in milliseconds
+    #[structopt(long, default_value = "3.0")]
+    pub chorus_depth: f32,
+
+    /// Chorus wet/dry mix, 0 to 1
+    #[structopt(long, default_value = "0.5")]
+    pub chorus_mix: f32,
+
+    /// Reverb room size, 0 to 1
+    #[structopt(long, default_value = "0.5")]
+    pub reverb_size: f32,
+
+    /// Reverb damping, 0 to 1
+    #[structopt(long, default_value = "0.5")]
+    pub reverb_damping: f32,
+
+    /// Reverb wet/dry mix, 0 to 1
+    #[structopt(long, default_value = "0.3")]
+    pub reverb_mix: f32,
+
+    /// Keep the effects from mixing the channels together, so shapes on an XY scope stay intact
+    #[structopt(long)]
+    pub xy_preserve: bool,
+
+    /// Scala scale file (.scl) to tune to, 12-TET if not given
+    #[structopt(long, parse(from_os_str))]
+    pub scl: Option<std::path::PathBuf>,
+
+    /// Scala keyboard mapping file (.kbm), by default the scale starts at middle C
+    #[structopt(long, parse(from_os_str))]
+    pub kbm: Option<std::path::PathBuf>,
+
+    /// Frequency of the reference note in Hz, A4 unless the keyboard mapping says otherwise.
+    /// Defaults to 440 or the keyboard mapping's frequency
+    #[structopt(long)]
+    pub reference_pitch: Option<f32>,
+
+    /// Shift every key by this many keys
+    #[structopt(long, default_value = "0", allow_hyphen_values = true)]
+    pub transpose: i32,
+
+    /// Turn on the arpeggiator. options: up, down, up-down, random, as-played
+    #[structopt(long)]
+    pub arp: Option<crate::arpeggiator::ArpMode>,
+
+    /// How many octaves the arpeggiator covers
+    #[structopt(long, default_value = "1")]
+    pub arp_octaves: u8,
+
+    /// Length of arpeggiated notes as a fraction of a step
+    #[structopt(long, default_value = "0.5")]
+    pub arp_gate: f32,
+
+    /// Arpeggiator step length as a note division, e.g. 1/16 or 1/8t
+    #[structopt(long, parse(try_from_str = parse_division), default_value = "1/16")]
+    pub arp_rate: f32,
+
+    /// Keep arpeggiating after the keys are released
+    #[structopt(long)]
+    pub arp_latch: bool,
+
+    /// What the arpeggiator follows. options: internal, midi
+    #[structopt(long, default_value = "internal")]
+    pub arp_sync: crate::arpeggiator::ArpSync,
+
+    /// Tempo in beats per minute for tempo synced rates, when there's no incoming MIDI clock
+    #[structopt(long, default_value = "120.0")]
+    pub tempo: f32,
 }
 
 /// Get and also validate CLI options
@@ -94,8 +354,21 @@ pub fn getopts() -> Opts {
     Opts::from_args()
 }
 
-fn parse_sample_rate(input: &str) -> Result<SampleRate> {
-    Ok(SampleRate(u32::from_str(input)?))
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HostKind {
+    Alsa,
+    Jack,
+}
+
+impl FromStr for HostKind {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<HostKind> {
+        match s.to_lowercase().as_str() {
+            "alsa" => Ok(HostKind::Alsa),
+            "jack" => Ok(HostKind::Jack),
+            _ => Err(anyhow!("Invalid value \"{}\" for HostKind", s)),
+        }
+    }
 }
 
 fn parse_duration(input: &str) -> Result<Duration> {
@@ -103,3 +376,37 @@ fn parse_duration(input: &str) -> Result<Duration> {
     // Dont want no divide by zero errors, fix it up here, dont worry about it later ;-)
     Ok(Duration::from_secs_f32(seconds.max(f32::EPSILON)))
 }
+
+fn parse_vec2(input: &str) -> Result<crate::vec2::Vec2> {
+    match input.split(':').collect::<Vec<&str>>().as_slice() {
+        [x, y] => Ok((f32::from_str(x)?, f32::from_str(y)?)),
+        _ => Err(anyhow!("Expected x:y, got \"{}\"", input)),
+    }
+}
+
+fn parse_vec3(input: &str) -> Result<crate::maths::vec3::Vec3> {
+    match input.split(':').collect::<Vec<&str>>().as_slice() {
+        [x, y, z] => Ok((f32::from_str(x)?, f32::from_str(y)?, f32::from_str(z)?)),
+        _ => Err(anyhow!("Expected x:y:z, got \"{}\"", input)),
+    }
+}
+
+fn parse_vec4(input: &str) -> Result<(f32, f32, f32, f32)> {
+    match input.split(':').collect::<Vec<&str>>().as_slice() {
+        [a, b, c, d] => Ok((
+            f32::from_str(a)?,
+            f32::from_str(b)?,
+            f32::from_str(c)?,
+            f32::from_str(d)?,
+        )),
+        _ => Err(anyhow!("Expected a:b:c:d, got \"{}\"", input)),
+    }
+}
+
+/// Note division in beats
+fn parse_division(input: &str) -> Result<f32> {
+    crate::util::parse_note_division(input)?.ok_or(anyhow!(
+        "Expected a note division like 1/16, got \"{}\"",
+        input
+    ))
+}
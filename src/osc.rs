@@ -0,0 +1,440 @@
+use shapes::channel::Sender;
+use shapes::engine::Message;
+use shapes::parameter::Parameter;
+
+use anyhow::{anyhow, Context, Result};
+use std::convert::TryFrom;
+use std::net::UdpSocket;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use wmidi::Note;
+
+/// Every address starts with this
+const PREFIX: &str = concat!("/", env!("CARGO_PKG_NAME"));
+/// How long to block waiting for packets before checking whether to stop
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Bundles can contain bundles, don't let someone send us something silly
+const MAX_BUNDLE_DEPTH: usize = 8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Argument {
+    Int(i32),
+    Float(f32),
+    String(String),
+    Blob(Vec<u8>),
+    Long(i64),
+    Double(f64),
+    Bool(bool),
+    Nil,
+    Impulse,
+}
+
+impl Argument {
+    fn as_f32(&self) -> Option<f32> {
+        match self {
+            Argument::Int(i) => Some(*i as f32),
+            Argument::Float(f) => Some(*f),
+            Argument::Long(i) => Some(*i as f32),
+            Argument::Double(d) => Some(*d as f32),
+            _ => None,
+        }
+    }
+
+    fn as_int(&self) -> Option<i64> {
+        match self {
+            Argument::Int(i) => Some(*i as i64),
+            Argument::Long(i) => Some(*i),
+            Argument::Float(f) => Some(*f as i64),
+            Argument::Double(d) => Some(*d as i64),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OscMessage {
+    pub address: String,
+    pub args: Vec<Argument>,
+}
+
+/// Everything in a packet, bundles get flattened. Time tags are ignored, everything happens
+/// as soon as it arrives
+pub fn parse_packet(data: &[u8]) -> Result<Vec<OscMessage>> {
+    let mut messages = Vec::new();
+    parse_element(data, &mut messages, 0)?;
+    Ok(messages)
+}
+
+fn parse_element(data: &[u8], messages: &mut Vec<OscMessage>, depth: usize) -> Result<()> {
+    if data.starts_with(b"#bundle\0") {
+        if depth >= MAX_BUNDLE_DEPTH {
+            return Err(anyhow!("Bundles nested too deep"));
+        }
+        // Skip the time tag
+        let mut pos = 16;
+        while pos < data.len() {
+            let size = read_i32(data, &mut pos)?;
+            let size = usize::try_from(size).context("Negative bundle element size")?;
+            let element = data
+                .get(pos..pos + size)
+                .ok_or(anyhow!("Bundle element runs past the end of the packet"))?;
+            parse_element(element, messages, depth + 1)?;
+            pos += size;
+        }
+        return Ok(());
+    }
+
+    let mut pos = 0;
+    let address = read_string(data, &mut pos)?;
+    if !address.starts_with('/') {
+        return Err(anyhow!("Invalid address \"{}\"", address));
+    }
+    // Old senders might leave the type tags out, then there are no arguments we can read
+    let tags = if pos < data.len() {
+        read_string(data, &mut pos)?
+    } else {
+        String::from(",")
+    };
+    let tags = tags
+        .strip_prefix(',')
+        .ok_or(anyhow!("Type tags should start with a comma"))?;
+
+    let mut args = Vec::new();
+    for tag in tags.chars() {
+        args.push(match tag {
+            'i' => Argument::Int(read_i32(data, &mut pos)?),
+            'f' => Argument::Float(f32::from_bits(read_i32(data, &mut pos)? as u32)),
+            's' | 'S' => Argument::String(read_string(data, &mut pos)?),
+            'b' => {
+                let size =
+                    usize::try_from(read_i32(data, &mut pos)?).context("Negative blob size")?;
+                let blob = data
+                    .get(pos..pos + size)
+                    .ok_or(anyhow!("Blob runs past the end of the packet"))?
+                    .to_vec();
+                pos += padded(size);
+                Argument::Blob(blob)
+            }
+            'h' => Argument::Long(read_i64(data, &mut pos)?),
+            'd' => Argument::Double(f64::from_bits(read_i64(data, &mut pos)? as u64)),
+            'T' => Argument::Bool(true),
+            'F' => Argument::Bool(false),
+            'N' => Argument::Nil,
+            'I' => Argument::Impulse,
+            _ => return Err(anyhow!("Unsupported argument type '{}'", tag)),
+        });
+    }
+
+    messages.push(OscMessage { address, args });
+    Ok(())
+}
+
+/// Everything is padded to a multiple of 4 bytes
+fn padded(size: usize) -> usize {
+    (size + 3) & !3
+}
+
+fn read_string(data: &[u8], pos: &mut usize) -> Result<String> {
+    let rest = data.get(*pos..).unwrap_or(&[]);
+    let len = rest
+        .iter()
+        .position(|b| *b == 0)
+        .ok_or(anyhow!("Unterminated string"))?;
+    let s = std::str::from_utf8(&rest[..len])?.to_string();
+    *pos += padded(len + 1);
+    Ok(s)
+}
+
+fn read_i32(data: &[u8], pos: &mut usize) -> Result<i32> {
+    let bytes = data
+        .get(*pos..*pos + 4)
+        .ok_or(anyhow!("Packet ends in the middle of an argument"))?;
+    *pos += 4;
+    Ok(i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn read_i64(data: &[u8], pos: &mut usize) -> Result<i64> {
+    let high = read_i32(data, pos)? as u32 as u64;
+    let low = read_i32(data, pos)? as u32 as u64;
+    Ok((high << 32 | low) as i64)
+}
+
+/// What to tell the audio thread for a message.
+///     /shapes/note/on note [velocity], velocity is 0 to 1 as a float or 0 to 127 as an int
+///     /shapes/note/off note
+///     /shapes/bend -1 to 1, /shapes/modwheel 0 to 1, /shapes/aftertouch 0 to 1
+///     /shapes/allnotesoff, /shapes/panic
+///     /shapes/<parameter> value in its own units like the control socket's set, e.g. Hz for
+///     the cutoff, or the name for ones like shape. Same names as --learn
+pub fn to_message(osc: &OscMessage) -> Result<Message> {
+    let path = osc
+        .address
+        .strip_prefix(PREFIX)
+        .and_then(|path| path.strip_prefix('/'))
+        .ok_or(anyhow!("Address doesn't start with {}/", PREFIX))?;
+
+    // NaN gets through clamp, and would stick in the filters and effects until restarting
+    let finite = |value: f32| {
+        if value.is_finite() {
+            Ok(value)
+        } else {
+            Err(anyhow!("Invalid value {} for {}", value, osc.address))
+        }
+    };
+    let float = |idx: usize| {
+        osc.args
+            .get(idx)
+            .and_then(Argument::as_f32)
+            .ok_or(anyhow!("{} needs a number argument", osc.address))
+            .and_then(finite)
+    };
+    let note = || {
+        let note = osc
+            .args
+            .first()
+            .and_then(Argument::as_int)
+            .ok_or(anyhow!("{} needs a note number", osc.address))?;
+        u8::try_from(note)
+            .ok()
+            .and_then(|note| Note::try_from(note).ok())
+            .ok_or(anyhow!("Invalid note {}", note))
+    };
+
+    match path {
+        "note/on" => {
+            let level = match osc.args.get(1) {
+                Some(Argument::Int(velocity)) => *velocity as f32 / 127.0,
+                Some(arg) => finite(arg.as_f32().ok_or(anyhow!("Invalid velocity {:?}", arg))?)?,
+                None => 1.0,
+            };
+            if level <= 0.0 {
+                Ok(Message::NoteOff(note()?))
+            } else {
+                Ok(Message::NoteOn(note()?, level.min(1.0)))
+            }
+        }
+        "note/off" => Ok(Message::NoteOff(note()?)),
+        "bend" => Ok(Message::PitchBend(float(0)?.clamp(-1.0, 1.0))),
+        "modwheel" => Ok(Message::ModWheel(float(0)?.clamp(0.0, 1.0))),
+        "aftertouch" => Ok(Message::Aftertouch(float(0)?.clamp(0.0, 1.0))),
+        "allnotesoff" => Ok(Message::AllNotesOff),
+        "panic" => Ok(Message::Reset),
+        parameter => {
+            let parameter = Parameter::from_str(parameter)?;
+            let value = match (osc.args.first(), parameter.choices()) {
+                (Some(Argument::String(name)), Some(choices)) => choices
+                    .iter()
+                    .position(|choice| choice == name)
+                    .ok_or(anyhow!("Invalid value \"{}\" for {}", name, parameter))?
+                    as f32,
+                _ => float(0)?,
+            };
+            // Goes through the same path as a controller, which clamps it to the range
+            Ok(Message::SetParameter(
+                parameter,
+                parameter.to_normalized(value),
+            ))
+        }
+    }
+}
+
+/// Handle to the thread receiving OSC
+pub struct OscServer {
+    stop: Arc<AtomicBool>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl OscServer {
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if self.thread.join().is_err() {
+            log::error!("OSC server thread panicked");
+        }
+    }
+}
+
+/// Listen for OSC on UDP on every interface
+pub fn serve(port: u16, sender: Sender) -> Result<OscServer> {
+    let socket = UdpSocket::bind(("0.0.0.0", port))
+        .with_context(|| format!("Couldn't listen for OSC on port {}", port))?;
+    socket.set_read_timeout(Some(POLL_INTERVAL))?;
+    log::info!("Listening for OSC on port {}", port);
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = stop.clone();
+    let thread = thread::spawn(move || {
+        // The biggest a UDP packet can be
+        let mut buffer = vec![0; 65536];
+        while !stopped.load(Ordering::Relaxed) {
+            let (size, from) = match socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(e)
+                    if e.kind() == std::io::ErrorKind::WouldBlock
+                        || e.kind() == std::io::ErrorKind::TimedOut =>
+                {
+                    continue
+                }
+                Err(e) => {
+                    log::error!("Couldn't receive OSC: {}", e);
+                    continue;
+                }
+            };
+
+            let messages = match parse_packet(&buffer[..size]) {
+                Ok(messages) => messages,
+                Err(e) => {
+                    log::warn!("Invalid OSC packet from {}: {}", from, e);
+                    continue;
+                }
+            };
+            for osc in messages {
+                log::trace!("OSC from {}: {} {:?}", from, osc.address, osc.args);
+                match to_message(&osc) {
+                    Ok(message) => {
+                        if sender.send(message).is_err() {
+                            // Audio's gone
+                            return;
+                        }
+                    }
+                    Err(e) => log::warn!("Ignoring OSC message {}: {}", osc.address, e),
+                }
+            }
+        }
+    });
+
+    Ok(OscServer { stop, thread })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-4,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    /// Null terminated and padded
+    fn string(s: &str) -> Vec<u8> {
+        let mut bytes = s.as_bytes().to_vec();
+        bytes.resize(padded(s.len() + 1), 0);
+        bytes
+    }
+
+    fn encode(osc: &OscMessage) -> Vec<u8> {
+        let mut tags = String::from(",");
+        let mut args = Vec::new();
+        for arg in &osc.args {
+            match arg {
+                Argument::Int(i) => {
+                    tags.push('i');
+                    args.extend_from_slice(&i.to_be_bytes());
+                }
+                Argument::Float(f) => {
+                    tags.push('f');
+                    args.extend_from_slice(&f.to_bits().to_be_bytes());
+                }
+                Argument::String(s) => {
+                    tags.push('s');
+                    args.extend(string(s));
+                }
+                arg => panic!("Can't encode {:?}", arg),
+            }
+        }
+        let mut packet = string(&osc.address);
+        packet.extend(string(&tags));
+        packet.extend(args);
+        packet
+    }
+
+    fn bundle(elements: &[Vec<u8>]) -> Vec<u8> {
+        let mut packet = string("#bundle");
+        // Time tag for right away
+        packet.extend_from_slice(&1u64.to_be_bytes());
+        for element in elements {
+            packet.extend_from_slice(&(element.len() as i32).to_be_bytes());
+            packet.extend_from_slice(element);
+        }
+        packet
+    }
+
+    fn message(address: &str, args: Vec<Argument>) -> OscMessage {
+        OscMessage {
+            address: String::from(address),
+            args,
+        }
+    }
+
+    #[test]
+    fn bundle_round_trip() {
+        let on = message(
+            "/shapes/note/on",
+            vec![Argument::Int(60), Argument::Float(0.5)],
+        );
+        let shape = message(
+            "/shapes/shape",
+            vec![Argument::String(String::from("cube"))],
+        );
+        let panic = message("/shapes/panic", vec![]);
+        let packet = bundle(&[encode(&on), bundle(&[encode(&shape)]), encode(&panic)]);
+        assert_eq!(parse_packet(&packet).unwrap(), vec![on, shape, panic]);
+
+        assert!(parse_packet(&packet[..packet.len() - 4]).is_err());
+    }
+
+    #[test]
+    fn int_and_float_velocity() {
+        let level = |velocity: Argument| match to_message(&message(
+            "/shapes/note/on",
+            vec![Argument::Int(60), velocity],
+        )) {
+            Ok(Message::NoteOn(Note::C4, level)) => level,
+            _ => panic!("Expected a note on"),
+        };
+        assert_close(level(Argument::Int(127)), 1.0);
+        assert_close(level(Argument::Int(64)), 64.0 / 127.0);
+        assert_close(level(Argument::Float(0.5)), 0.5);
+        assert_close(level(Argument::Float(2.0)), 1.0);
+
+        let off = to_message(&message(
+            "/shapes/note/on",
+            vec![Argument::Int(60), Argument::Int(0)],
+        ));
+        assert!(matches!(off, Ok(Message::NoteOff(Note::C4))));
+    }
+
+    #[test]
+    fn parameters_in_their_own_units() {
+        let set = |address: &str, arg: Argument| match to_message(&message(address, vec![arg])) {
+            Ok(Message::SetParameter(parameter, value)) => parameter.from_normalized(value),
+            _ => panic!("Expected a parameter change"),
+        };
+        assert_close(set("/shapes/corners", Argument::Float(5.0)), 5.0);
+        assert_close(set("/shapes/corners", Argument::Int(5)), 5.0);
+        // Clamped to the range, 2 to 12
+        assert_close(set("/shapes/corners", Argument::Float(0.5)), 2.0);
+        assert_close(set("/shapes/corners", Argument::Float(100.0)), 12.0);
+        assert_close(set("/shapes/cutoff", Argument::Int(800)), 800.0);
+        assert_close(
+            set("/shapes/shape", Argument::String(String::from("cube"))),
+            2.0,
+        );
+
+        assert!(to_message(&message("/shapes/corners", vec![])).is_err());
+        for value in &[f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            let arg = || vec![Argument::Float(*value)];
+            assert!(to_message(&message("/shapes/cutoff", arg())).is_err());
+            assert!(to_message(&message("/shapes/bend", arg())).is_err());
+            let note = vec![Argument::Int(60), Argument::Float(*value)];
+            assert!(to_message(&message("/shapes/note/on", note)).is_err());
+        }
+        assert!(to_message(&message("/shapes/nothing", vec![Argument::Float(1.0)])).is_err());
+    }
+}
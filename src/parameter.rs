@@ -0,0 +1,616 @@
+use crate::arpeggiator::ArpMode;
+use crate::effects::DelayTime;
+use crate::filter::FilterMode;
+use crate::lfo::Rate;
+use crate::modulation::Source;
+use crate::patch::Patch;
+use crate::synthesis::Shape;
+
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Patch parameters that can be set while playing, e.g. from a MIDI controller
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Parameter {
+    Volume,
+    Cutoff,
+    Resonance,
+    FilterEnv,
+    KeyTracking,
+    Corners,
+    RatioX,
+    RatioY,
+    ShapePhase,
+    Ring,
+    Wheel,
+    Pen,
+    SpinX,
+    SpinY,
+    SpinZ,
+    Perspective,
+    DelayFeedback,
+    DelayMix,
+    ChorusRate,
+    ChorusDepth,
+    ChorusMix,
+    ReverbSize,
+    ReverbDamping,
+    ReverbMix,
+    Tempo,
+    ArpGate,
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    /// The LFO from --mod-rate and --mod-amount
+    ModRate,
+    ModAmount,
+    FilterAttack,
+    FilterDecay,
+    FilterSustain,
+    FilterRelease,
+    BendRange,
+    /// In seconds, setting it stops it following the tempo
+    DelayTime,
+    Transpose,
+    /// Picks from `choices`, the first is off
+    ArpMode,
+    /// In beats
+    ArpRate,
+    ArpOctaves,
+    Shape,
+    FilterMode,
+    SuperformulaM,
+    SuperformulaN1,
+    SuperformulaN2,
+    SuperformulaN3,
+    KnotP,
+    KnotQ,
+}
+
+pub const PARAMETERS: &[Parameter] = &[
+    Parameter::Volume,
+    Parameter::Cutoff,
+    Parameter::Resonance,
+    Parameter::FilterEnv,
+    Parameter::KeyTracking,
+    Parameter::Corners,
+    Parameter::RatioX,
+    Parameter::RatioY,
+    Parameter::ShapePhase,
+    Parameter::Ring,
+    Parameter::Wheel,
+    Parameter::Pen,
+    Parameter::SpinX,
+    Parameter::SpinY,
+    Parameter::SpinZ,
+    Parameter::Perspective,
+    Parameter::DelayFeedback,
+    Parameter::DelayMix,
+    Parameter::ChorusRate,
+    Parameter::ChorusDepth,
+    Parameter::ChorusMix,
+    Parameter::ReverbSize,
+    Parameter::ReverbDamping,
+    Parameter::ReverbMix,
+    Parameter::Tempo,
+    Parameter::ArpGate,
+    Parameter::Attack,
+    Parameter::Decay,
+    Parameter::Sustain,
+    Parameter::Release,
+    Parameter::ModRate,
+    Parameter::ModAmount,
+    Parameter::FilterAttack,
+    Parameter::FilterDecay,
+    Parameter::FilterSustain,
+    Parameter::FilterRelease,
+    Parameter::BendRange,
+    Parameter::DelayTime,
+    Parameter::Transpose,
+    Parameter::ArpMode,
+    Parameter::ArpRate,
+    Parameter::ArpOctaves,
+    Parameter::Shape,
+    Parameter::FilterMode,
+    Parameter::SuperformulaM,
+    Parameter::SuperformulaN1,
+    Parameter::SuperformulaN2,
+    Parameter::SuperformulaN3,
+    Parameter::KnotP,
+    Parameter::KnotQ,
+];
+
+const SHAPES: &[Shape] = &[
+    Shape::Polygon,
+    Shape::Tetrahedron,
+    Shape::Cube,
+    Shape::Octahedron,
+    Shape::Icosahedron,
+    Shape::TorusKnot,
+    Shape::Lissajous,
+    Shape::Hypotrochoid,
+    Shape::Epitrochoid,
+    Shape::Superformula,
+];
+
+const SHAPE_NAMES: &[&str] = &[
+    "polygon",
+    "tetrahedron",
+    "cube",
+    "octahedron",
+    "icosahedron",
+    "torus-knot",
+    "lissajous",
+    "hypotrochoid",
+    "epitrochoid",
+    "superformula",
+];
+
+const FILTER_MODES: &[FilterMode] = &[
+    FilterMode::Off,
+    FilterMode::LowPass,
+    FilterMode::BandPass,
+    FilterMode::HighPass,
+    FilterMode::Notch,
+];
+
+const FILTER_MODE_NAMES: &[&str] = &["off", "lowpass", "bandpass", "highpass", "notch"];
+
+const ARP_MODES: &[Option<ArpMode>] = &[
+    None,
+    Some(ArpMode::Up),
+    Some(ArpMode::Down),
+    Some(ArpMode::UpDown),
+    Some(ArpMode::Random),
+    Some(ArpMode::AsPlayed),
+];
+
+const ARP_MODE_NAMES: &[&str] = &["off", "up", "down", "up-down", "random", "as-played"];
+
+impl Parameter {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Parameter::Volume => "volume",
+            Parameter::Cutoff => "cutoff",
+            Parameter::Resonance => "resonance",
+            Parameter::FilterEnv => "filter-env",
+            Parameter::KeyTracking => "key-tracking",
+            Parameter::Corners => "corners",
+            Parameter::RatioX => "ratio-x",
+            Parameter::RatioY => "ratio-y",
+            Parameter::ShapePhase => "shape-phase",
+            Parameter::Ring => "ring",
+            Parameter::Wheel => "wheel",
+            Parameter::Pen => "pen",
+            Parameter::SpinX => "spin-x",
+            Parameter::SpinY => "spin-y",
+            Parameter::SpinZ => "spin-z",
+            Parameter::Perspective => "perspective",
+            Parameter::DelayFeedback => "delay-feedback",
+            Parameter::DelayMix => "delay-mix",
+            Parameter::ChorusRate => "chorus-rate",
+            Parameter::ChorusDepth => "chorus-depth",
+            Parameter::ChorusMix => "chorus-mix",
+            Parameter::ReverbSize => "reverb-size",
+            Parameter::ReverbDamping => "reverb-damping",
+            Parameter::ReverbMix => "reverb-mix",
+            Parameter::Tempo => "tempo",
+            Parameter::ArpGate => "arp-gate",
+            Parameter::Attack => "attack",
+            Parameter::Decay => "decay",
+            Parameter::Sustain => "sustain",
+            Parameter::Release => "release",
+            Parameter::ModRate => "mod-rate",
+            Parameter::ModAmount => "mod-amount",
+            Parameter::FilterAttack => "filter-attack",
+            Parameter::FilterDecay => "filter-decay",
+            Parameter::FilterSustain => "filter-sustain",
+            Parameter::FilterRelease => "filter-release",
+            Parameter::BendRange => "bend-range",
+            Parameter::DelayTime => "delay-time",
+            Parameter::Transpose => "transpose",
+            Parameter::ArpMode => "arp",
+            Parameter::ArpRate => "arp-rate",
+            Parameter::ArpOctaves => "arp-octaves",
+            Parameter::Shape => "shape",
+            Parameter::FilterMode => "filter",
+            Parameter::SuperformulaM => "superformula-m",
+            Parameter::SuperformulaN1 => "superformula-n1",
+            Parameter::SuperformulaN2 => "superformula-n2",
+            Parameter::SuperformulaN3 => "superformula-n3",
+            Parameter::KnotP => "knot-p",
+            Parameter::KnotQ => "knot-q",
+        }
+    }
+
+    fn range(&self) -> Range {
+        use Range::*;
+        match self {
+            Parameter::Volume => Linear(0.0, 1.0),
+            Parameter::Cutoff => Exponential(20.0, 20000.0),
+            Parameter::Resonance => Linear(0.0, 1.0),
+            Parameter::FilterEnv => Linear(-4.0, 4.0),
+            Parameter::KeyTracking => Linear(0.0, 1.0),
+            Parameter::Corners => Linear(2.0, 12.0),
+            Parameter::RatioX | Parameter::RatioY => Linear(1.0, 8.0),
+            Parameter::ShapePhase => Linear(0.0, 1.0),
+            Parameter::Ring | Parameter::Wheel => Linear(1.0, 10.0),
+            Parameter::Pen => Linear(0.0, 10.0),
+            Parameter::SpinX | Parameter::SpinY | Parameter::SpinZ => Linear(-2.0, 2.0),
+            Parameter::Perspective => Linear(0.0, 10.0),
+            Parameter::DelayFeedback => Linear(0.0, 0.95),
+            Parameter::DelayMix => Linear(0.0, 1.0),
+            Parameter::ChorusRate => Exponential(0.05, 5.0),
+            Parameter::ChorusDepth => Linear(0.0, 15.0),
+            Parameter::ChorusMix => Linear(0.0, 1.0),
+            Parameter::ReverbSize | Parameter::ReverbDamping | Parameter::ReverbMix => {
+                Linear(0.0, 1.0)
+            }
+            Parameter::Tempo => Linear(40.0, 240.0),
+            Parameter::ArpGate => Linear(0.0, 1.0),
+            Parameter::Attack | Parameter::Decay | Parameter::Release => Exponential(0.001, 10.0),
+            Parameter::Sustain => Linear(0.0, 1.0),
+            Parameter::ModRate => Exponential(0.01, 1000.0),
+            Parameter::ModAmount => Linear(0.0, 10.0),
+            Parameter::FilterAttack | Parameter::FilterDecay | Parameter::FilterRelease => {
+                Exponential(0.001, 10.0)
+            }
+            Parameter::FilterSustain => Linear(0.0, 1.0),
+            Parameter::BendRange => Linear(0.0, 24.0),
+            Parameter::DelayTime => Exponential(0.01, 4.0),
+            Parameter::Transpose => Linear(-48.0, 48.0),
+            // A 32nd note to a whole note
+            Parameter::ArpRate => Exponential(0.125, 4.0),
+            Parameter::ArpOctaves => Linear(1.0, 10.0),
+            Parameter::SuperformulaM => Linear(0.0, 20.0),
+            Parameter::SuperformulaN1 | Parameter::SuperformulaN2 | Parameter::SuperformulaN3 => {
+                Exponential(0.1, 100.0)
+            }
+            Parameter::KnotP | Parameter::KnotQ => Linear(1.0, 10.0),
+            Parameter::ArpMode | Parameter::Shape | Parameter::FilterMode => {
+                let choices = self.choices().unwrap_or(&[]);
+                Linear(0.0, choices.len().saturating_sub(1) as f32)
+            }
+        }
+    }
+
+    /// Names for the values of the ones that pick from a list, in order from 0
+    pub fn choices(&self) -> Option<&'static [&'static str]> {
+        match self {
+            Parameter::ArpMode => Some(ARP_MODE_NAMES),
+            Parameter::Shape => Some(SHAPE_NAMES),
+            Parameter::FilterMode => Some(FILTER_MODE_NAMES),
+            _ => None,
+        }
+    }
+
+    /// Whether it only takes whole numbers, which everything with choices does
+    pub fn is_integer(&self) -> bool {
+        self.choices().is_some() || matches!(self, Parameter::Transpose | Parameter::ArpOctaves)
+    }
+
+    /// Value in the parameter's units from a controller value between 0 and 1
+    pub fn from_normalized(&self, value: f32) -> f32 {
+        let value = value.clamp(0.0, 1.0);
+        let value = match self.range() {
+            Range::Linear(min, max) => min + (max - min) * value,
+            Range::Exponential(min, max) => min * f32::powf(max / min, value),
+        };
+        if self.is_integer() {
+            value.round()
+        } else {
+            value
+        }
+    }
+
+    /// Where a value sits between 0 and 1
+    pub fn to_normalized(&self, value: f32) -> f32 {
+        let normalized = match self.range() {
+            Range::Linear(min, max) => (value - min) / (max - min),
+            Range::Exponential(min, max) => f32::ln(value.max(min) / min) / f32::ln(max / min),
+        };
+        normalized.clamp(0.0, 1.0)
+    }
+
+    pub fn get(&self, patch: &Patch) -> f32 {
+        match self {
+            Parameter::Volume => patch.master_gain,
+            Parameter::Cutoff => patch.cutoff,
+            Parameter::Resonance => patch.resonance,
+            Parameter::FilterEnv => patch.filter_env_amount,
+            Parameter::KeyTracking => patch.key_tracking,
+            Parameter::Corners => patch.shape_params.corners,
+            Parameter::RatioX => patch.shape_params.ratio.0,
+            Parameter::RatioY => patch.shape_params.ratio.1,
+            Parameter::ShapePhase => patch.shape_params.phase,
+            Parameter::Ring => patch.shape_params.ring,
+            Parameter::Wheel => patch.shape_params.wheel,
+            Parameter::Pen => patch.shape_params.pen,
+            Parameter::SpinX => patch.spin.0,
+            Parameter::SpinY => patch.spin.1,
+            Parameter::SpinZ => patch.spin.2,
+            Parameter::Perspective => patch.perspective,
+            Parameter::DelayFeedback => patch.effects.delay.feedback,
+            Parameter::DelayMix => patch.effects.delay.mix,
+            Parameter::ChorusRate => patch.effects.chorus.rate,
+            Parameter::ChorusDepth => patch.effects.chorus.depth,
+            Parameter::ChorusMix => patch.effects.chorus.mix,
+            Parameter::ReverbSize => patch.effects.reverb.size,
+            Parameter::ReverbDamping => patch.effects.reverb.damping,
+            Parameter::ReverbMix => patch.effects.reverb.mix,
+            Parameter::Tempo => patch.tempo,
+            Parameter::ArpGate => patch.arpeggiator.gate,
+            Parameter::Attack => patch.attack.as_secs_f32(),
+            Parameter::Decay => patch.decay.as_secs_f32(),
+            Parameter::Sustain => patch.sustain,
+            Parameter::Release => patch.release.as_secs_f32(),
+            Parameter::ModRate => match patch.mod_lfo.map(|idx| patch.lfos[idx].rate) {
+                Some(Rate::Hz(freq)) => freq,
+                _ => 0.0,
+            },
+            Parameter::ModAmount => mod_route(patch).map(|route| route.amount).unwrap_or(0.0),
+            Parameter::FilterAttack => patch.filter_attack.as_secs_f32(),
+            Parameter::FilterDecay => patch.filter_decay.as_secs_f32(),
+            Parameter::FilterSustain => patch.filter_sustain,
+            Parameter::FilterRelease => patch.filter_release.as_secs_f32(),
+            Parameter::BendRange => patch.matrix.routes[patch.bend_route].amount,
+            Parameter::DelayTime => patch.effects.delay.time.seconds(patch.tempo),
+            Parameter::Transpose => patch.tuning.transpose as f32,
+            Parameter::ArpMode => position(ARP_MODES, &patch.arpeggiator.mode),
+            Parameter::ArpRate => patch.arpeggiator.rate,
+            Parameter::ArpOctaves => patch.arpeggiator.octaves as f32,
+            Parameter::Shape => position(SHAPES, &patch.shape),
+            Parameter::FilterMode => position(FILTER_MODES, &patch.filter_mode),
+            Parameter::SuperformulaM => patch.shape_params.m,
+            Parameter::SuperformulaN1 => patch.shape_params.n1,
+            Parameter::SuperformulaN2 => patch.shape_params.n2,
+            Parameter::SuperformulaN3 => patch.shape_params.n3,
+            Parameter::KnotP => patch.knot.0,
+            Parameter::KnotQ => patch.knot.1,
+        }
+    }
+
+    /// Set the parameter in its own units
+    pub fn set(&self, patch: &mut Patch, value: f32) {
+        let field = match self {
+            Parameter::Volume => &mut patch.master_gain,
+            Parameter::Cutoff => &mut patch.cutoff,
+            Parameter::Resonance => &mut patch.resonance,
+            Parameter::FilterEnv => &mut patch.filter_env_amount,
+            Parameter::KeyTracking => &mut patch.key_tracking,
+            Parameter::Corners => &mut patch.shape_params.corners,
+            Parameter::RatioX => &mut patch.shape_params.ratio.0,
+            Parameter::RatioY => &mut patch.shape_params.ratio.1,
+            Parameter::ShapePhase => &mut patch.shape_params.phase,
+            Parameter::Ring => &mut patch.shape_params.ring,
+            Parameter::Wheel => &mut patch.shape_params.wheel,
+            Parameter::Pen => &mut patch.shape_params.pen,
+            Parameter::SpinX => &mut patch.spin.0,
+            Parameter::SpinY => &mut patch.spin.1,
+            Parameter::SpinZ => &mut patch.spin.2,
+            Parameter::Perspective => &mut patch.perspective,
+            Parameter::DelayFeedback => &mut patch.effects.delay.feedback,
+            Parameter::DelayMix => &mut patch.effects.delay.mix,
+            Parameter::ChorusRate => &mut patch.effects.chorus.rate,
+            Parameter::ChorusDepth => &mut patch.effects.chorus.depth,
+            Parameter::ChorusMix => &mut patch.effects.chorus.mix,
+            Parameter::ReverbSize => &mut patch.effects.reverb.size,
+            Parameter::ReverbDamping => &mut patch.effects.reverb.damping,
+            Parameter::ReverbMix => &mut patch.effects.reverb.mix,
+            Parameter::Tempo => &mut patch.tempo,
+            Parameter::ArpGate => &mut patch.arpeggiator.gate,
+            Parameter::Sustain => &mut patch.sustain,
+            Parameter::FilterSustain => &mut patch.filter_sustain,
+            Parameter::ArpRate => &mut patch.arpeggiator.rate,
+            Parameter::SuperformulaM => &mut patch.shape_params.m,
+            Parameter::SuperformulaN1 => &mut patch.shape_params.n1,
+            Parameter::SuperformulaN2 => &mut patch.shape_params.n2,
+            Parameter::SuperformulaN3 => &mut patch.shape_params.n3,
+            Parameter::KnotP => &mut patch.knot.0,
+            Parameter::KnotQ => &mut patch.knot.1,
+            Parameter::Attack
+            | Parameter::Decay
+            | Parameter::Release
+            | Parameter::ModRate
+            | Parameter::ModAmount
+            | Parameter::FilterAttack
+            | Parameter::FilterDecay
+            | Parameter::FilterRelease
+            | Parameter::BendRange
+            | Parameter::DelayTime
+            | Parameter::Transpose
+            | Parameter::ArpMode
+            | Parameter::ArpOctaves
+            | Parameter::Shape
+            | Parameter::FilterMode => return self.set_other(patch, value),
+        };
+        *field = value;
+    }
+
+    /// The ones that aren't just an f32 in the patch
+    fn set_other(&self, patch: &mut Patch, value: f32) {
+        // Durations can't be zero, see parse_duration in opts
+        let duration = Duration::from_secs_f32(value.max(f32::EPSILON));
+        match self {
+            Parameter::Attack => patch.attack = duration,
+            Parameter::Decay => patch.decay = duration,
+            Parameter::Release => patch.release = duration,
+            Parameter::ModRate => {
+                if let Some(idx) = patch.mod_lfo {
+                    patch.lfos[idx].rate = Rate::Hz(value);
+                }
+            }
+            Parameter::ModAmount => {
+                if let Some(route) = mod_route_mut(patch) {
+                    route.amount = value;
+                }
+            }
+            Parameter::FilterAttack => patch.filter_attack = duration,
+            Parameter::FilterDecay => patch.filter_decay = duration,
+            Parameter::FilterRelease => patch.filter_release = duration,
+            Parameter::BendRange => patch.matrix.routes[patch.bend_route].amount = value,
+            Parameter::DelayTime => patch.effects.delay.time = DelayTime::Seconds(value),
+            Parameter::Transpose => patch.tuning.transpose = value.round() as i32,
+            Parameter::ArpMode => patch.arpeggiator.mode = choose(ARP_MODES, value),
+            Parameter::ArpOctaves => patch.arpeggiator.octaves = value.round().max(1.0) as u8,
+            Parameter::Shape => patch.shape = choose(SHAPES, value),
+            Parameter::FilterMode => patch.filter_mode = choose(FILTER_MODES, value),
+            _ => {}
+        }
+    }
+
+    /// Whether it changes the amp envelope, which the voices keep their own copy of
+    pub fn is_envelope(&self) -> bool {
+        matches!(
+            self,
+            Parameter::Attack | Parameter::Decay | Parameter::Sustain | Parameter::Release
+        )
+    }
+
+    /// Same for the filter envelope
+    pub fn is_filter_envelope(&self) -> bool {
+        matches!(
+            self,
+            Parameter::FilterAttack
+                | Parameter::FilterDecay
+                | Parameter::FilterSustain
+                | Parameter::FilterRelease
+        )
+    }
+
+    /// Set the parameter from a controller value between 0 and 1, scaled to a useful range
+    pub fn apply(&self, patch: &mut Patch, value: f32) {
+        self.set(patch, self.from_normalized(value));
+    }
+
+    /// Position in PARAMETERS
+    pub fn index(&self) -> usize {
+        *self as usize
+    }
+
+    /// What plugin hosts save automation against, so it can never change. New parameters
+    /// get the next number up, wherever they go in PARAMETERS
+    pub fn id(&self) -> u32 {
+        match self {
+            Parameter::Volume => 0,
+            Parameter::Cutoff => 1,
+            Parameter::Resonance => 2,
+            Parameter::FilterEnv => 3,
+            Parameter::KeyTracking => 4,
+            Parameter::Corners => 5,
+            Parameter::RatioX => 6,
+            Parameter::RatioY => 7,
+            Parameter::ShapePhase => 8,
+            Parameter::Ring => 9,
+            Parameter::Wheel => 10,
+            Parameter::Pen => 11,
+            Parameter::SpinX => 12,
+            Parameter::SpinY => 13,
+            Parameter::SpinZ => 14,
+            Parameter::Perspective => 15,
+            Parameter::DelayFeedback => 16,
+            Parameter::DelayMix => 17,
+            Parameter::ChorusRate => 18,
+            Parameter::ChorusDepth => 19,
+            Parameter::ChorusMix => 20,
+            Parameter::ReverbSize => 21,
+            Parameter::ReverbDamping => 22,
+            Parameter::ReverbMix => 23,
+            Parameter::Tempo => 24,
+            Parameter::ArpGate => 25,
+            Parameter::Attack => 26,
+            Parameter::Decay => 27,
+            Parameter::Sustain => 28,
+            Parameter::Release => 29,
+            Parameter::ModRate => 30,
+            Parameter::ModAmount => 31,
+            Parameter::FilterAttack => 32,
+            Parameter::FilterDecay => 33,
+            Parameter::FilterSustain => 34,
+            Parameter::FilterRelease => 35,
+            Parameter::BendRange => 36,
+            Parameter::DelayTime => 37,
+            Parameter::Transpose => 38,
+            Parameter::ArpMode => 39,
+            Parameter::ArpRate => 40,
+            Parameter::ArpOctaves => 41,
+            Parameter::Shape => 42,
+            Parameter::FilterMode => 43,
+            Parameter::SuperformulaM => 44,
+            Parameter::SuperformulaN1 => 45,
+            Parameter::SuperformulaN2 => 46,
+            Parameter::SuperformulaN3 => 47,
+            Parameter::KnotP => 48,
+            Parameter::KnotQ => 49,
+        }
+    }
+
+    pub fn from_id(id: u32) -> Option<Parameter> {
+        PARAMETERS
+            .iter()
+            .find(|parameter| parameter.id() == id)
+            .copied()
+    }
+}
+
+/// Where `value` is in `options`, for the ones that pick from a list
+fn position<T: PartialEq>(options: &[T], value: &T) -> f32 {
+    options
+        .iter()
+        .position(|option| option == value)
+        .unwrap_or(0) as f32
+}
+
+fn choose<T: Copy>(options: &[T], value: f32) -> T {
+    let idx = value.round().max(0.0) as usize;
+    options[idx.min(options.len() - 1)]
+}
+
+fn is_mod_route(patch: &Patch, source: &Source) -> bool {
+    patch.mod_lfo.map(Source::Lfo).as_ref() == Some(source)
+}
+
+fn mod_route(patch: &Patch) -> Option<&crate::modulation::Route> {
+    patch
+        .matrix
+        .routes
+        .iter()
+        .find(|route| is_mod_route(patch, &route.source))
+}
+
+fn mod_route_mut(patch: &mut Patch) -> Option<&mut crate::modulation::Route> {
+    let lfo = patch.mod_lfo?;
+    patch
+        .matrix
+        .routes
+        .iter_mut()
+        .find(|route| route.source == Source::Lfo(lfo))
+}
+
+/// How a controller's 0 to 1 maps onto a parameter
+enum Range {
+    Linear(f32, f32),
+    /// For things we hear logarithmically, like frequencies
+    Exponential(f32, f32),
+}
+
+impl fmt::Display for Parameter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Parameter {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Parameter> {
+        let s = s.to_lowercase();
+        PARAMETERS
+            .iter()
+            .find(|parameter| parameter.name() == s)
+            .copied()
+            .ok_or(anyhow!("Invalid value \"{}\" for parameter", s))
+    }
+}
@@ -0,0 +1,408 @@
+use crate::arpeggiator::ArpParams;
+use crate::constants::*;
+use crate::effects::{ChorusParams, DelayParams, EffectParams, ReverbParams};
+use crate::filter::FilterMode;
+use crate::lfo::{self, LfoParams};
+use crate::maths::vec3::Vec3;
+use crate::modulation::{self, ModMatrix, Route};
+use crate::opts::Opts;
+use crate::parameter::Parameter;
+use crate::synthesis::{Shape, ShapeParams};
+use crate::transform::TransformParams;
+use crate::tuning::Tuning;
+use crate::util::format_note_division;
+use crate::vec2::Vec2;
+
+use anyhow::{anyhow, Context, Result};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+use structopt::StructOpt;
+
+/// All the parameters that make up a sound
+#[derive(Debug, Clone)]
+pub struct Patch {
+    pub master_gain: f32,
+    pub attack: Duration,
+    pub decay: Duration,
+    pub sustain: f32,
+    pub release: Duration,
+    pub shape: Shape,
+    pub shape_params: ShapeParams,
+    /// Turns per second around each axis
+    pub spin: Vec3,
+    pub perspective: f32,
+    /// p and q
+    pub knot: Vec2,
+    pub filter_mode: FilterMode,
+    /// Hz
+    pub cutoff: f32,
+    pub resonance: f32,
+    pub key_tracking: f32,
+    /// Octaves
+    pub filter_env_amount: f32,
+    pub filter_attack: Duration,
+    pub filter_decay: Duration,
+    pub filter_sustain: f32,
+    pub filter_release: Duration,
+    /// Beats per minute, used by anything tempo synced
+    pub tempo: f32,
+    pub lfos: Vec<LfoParams>,
+    /// Which of the LFOs is the one from --mod-rate and --mod-amount, if there was room
+    pub mod_lfo: Option<usize>,
+    /// Which of the routes is the one from --bend-range
+    pub bend_route: usize,
+    pub matrix: ModMatrix,
+    pub effects: EffectParams,
+    pub voice_transform: TransformParams,
+    pub master_transform: TransformParams,
+    pub tuning: Tuning,
+    pub arpeggiator: ArpParams,
+}
+
+impl Patch {
+    pub fn from_opts(opts: &Opts) -> Result<Self> {
+        let mut lfos = opts.lfo.clone();
+        let mut routes = opts.modulation.clone();
+
+        if lfos.len() > MAX_LFOS {
+            log::warn!("Only {} LFOs are supported, ignoring the rest", MAX_LFOS);
+            lfos.truncate(MAX_LFOS);
+        }
+
+        // The original single LFO, still available through --mod-rate and --mod-amount.
+        // Gets added after the others so it doesn't shift the LFO numbers used in --mod.
+        // It's there even with no amount so the amount can be turned up while playing
+        let mut mod_lfo = None;
+        if lfos.len() < MAX_LFOS {
+            mod_lfo = Some(lfos.len());
+            routes.push(Route {
+                source: modulation::Source::Lfo(lfos.len()),
+                destination: modulation::Destination::Corners,
+                amount: opts.mod_amount,
+                polarity: modulation::Polarity::Bipolar,
+            });
+            lfos.push(LfoParams {
+                rate: lfo::Rate::Hz(opts.mod_rate),
+                ..LfoParams::default()
+            });
+        }
+
+        // Always there, like the --mod-amount one, so the range can be changed while playing
+        let bend_route = routes.len();
+        routes.push(Route {
+            source: modulation::Source::PitchBend,
+            destination: modulation::Destination::Pitch,
+            amount: opts.bend_range,
+            polarity: modulation::Polarity::Bipolar,
+        });
+
+        let tuning = Tuning::load(
+            opts.scl.as_deref(),
+            opts.kbm.as_deref(),
+            opts.reference_pitch,
+            opts.transpose,
+        )?;
+
+        Ok(Self {
+            master_gain: opts.master_gain,
+            attack: opts.attack,
+            decay: opts.decay,
+            sustain: opts.sustain,
+            release: opts.release,
+            shape: opts.shape,
+            shape_params: ShapeParams {
+                corners: opts.corners,
+                ratio: opts.ratio,
+                phase: opts.shape_phase,
+                ring: opts.ring,
+                wheel: opts.wheel,
+                pen: opts.pen,
+                m: opts.superformula.0,
+                n1: opts.superformula.1,
+                n2: opts.superformula.2,
+                n3: opts.superformula.3,
+            },
+            spin: opts.spin,
+            perspective: opts.perspective,
+            knot: opts.knot,
+            filter_mode: opts.filter,
+            cutoff: opts.cutoff,
+            resonance: opts.resonance,
+            key_tracking: opts.key_tracking,
+            filter_env_amount: opts.filter_env,
+            filter_attack: opts.filter_attack,
+            filter_decay: opts.filter_decay,
+            filter_sustain: opts.filter_sustain,
+            filter_release: opts.filter_release,
+            tempo: opts.tempo,
+            lfos,
+            mod_lfo,
+            bend_route,
+            matrix: ModMatrix { routes },
+            effects: EffectParams {
+                order: opts.effects.clone(),
+                delay: DelayParams {
+                    time: opts.delay_time,
+                    feedback: opts.delay_feedback,
+                    ping_pong: !opts.no_ping_pong,
+                    mix: opts.delay_mix,
+                },
+                chorus: ChorusParams {
+                    rate: opts.chorus_rate,
+                    depth: opts.chorus_depth,
+                    mix: opts.chorus_mix,
+                },
+                reverb: ReverbParams {
+                    size: opts.reverb_size,
+                    damping: opts.reverb_damping,
+                    mix: opts.reverb_mix,
+                },
+                xy_preserve: opts.xy_preserve,
+            },
+            voice_transform: opts.transform.clone(),
+            master_transform: opts.master_transform.clone(),
+            tuning,
+            arpeggiator: ArpParams {
+                mode: opts.arp,
+                octaves: opts.arp_octaves,
+                gate: opts.arp_gate,
+                rate: opts.arp_rate,
+                latch: opts.arp_latch,
+                sync: opts.arp_sync,
+            },
+        })
+    }
+
+    /// A file of the same options given on the command line, # starts a comment. Anything
+    /// not in the file gets its default
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::from_options(&read_options(path)?)
+            .with_context(|| format!("Invalid patch {}", path.display()))
+    }
+
+    pub fn from_options(options: &[String]) -> Result<Self> {
+        let opts = Opts::from_iter_safe(
+            std::iter::once(env!("CARGO_PKG_NAME")).chain(options.iter().map(String::as_str)),
+        )
+        .map_err(|e| anyhow!("{}", e.message))?;
+        Self::from_opts(&opts)
+    }
+
+    /// Write the patch out in the same format `load` reads. Anything that can't change while
+    /// playing, like the LFOs, is copied from `options`, which should be what
+    /// the patch was made from
+    pub fn save(&self, path: &Path, options: &[String]) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, self.contents(options))
+            .with_context(|| format!("Couldn't write patch {}", path.display()))
+    }
+
+    /// What `save` writes
+    pub fn contents(&self, options: &[String]) -> String {
+        let parameters = self.parameter_options();
+        let mut contents = String::from("# shapes patch, the same options as the command line\n");
+        for option in group_options(options) {
+            let name = option_name(&option[0]);
+            if SESSION_OPTIONS.contains(&name) || parameters.iter().any(|(p, _)| *p == name) {
+                continue;
+            }
+            contents += &option.join(" ");
+            contents += "\n";
+        }
+        for (name, value) in parameters {
+            if let Some(value) = value {
+                contents += &format!("{}={}\n", name, value);
+            }
+        }
+        contents
+    }
+
+    /// Options for everything that can be set while playing, None for ones that are left out
+    /// to get their default, like --arp when it's off
+    fn parameter_options(&self) -> Vec<(&'static str, Option<String>)> {
+        let shape_params = &self.shape_params;
+        let choice = |parameter: Parameter| {
+            let choices = parameter.choices().unwrap_or(&[]);
+            choices
+                .get(parameter.get(self) as usize)
+                .map(|name| name.to_string())
+        };
+        vec![
+            ("--master-gain", Some(self.master_gain.to_string())),
+            ("--cutoff", Some(self.cutoff.to_string())),
+            ("--resonance", Some(self.resonance.to_string())),
+            ("--filter-env", Some(self.filter_env_amount.to_string())),
+            ("--key-tracking", Some(self.key_tracking.to_string())),
+            ("--corners", Some(shape_params.corners.to_string())),
+            (
+                "--ratio",
+                Some(format!("{}:{}", shape_params.ratio.0, shape_params.ratio.1)),
+            ),
+            ("--shape-phase", Some(shape_params.phase.to_string())),
+            ("--ring", Some(shape_params.ring.to_string())),
+            ("--wheel", Some(shape_params.wheel.to_string())),
+            ("--pen", Some(shape_params.pen.to_string())),
+            (
+                "--spin",
+                Some(format!("{}:{}:{}", self.spin.0, self.spin.1, self.spin.2)),
+            ),
+            ("--perspective", Some(self.perspective.to_string())),
+            (
+                "--delay-feedback",
+                Some(self.effects.delay.feedback.to_string()),
+            ),
+            ("--delay-mix", Some(self.effects.delay.mix.to_string())),
+            ("--chorus-rate", Some(self.effects.chorus.rate.to_string())),
+            (
+                "--chorus-depth",
+                Some(self.effects.chorus.depth.to_string()),
+            ),
+            ("--chorus-mix", Some(self.effects.chorus.mix.to_string())),
+            ("--reverb-size", Some(self.effects.reverb.size.to_string())),
+            (
+                "--reverb-damping",
+                Some(self.effects.reverb.damping.to_string()),
+            ),
+            ("--reverb-mix", Some(self.effects.reverb.mix.to_string())),
+            ("--tempo", Some(self.tempo.to_string())),
+            ("--arp-gate", Some(self.arpeggiator.gate.to_string())),
+            ("--attack", Some(self.attack.as_secs_f32().to_string())),
+            ("--decay", Some(self.decay.as_secs_f32().to_string())),
+            ("--sustain", Some(self.sustain.to_string())),
+            ("--release", Some(self.release.as_secs_f32().to_string())),
+            ("--mod-rate", Some(Parameter::ModRate.get(self).to_string())),
+            (
+                "--mod-amount",
+                Some(Parameter::ModAmount.get(self).to_string()),
+            ),
+            (
+                "--filter-attack",
+                Some(self.filter_attack.as_secs_f32().to_string()),
+            ),
+            (
+                "--filter-decay",
+                Some(self.filter_decay.as_secs_f32().to_string()),
+            ),
+            ("--filter-sustain", Some(self.filter_sustain.to_string())),
+            (
+                "--filter-release",
+                Some(self.filter_release.as_secs_f32().to_string()),
+            ),
+            (
+                "--bend-range",
+                Some(Parameter::BendRange.get(self).to_string()),
+            ),
+            ("--delay-time", Some(self.effects.delay.time.to_string())),
+            ("--transpose", Some(self.tuning.transpose.to_string())),
+            (
+                "--arp",
+                self.arpeggiator.mode.and(choice(Parameter::ArpMode)),
+            ),
+            (
+                "--arp-rate",
+                Some(format_note_division(self.arpeggiator.rate)),
+            ),
+            ("--arp-octaves", Some(self.arpeggiator.octaves.to_string())),
+            ("--shape", choice(Parameter::Shape)),
+            ("--filter", choice(Parameter::FilterMode)),
+            (
+                "--superformula",
+                Some(format!(
+                    "{}:{}:{}:{}",
+                    shape_params.m, shape_params.n1, shape_params.n2, shape_params.n3
+                )),
+            ),
+            ("--knot", Some(format!("{}:{}", self.knot.0, self.knot.1))),
+        ]
+    }
+}
+
+/// Options that are about how we're running rather than how we sound, left out of saved
+/// patches
+const SESSION_OPTIONS: &[&str] = &[
+    "--channels",
+    "--sample-rate",
+    "--buffer-size",
+    "--voices",
+    "--unison-mode",
+    "--host",
+    "--device",
+    "--jack-name",
+    "--jack-connect",
+    "--midi-port",
+    "--midi-port-index",
+    "--virtual-midi",
+    "--osc-port",
+    "--control-socket",
+    "--learn",
+    "--midi-map",
+    "--list-hosts",
+    "--list-outputs",
+    "--list-inputs",
+    "--verbose",
+    "--quiet",
+    "--tui",
+    "--keyboard",
+];
+
+/// Long names for the short options, -v and -q don't have long ones so they get made up
+const SHORT_OPTIONS: &[(&str, &str)] = &[
+    ("-c", "--channels"),
+    ("-s", "--sample-rate"),
+    ("-b", "--buffer-size"),
+    ("-o", "--voices"),
+    ("-u", "--unison-mode"),
+    ("-d", "--device"),
+    ("-p", "--midi-port"),
+    ("-g", "--master-gain"),
+    ("-v", "--verbose"),
+    ("-q", "--quiet"),
+    ("-m", "--mod"),
+];
+
+/// Every word in a patch file, without comments
+pub fn read_options(path: &Path) -> Result<Vec<String>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Couldn't read patch {}", path.display()))?;
+    Ok(parse_options(&contents))
+}
+
+/// Every word in the contents of a patch file, without comments
+pub fn parse_options(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or(""))
+        .flat_map(str::split_whitespace)
+        .map(String::from)
+        .collect()
+}
+
+/// Each option along with its values. Nothing takes positional arguments, so anything that
+/// isn't an option belongs to the one before it
+fn group_options(words: &[String]) -> Vec<Vec<String>> {
+    let mut options: Vec<Vec<String>> = Vec::new();
+    for word in words {
+        let is_option = word.starts_with('-') && f32::from_str(word).is_err();
+        match options.last_mut() {
+            Some(option) if !is_option => option.push(word.clone()),
+            _ => options.push(vec![word.clone()]),
+        }
+    }
+    options
+}
+
+/// The long name of an option, e.g. "--cutoff" for "--cutoff=800" and "--verbose" for "-vv"
+fn option_name(word: &str) -> &str {
+    if word.starts_with("--") {
+        return word.split('=').next().unwrap_or(word);
+    }
+    let short = word.get(..2).unwrap_or(word);
+    SHORT_OPTIONS
+        .iter()
+        .find(|(s, _)| *s == short)
+        .map(|(_, long)| *long)
+        .unwrap_or(short)
+}
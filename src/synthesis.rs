@@ -14,6 +14,39 @@ pub enum EnvelopeState {
     Off,
 }
 
+/// Where an envelope is up to, for showing people
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stage {
+    Off,
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+impl Stage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::Off => "off",
+            Stage::Attack => "attack",
+            Stage::Decay => "decay",
+            Stage::Sustain => "sustain",
+            Stage::Release => "release",
+        }
+    }
+
+    /// Back from `as u8`, for passing between threads
+    pub fn from_u8(stage: u8) -> Self {
+        match stage {
+            1 => Stage::Attack,
+            2 => Stage::Decay,
+            3 => Stage::Sustain,
+            4 => Stage::Release,
+            _ => Stage::Off,
+        }
+    }
+}
+
 pub struct Envelope {
     state: EnvelopeState,
     attack: Duration,
@@ -62,6 +95,20 @@ impl Envelope {
         }
     }
 
+    /// Change the times and levels without starting over
+    pub fn set_adsr(
+        &mut self,
+        attack: Duration,
+        decay: Duration,
+        sustain_level: f32,
+        release: Duration,
+    ) {
+        self.attack = attack;
+        self.decay = decay;
+        self.sustain_level = sustain_level;
+        self.release = release;
+    }
+
     pub fn hold(&mut self, timer: &SampleTimer) {
         let level = self.get(timer);
         self.state = EnvelopeState::Held(level, timer.sample());
@@ -79,13 +126,96 @@ impl Envelope {
     pub fn bypass(&mut self) {
         self.state = EnvelopeState::Bypass;
     }
+
+    pub fn stage(&self, timer: &SampleTimer) -> Stage {
+        match &self.state {
+            EnvelopeState::Held(_, start) => {
+                let elapsed = timer.time_since(*start);
+                let attack = self.attack.as_secs_f32();
+                if elapsed < attack {
+                    Stage::Attack
+                } else if elapsed < attack + self.decay.as_secs_f32() {
+                    Stage::Decay
+                } else {
+                    Stage::Sustain
+                }
+            }
+            EnvelopeState::Released(_, start) => {
+                if timer.time_since(*start) < self.release.as_secs_f32() {
+                    Stage::Release
+                } else {
+                    Stage::Off
+                }
+            }
+            EnvelopeState::Bypass => Stage::Sustain,
+            EnvelopeState::Off => Stage::Off,
+        }
+    }
 }
 
 pub struct Voice {
     pub note: Note,
     pub level: f32,
     pub envelope: Envelope,
-    pub lfo_timer: SampleTimer,
+    pub filter: crate::filter::Filter,
+    pub filter_envelope: Envelope,
+    pub transform: crate::transform::Transform,
+    pub spin: crate::wireframe::Spin,
+    pub superformula: Superformula,
+    pub lfos: Vec<crate::lfo::Lfo>,
+    /// Polyphonic aftertouch
+    pub aftertouch: f32,
+    /// Picked on note on, for the random modulation source
+    pub random: f32,
+    /// Modulation amounts from the last sample
+    pub modulation: crate::modulation::Modulation,
+    /// How far around the shape it is, 0 to 1
+    pub phase: f32,
+}
+
+impl Voice {
+    /// Stop making sound right away, no release
+    pub fn reset(&mut self) {
+        self.envelope.disable();
+        self.filter_envelope.disable();
+        self.filter.reset();
+        self.transform.reset();
+        self.spin.reset();
+        self.aftertouch = 0.0;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shape {
+    Polygon,
+    Tetrahedron,
+    Cube,
+    Octahedron,
+    Icosahedron,
+    TorusKnot,
+    Lissajous,
+    Hypotrochoid,
+    Epitrochoid,
+    Superformula,
+}
+
+impl std::str::FromStr for Shape {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Shape> {
+        match s.to_lowercase().as_str() {
+            "polygon" => Ok(Shape::Polygon),
+            "tetrahedron" => Ok(Shape::Tetrahedron),
+            "cube" => Ok(Shape::Cube),
+            "octahedron" => Ok(Shape::Octahedron),
+            "icosahedron" => Ok(Shape::Icosahedron),
+            "torus-knot" | "knot" => Ok(Shape::TorusKnot),
+            "lissajous" => Ok(Shape::Lissajous),
+            "hypotrochoid" | "spirograph" => Ok(Shape::Hypotrochoid),
+            "epitrochoid" => Ok(Shape::Epitrochoid),
+            "superformula" => Ok(Shape::Superformula),
+            _ => Err(anyhow!("Invalid value \"{}\" for Shape", s)),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -105,8 +235,15 @@ impl std::str::FromStr for UnisonMode {
     }
 }
 
-pub fn phase(freq: f32, counter: &SampleTimer) -> f32 {
-    (counter.sample() % (counter.samplerate() / freq) as u64) as f32 * freq / counter.samplerate()
+/// One sample further around the shape. Bends, modulation and retuning can take the pitch
+/// anywhere, past Nyquist it would only alias so it stops there, and NaN stands still
+pub fn advance_phase(phase: f32, freq: f32, samplerate: f32) -> f32 {
+    let freq = if freq.is_finite() {
+        freq.clamp(0.0, samplerate / 2.0)
+    } else {
+        0.0
+    };
+    (phase + freq / samplerate).fract()
 }
 
 pub fn circle(p: f32) -> Vec2 {
@@ -132,6 +269,152 @@ pub fn polygon(n: f32, p: f32) -> Vec2 {
     vec2::lerp(c1, c2, progress)
 }
 
+/// Parameters of the flat shapes. Also used to sum up their modulation
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ShapeParams {
+    pub corners: f32,
+    /// Lissajous frequency ratio, x:y
+    pub ratio: Vec2,
+    /// Lissajous phase offset of x, in turns
+    pub phase: f32,
+    /// Spirograph ring radius
+    pub ring: f32,
+    /// Spirograph wheel radius
+    pub wheel: f32,
+    /// Spirograph pen distance from the wheel's center
+    pub pen: f32,
+    /// Superformula parameters
+    pub m: f32,
+    pub n1: f32,
+    pub n2: f32,
+    pub n3: f32,
+}
+
+impl ShapeParams {
+    pub fn add(&self, other: &ShapeParams) -> ShapeParams {
+        ShapeParams {
+            corners: self.corners + other.corners,
+            ratio: vec2::add(self.ratio, other.ratio),
+            phase: self.phase + other.phase,
+            ring: self.ring + other.ring,
+            wheel: self.wheel + other.wheel,
+            pen: self.pen + other.pen,
+            m: self.m + other.m,
+            n1: self.n1 + other.n1,
+            n2: self.n2 + other.n2,
+            n3: self.n3 + other.n3,
+        }
+    }
+}
+
+/// Any of the flat shapes, the 3D ones are in `wireframe`
+pub fn flat_shape(
+    shape: Shape,
+    params: &ShapeParams,
+    superformula: &mut Superformula,
+    p: f32,
+) -> Vec2 {
+    match shape {
+        Shape::Lissajous => lissajous(params.ratio, params.phase, p),
+        Shape::Hypotrochoid => spirograph(params.ring, params.wheel, params.pen, false, p),
+        Shape::Epitrochoid => spirograph(params.ring, params.wheel, params.pen, true, p),
+        Shape::Superformula => superformula.point(params, p),
+        _ => polygon(params.corners, p),
+    }
+}
+
+pub fn lissajous(ratio: Vec2, phase: f32, p: f32) -> Vec2 {
+    let theta = 2.0 * PI * p;
+    (
+        f32::sin(ratio.0 * theta + 2.0 * PI * phase),
+        f32::sin(ratio.1 * theta),
+    )
+}
+
+/// Hypotrochoid (wheel rolling inside the ring) or epitrochoid (rolling around the outside).
+/// A single period traces the whole curve until it closes, up to 32 laps of the ring
+pub fn spirograph(ring: f32, wheel: f32, pen: f32, outside: bool, p: f32) -> Vec2 {
+    let wheel = if wheel.abs() < 0.001 { 0.001 } else { wheel };
+    let ratio = ring / wheel;
+    let laps = (1..=32)
+        .find(|n| {
+            let turns = *n as f32 * ratio;
+            (turns - turns.round()).abs() < 0.001
+        })
+        .unwrap_or(32);
+
+    let theta = 2.0 * PI * p * laps as f32;
+    let (x, y) = if outside {
+        let r = ring + wheel;
+        (
+            r * f32::cos(theta) - pen * f32::cos(r / wheel * theta),
+            r * f32::sin(theta) - pen * f32::sin(r / wheel * theta),
+        )
+    } else {
+        let r = ring - wheel;
+        (
+            r * f32::cos(theta) + pen * f32::cos(r / wheel * theta),
+            r * f32::sin(theta) - pen * f32::sin(r / wheel * theta),
+        )
+    };
+
+    // Scale down to the unit circle
+    let extent = if outside {
+        ring + wheel
+    } else {
+        (ring - wheel).abs()
+    } + pen.abs();
+    if extent > 0.0 {
+        vec2::scale((x, y), 1.0 / extent)
+    } else {
+        (0.0, 0.0)
+    }
+}
+
+/// Points checked around a quarter turn of the superformula when looking for its furthest one
+const SUPERFORMULA_STEPS: usize = 64;
+
+/// Gielis superformula with a = b = 1, scaled down so its furthest point is on the unit
+/// circle. Finding that point means going around the curve, so it's kept until n1, n2 or n3
+/// change. m only repeats the curve, it doesn't change how far out it goes
+#[derive(Default)]
+pub struct Superformula {
+    /// n1, n2 and n3 the scale was worked out for
+    n: Option<(f32, f32, f32)>,
+    scale: f32,
+}
+
+impl Superformula {
+    pub fn point(&mut self, params: &ShapeParams, p: f32) -> Vec2 {
+        let n1 = if params.n1.abs() < 0.001 {
+            0.001
+        } else {
+            params.n1
+        };
+        let n = (n1, params.n2, params.n3);
+        if self.n != Some(n) {
+            // The radius repeats every quarter turn of the angle inside the formula
+            let peak = (0..=SUPERFORMULA_STEPS)
+                .map(|step| step as f32 / SUPERFORMULA_STEPS as f32 * PI / 2.0)
+                .map(|angle| superformula_radius(n, angle))
+                .filter(|r| r.is_finite())
+                .fold(0.0, f32::max);
+            self.n = Some(n);
+            self.scale = if peak > 0.0 { 1.0 / peak } else { 0.0 };
+        }
+        let phi = 2.0 * PI * p;
+        let r = superformula_radius(n, params.m * phi / 4.0) * self.scale;
+        // Some settings blow up at a few points
+        let r = if r.is_finite() { r } else { 0.0 };
+        (r * f32::cos(phi), r * f32::sin(phi))
+    }
+}
+
+fn superformula_radius((n1, n2, n3): (f32, f32, f32), angle: f32) -> f32 {
+    let sum = f32::cos(angle).abs().powf(n2) + f32::sin(angle).abs().powf(n3);
+    sum.powf(-1.0 / n1)
+}
+
 fn binaural_beats(sample: u64, f1: f32, f2: f32, samplerate: f32) -> Vec2 {
     (0.0, 0.0)
 }
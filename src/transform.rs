@@ -0,0 +1,153 @@
+use crate::vec2::{self, Vec2};
+
+use anyhow::{anyhow, Result};
+use core::f32::consts::PI;
+use std::str::FromStr;
+
+/// Number of `TransformParam`s, for indexing arrays of modulation amounts
+pub const TRANSFORM_PARAMS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransformParam {
+    /// In turns, 1 is a full rotation
+    Rotation,
+    /// In turns per second
+    RotationSpeed,
+    ScaleX,
+    ScaleY,
+    ShearX,
+    ShearY,
+    OffsetX,
+    OffsetY,
+}
+
+impl FromStr for TransformParam {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<TransformParam> {
+        match s.to_lowercase().as_str() {
+            "rotation" => Ok(TransformParam::Rotation),
+            "rotation-speed" => Ok(TransformParam::RotationSpeed),
+            "scale-x" => Ok(TransformParam::ScaleX),
+            "scale-y" => Ok(TransformParam::ScaleY),
+            "shear-x" => Ok(TransformParam::ShearX),
+            "shear-y" => Ok(TransformParam::ShearY),
+            "offset-x" => Ok(TransformParam::OffsetX),
+            "offset-y" => Ok(TransformParam::OffsetY),
+            _ => Err(anyhow!("Invalid value \"{}\" for transform parameter", s)),
+        }
+    }
+}
+
+/// 2D affine transform, applied as scale, shear, rotation then offset
+#[derive(Debug, Clone)]
+pub struct TransformParams {
+    /// In turns
+    pub rotation: f32,
+    /// In turns per second
+    pub rotation_speed: f32,
+    pub scale: Vec2,
+    pub shear: Vec2,
+    pub offset: Vec2,
+}
+
+impl Default for TransformParams {
+    fn default() -> Self {
+        Self {
+            rotation: 0.0,
+            rotation_speed: 0.0,
+            scale: (1.0, 1.0),
+            shear: (0.0, 0.0),
+            offset: (0.0, 0.0),
+        }
+    }
+}
+
+impl FromStr for TransformParams {
+    type Err = anyhow::Error;
+    /// Comma separated key=value pairs, pairs of values are separated by a colon, e.g.
+    /// "rotation=0.125,speed=0.5,scale=1:0.5,shear=0.2:0,offset=0:0.1"
+    fn from_str(s: &str) -> Result<TransformParams> {
+        let mut params = TransformParams::default();
+
+        let parse_pair = |value: &str| -> Result<Vec2> {
+            match value.split_once(':') {
+                Some((x, y)) => Ok((f32::from_str(x)?, f32::from_str(y)?)),
+                None => {
+                    let v = f32::from_str(value)?;
+                    Ok((v, v))
+                }
+            }
+        };
+
+        for field in s.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            let (key, value) = field.split_once('=').ok_or(anyhow!(
+                "Expected key=value in transform options, got \"{}\"",
+                field
+            ))?;
+            match key {
+                "rotation" => params.rotation = f32::from_str(value)?,
+                "speed" => params.rotation_speed = f32::from_str(value)?,
+                "scale" => params.scale = parse_pair(value)?,
+                "shear" => params.shear = parse_pair(value)?,
+                "offset" => params.offset = parse_pair(value)?,
+                _ => return Err(anyhow!("Unknown transform option \"{}\"", key)),
+            }
+        }
+
+        Ok(params)
+    }
+}
+
+/// Running state of a transform, the rotation accumulated from the rotation speed
+pub struct Transform {
+    angle: f32,
+}
+
+impl Transform {
+    pub fn new() -> Self {
+        Self { angle: 0.0 }
+    }
+
+    pub fn reset(&mut self) {
+        self.angle = 0.0;
+    }
+
+    /// Transform `v` and advance by one sample. `modulation` is indexed by `TransformParam`
+    pub fn process(
+        &mut self,
+        params: &TransformParams,
+        modulation: &[f32; TRANSFORM_PARAMS],
+        v: Vec2,
+        samplerate: f32,
+    ) -> Vec2 {
+        let amount = |param: TransformParam| modulation[param as usize];
+
+        let scale = (
+            params.scale.0 + amount(TransformParam::ScaleX),
+            params.scale.1 + amount(TransformParam::ScaleY),
+        );
+        let shear = (
+            params.shear.0 + amount(TransformParam::ShearX),
+            params.shear.1 + amount(TransformParam::ShearY),
+        );
+        let offset = (
+            params.offset.0 + amount(TransformParam::OffsetX),
+            params.offset.1 + amount(TransformParam::OffsetY),
+        );
+        let rotation = params.rotation + amount(TransformParam::Rotation) + self.angle;
+
+        let speed = params.rotation_speed + amount(TransformParam::RotationSpeed);
+        self.angle = (self.angle + speed / samplerate).rem_euclid(1.0);
+
+        let v = vec2::mul(v, scale);
+        let v = vec2::shear(v, shear);
+        let v = vec2::rotate(v, 2.0 * PI * rotation);
+        vec2::add(v, offset)
+    }
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self::new()
+    }
+}
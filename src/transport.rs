@@ -0,0 +1,269 @@
+/// MIDI clock ticks per beat
+pub const CLOCKS_PER_BEAT: u32 = 24;
+/// Fall back to the internal clock after this long without any clock ticks
+const CLOCK_TIMEOUT_SECONDS: f32 = 0.5;
+/// How much each new tick interval moves the smoothed interval
+const SMOOTHING: f64 = 0.1;
+/// Beats per minute, anything outside this is a glitch in the clock
+const MIN_TEMPO: f32 = 20.0;
+const MAX_TEMPO: f32 = 400.0;
+
+/// Tempo and beat position, following incoming MIDI clock when there is any and the
+/// patch tempo otherwise. Lives on the audio thread
+pub struct Transport {
+    internal_tempo: f32,
+    /// Smoothed time between clock ticks in microseconds
+    tick_interval: Option<f64>,
+    last_tick_timestamp: Option<u64>,
+    samples_since_tick: u64,
+    /// Ticks since the start of the song, up to the last one
+    ticks: u64,
+    /// Started or moved since the last tick, so the next one lands on `ticks` rather than
+    /// the one after. The first tick after a Start is beat 0
+    waiting: bool,
+    /// Beats since the start of the song, only used with the internal clock
+    internal_beat: f64,
+    external: bool,
+    running: bool,
+}
+
+impl Transport {
+    pub fn new(tempo: f32) -> Self {
+        Self {
+            internal_tempo: tempo,
+            tick_interval: None,
+            last_tick_timestamp: None,
+            samples_since_tick: 0,
+            ticks: 0,
+            waiting: true,
+            internal_beat: 0.0,
+            external: false,
+            running: true,
+        }
+    }
+
+    pub fn set_internal_tempo(&mut self, tempo: f32) {
+        self.internal_tempo = tempo;
+    }
+
+    /// Beats per minute
+    pub fn tempo(&self) -> f32 {
+        let tempo = match self.tick_interval {
+            Some(interval) if self.external => {
+                (60_000_000.0 / (interval * CLOCKS_PER_BEAT as f64)) as f32
+            }
+            _ => self.internal_tempo,
+        };
+        tempo.clamp(MIN_TEMPO, MAX_TEMPO)
+    }
+
+    /// Whether we're following an external clock
+    pub fn is_external(&self) -> bool {
+        self.external
+    }
+
+    /// Beats since the start of the song
+    pub fn beat(&self, samplerate: f32) -> f64 {
+        if !self.external {
+            return self.internal_beat;
+        }
+        // Fill in between ticks, but never run past where the next tick will be
+        let since_tick =
+            (self.samples_since_tick as f64 / samplerate as f64) * self.tempo() as f64 / 60.0;
+        let tick = 1.0 / CLOCKS_PER_BEAT as f64;
+        self.ticks as f64 * tick
+            + if self.running && !self.waiting {
+                since_tick.min(tick)
+            } else {
+                0.0
+            }
+    }
+
+    /// MIDI clock tick, `timestamp` in microseconds
+    pub fn clock(&mut self, timestamp: u64) {
+        // Ticks with the same timestamp, e.g. from two ports at once, don't say anything about
+        // the tempo
+        let interval = self
+            .last_tick_timestamp
+            .filter(|last| timestamp > *last)
+            .map(|last| (timestamp - last) as f64);
+        if let Some(interval) = interval {
+            self.tick_interval = Some(match self.tick_interval {
+                // Way off from what we had, the tempo probably jumped so don't smooth it
+                Some(smoothed) if interval > smoothed * 2.0 || interval < smoothed / 2.0 => {
+                    interval
+                }
+                Some(smoothed) => smoothed + (interval - smoothed) * SMOOTHING,
+                None => interval,
+            });
+        }
+        if !self.external {
+            self.external = true;
+            self.ticks = (self.internal_beat * CLOCKS_PER_BEAT as f64) as u64;
+            self.waiting = true;
+        }
+        self.last_tick_timestamp = Some(timestamp);
+        self.samples_since_tick = 0;
+        if self.running {
+            if self.waiting {
+                self.waiting = false;
+            } else {
+                self.ticks += 1;
+            }
+        }
+    }
+
+    pub fn start(&mut self) {
+        self.ticks = 0;
+        self.waiting = true;
+        self.internal_beat = 0.0;
+        self.running = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.running = true;
+    }
+
+    pub fn stop(&mut self) {
+        self.running = false;
+    }
+
+    /// Position in MIDI beats, which are sixteenth notes
+    pub fn song_position(&mut self, position: u16) {
+        self.ticks = position as u64 * (CLOCKS_PER_BEAT / 4) as u64;
+        self.waiting = true;
+        self.internal_beat = position as f64 / 4.0;
+    }
+
+    /// Advance by one sample
+    pub fn tick(&mut self, samplerate: f32) {
+        self.samples_since_tick += 1;
+        if self.external && self.samples_since_tick as f32 > CLOCK_TIMEOUT_SECONDS * samplerate {
+            self.external = false;
+            self.last_tick_timestamp = None;
+            self.tick_interval = None;
+            self.internal_beat = self.ticks as f64 / CLOCKS_PER_BEAT as f64;
+            // Not getting clock anymore, don't sit there stopped forever
+            self.running = true;
+        }
+        if !self.external && self.running {
+            self.internal_beat += self.tempo() as f64 / 60.0 / samplerate as f64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: f32 = 48000.0;
+
+    /// Microseconds between ticks at a tempo
+    fn interval(tempo: f64) -> u64 {
+        (60_000_000.0 / (tempo * CLOCKS_PER_BEAT as f64)) as u64
+    }
+
+    fn assert_near(a: f32, b: f32) {
+        assert!((a - b).abs() < 0.01, "{} isn't {}", a, b);
+    }
+
+    #[test]
+    fn first_tick_after_start_is_beat_zero() {
+        let mut transport = Transport::new(120.0);
+        transport.start();
+        transport.clock(1000);
+        assert_eq!(transport.beat(SAMPLE_RATE), 0.0);
+        for tick in 1..=CLOCKS_PER_BEAT as u64 {
+            transport.clock(1000 + tick * interval(120.0));
+        }
+        assert_eq!(transport.beat(SAMPLE_RATE), 1.0);
+
+        // Stopping and starting again goes back to the beginning
+        transport.stop();
+        transport.start();
+        transport.clock(1000 + 25 * interval(120.0));
+        assert_eq!(transport.beat(SAMPLE_RATE), 0.0);
+    }
+
+    #[test]
+    fn song_position_is_the_next_tick() {
+        let mut transport = Transport::new(120.0);
+        transport.clock(0);
+        transport.stop();
+        // Sixteenths, so the second bar
+        transport.song_position(16);
+        transport.resume();
+        transport.clock(interval(120.0));
+        assert_eq!(transport.beat(SAMPLE_RATE), 4.0);
+        transport.clock(2 * interval(120.0));
+        let beat = transport.beat(SAMPLE_RATE);
+        assert!(
+            (beat - (4.0 + 1.0 / CLOCKS_PER_BEAT as f64)).abs() < 1e-9,
+            "{}",
+            beat
+        );
+    }
+
+    #[test]
+    fn tempo_is_smoothed() {
+        let mut transport = Transport::new(100.0);
+        let mut timestamp = 0;
+        for _ in 0..4 {
+            timestamp += interval(125.0);
+            transport.clock(timestamp);
+        }
+        assert_near(transport.tempo(), 125.0);
+
+        // A late tick only moves it a bit
+        timestamp += interval(125.0) * 11 / 10;
+        transport.clock(timestamp);
+        let tempo = transport.tempo();
+        assert!(tempo < 125.0 && tempo > 123.0, "{}", tempo);
+
+        // Twice as slow is a new tempo, not jitter
+        timestamp += interval(60.0);
+        transport.clock(timestamp);
+        assert_near(transport.tempo(), 60.0);
+    }
+
+    #[test]
+    fn zero_intervals_are_ignored() {
+        let mut transport = Transport::new(100.0);
+        transport.clock(0);
+        transport.clock(interval(125.0));
+        // The same tick arriving from two ports
+        transport.clock(interval(125.0));
+        transport.clock(2 * interval(125.0));
+        assert_near(transport.tempo(), 125.0);
+    }
+
+    #[test]
+    fn tempo_stays_in_range() {
+        let mut transport = Transport::new(100.0);
+        transport.clock(0);
+        transport.clock(1);
+        assert_eq!(transport.tempo(), MAX_TEMPO);
+    }
+
+    #[test]
+    fn clock_times_out() {
+        let mut transport = Transport::new(100.0);
+        transport.start();
+        for tick in 0..=CLOCKS_PER_BEAT as u64 {
+            transport.clock(tick * interval(125.0));
+        }
+        transport.stop();
+        assert!(transport.is_external());
+        for _ in 0..(CLOCK_TIMEOUT_SECONDS * SAMPLE_RATE) as usize {
+            transport.tick(SAMPLE_RATE);
+        }
+        assert!(transport.is_external());
+
+        transport.tick(SAMPLE_RATE);
+        assert!(!transport.is_external());
+        assert_eq!(transport.tempo(), 100.0);
+        // Carries on from where the clock got to
+        let beat = transport.beat(SAMPLE_RATE);
+        assert!((1.0..1.01).contains(&beat), "{}", beat);
+    }
+}
@@ -0,0 +1,411 @@
+use shapes::channel::Sender;
+use shapes::engine::{Message, Status};
+use shapes::midi_map::MidiMap;
+use shapes::parameter::{Parameter, PARAMETERS};
+use shapes::patch::{self, Patch};
+use shapes::synthesis::Stage;
+use shapes::util::format_note_division;
+
+use anyhow::{anyhow, Result};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::terminal::{self, EnterAlternateScreen, LeaveAlternateScreen};
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Direction, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::symbols::Marker;
+use ratatui::text::{Line, Span};
+use ratatui::widgets::canvas::{Canvas, Points};
+use ratatui::widgets::{Block, Borders, Gauge, Paragraph};
+use ratatui::{Frame, Terminal};
+use std::convert::TryFrom;
+use std::io::Stdout;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::Duration;
+use wmidi::Note;
+
+/// How long to wait for a key before drawing again, about 30 frames a second
+const FRAME_INTERVAL: Duration = Duration::from_millis(33);
+/// Steps across a parameter's range with the arrow keys, shift makes them bigger
+const SMALL_STEP: f32 = 0.01;
+const BIG_STEP: f32 = 0.1;
+/// Quietest level the meters show
+const METER_FLOOR_DB: f32 = -60.0;
+
+/// Asking for a file name
+enum Prompt {
+    Save(String),
+    Load(String),
+}
+
+struct Tui<'a> {
+    sender: &'a Sender,
+    status: &'a Status,
+    midi_map: &'a Mutex<MidiMap>,
+    /// What the current patch was made from, what gets saved along with the parameters
+    options: Vec<String>,
+    selected: usize,
+    prompt: Option<Prompt>,
+    /// Result of the last save or load, logging would mess up the screen
+    message: String,
+}
+
+impl<'a> Tui<'a> {
+    /// Returns false when it's time to quit
+    fn key(&mut self, key: KeyEvent) -> bool {
+        if let Some(prompt) = &mut self.prompt {
+            let path = match prompt {
+                Prompt::Save(path) | Prompt::Load(path) => path,
+            };
+            match key.code {
+                KeyCode::Char(c) => path.push(c),
+                KeyCode::Backspace => {
+                    path.pop();
+                }
+                KeyCode::Enter => {
+                    let prompt = self.prompt.take().unwrap();
+                    self.message = match self.finish(prompt) {
+                        Ok(message) => message,
+                        Err(e) => format!("{:#}", e),
+                    };
+                }
+                KeyCode::Esc => self.prompt = None,
+                _ => {}
+            }
+            return true;
+        }
+
+        let step = if key.modifiers.contains(KeyModifiers::SHIFT) {
+            BIG_STEP
+        } else {
+            SMALL_STEP
+        };
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return false,
+            KeyCode::Up | KeyCode::Char('k') => {
+                self.selected = self.selected.checked_sub(1).unwrap_or(PARAMETERS.len() - 1)
+            }
+            KeyCode::Down | KeyCode::Char('j') => {
+                self.selected = (self.selected + 1) % PARAMETERS.len()
+            }
+            KeyCode::Left | KeyCode::Char('h') => self.nudge(-step),
+            KeyCode::Right | KeyCode::Char('l') => self.nudge(step),
+            KeyCode::Char('H') => self.nudge(-BIG_STEP),
+            KeyCode::Char('L') => self.nudge(BIG_STEP),
+            KeyCode::Char('s') => self.prompt = Some(Prompt::Save(preset_dir())),
+            KeyCode::Char('o') => self.prompt = Some(Prompt::Load(preset_dir())),
+            KeyCode::Char('p') => {
+                self.send(Message::Reset);
+                self.message = String::from("Reset all voices");
+            }
+            KeyCode::Char('m') => {
+                let parameter = PARAMETERS[self.selected];
+                self.midi_map.lock().unwrap().learn(parameter);
+                // Only shows once it's done, until then the footer asks for a controller
+                self.message = format!("Bound a controller to {}", parameter);
+            }
+            _ => {}
+        }
+        true
+    }
+
+    fn nudge(&mut self, step: f32) {
+        let parameter = PARAMETERS[self.selected];
+        let value = self.status.parameter(parameter);
+        let value = if parameter.is_integer() {
+            // A small step would get rounded straight back, so go to the next whole number
+            parameter.to_normalized(value + step.signum())
+        } else {
+            parameter.to_normalized(value) + step
+        };
+        self.send(Message::SetParameter(parameter, value.clamp(0.0, 1.0)));
+    }
+
+    fn send(&mut self, message: Message) {
+        if self.sender.send(message).is_err() {
+            self.message = String::from("Audio isn't running");
+        }
+    }
+
+    fn finish(&mut self, prompt: Prompt) -> Result<String> {
+        match prompt {
+            Prompt::Save(path) => {
+                let path = PathBuf::from(path);
+                // The audio thread has the patch, this gets the same one back from what it
+                // was made of and what the parameters are now
+                let mut patch = Patch::from_options(&self.options)?;
+                for parameter in PARAMETERS {
+                    parameter.set(&mut patch, self.status.parameter(*parameter));
+                }
+                patch.save(&path, &self.options)?;
+                Ok(format!("Saved {}", path.display()))
+            }
+            Prompt::Load(path) => {
+                let path = PathBuf::from(path);
+                let options = patch::read_options(&path)?;
+                let patch = Patch::from_options(&options)?;
+                self.sender
+                    .send(Message::load_patch(patch))
+                    .map_err(|_| anyhow!("Audio isn't running"))?;
+                self.options = options;
+                Ok(format!("Loaded {}", path.display()))
+            }
+        }
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(0), Constraint::Length(1)])
+            .split(frame.size());
+        let columns = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(55), Constraint::Percentage(45)])
+            .split(rows[0]);
+        let right = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Percentage(50),
+                Constraint::Length(4),
+                Constraint::Min(0),
+            ])
+            .split(columns[1]);
+
+        self.draw_parameters(frame, columns[0]);
+        self.draw_scope(frame, right[0]);
+        self.draw_meters(frame, right[1]);
+        self.draw_voices(frame, right[2]);
+
+        let learning = self.midi_map.lock().unwrap().learning();
+        let footer = match (&self.prompt, learning) {
+            (Some(Prompt::Save(path)), _) => format!("Save to: {}", path),
+            (Some(Prompt::Load(path)), _) => format!("Load from: {}", path),
+            (None, Some(parameter)) => format!("Move a controller to bind it to {}", parameter),
+            (None, None) if !self.message.is_empty() => self.message.clone(),
+            (None, None) => String::from(
+                "↑↓ select  ←→ change (shift for more)  m learn  s save  o load  p panic  q quit",
+            ),
+        };
+        frame.render_widget(Paragraph::new(footer), rows[1]);
+    }
+
+    fn draw_parameters(&self, frame: &mut Frame, area: Rect) {
+        let block = Block::default().borders(Borders::ALL).title("Parameters");
+        let inner = block.inner(area);
+        frame.render_widget(block, area);
+
+        // Name, bar, value
+        let bar_width = (inner.width as usize).saturating_sub(16 + 12 + 2);
+        let height = inner.height as usize;
+        // Keep the selected one on screen
+        let first = (self.selected + 1).saturating_sub(height);
+        let lines = PARAMETERS
+            .iter()
+            .enumerate()
+            .skip(first)
+            .take(height)
+            .map(|(idx, parameter)| {
+                let value = self.status.parameter(*parameter);
+                let filled = (parameter.to_normalized(value) * bar_width as f32).round() as usize;
+                let style = if idx == self.selected {
+                    Style::default().add_modifier(Modifier::REVERSED)
+                } else {
+                    Style::default()
+                };
+                Line::from(vec![
+                    Span::styled(format!("{:<16}", parameter.name()), style),
+                    Span::raw(" "),
+                    Span::styled("█".repeat(filled), Style::default().fg(Color::Cyan)),
+                    Span::styled(
+                        "·".repeat(bar_width.saturating_sub(filled)),
+                        Style::default().fg(Color::DarkGray),
+                    ),
+                    Span::raw(format!(" {:>12}", format_value(*parameter, value))),
+                ])
+            })
+            .collect::<Vec<Line>>();
+        frame.render_widget(Paragraph::new(lines), inner);
+    }
+
+    fn draw_scope(&self, frame: &mut Frame, area: Rect) {
+        let points = self
+            .status
+            .scope()
+            .into_iter()
+            .map(|(x, y)| (x as f64, y as f64))
+            .collect::<Vec<(f64, f64)>>();
+        let canvas = Canvas::default()
+            .block(Block::default().borders(Borders::ALL).title("Output"))
+            .marker(Marker::Braille)
+            .x_bounds([-1.0, 1.0])
+            .y_bounds([-1.0, 1.0])
+            .paint(|ctx| {
+                ctx.draw(&Points {
+                    coords: &points,
+                    color: Color::Green,
+                })
+            });
+        frame.render_widget(canvas, area);
+    }
+
+    fn draw_meters(&self, frame: &mut Frame, area: Rect) {
+        let block = Block::default().borders(Borders::ALL).title("Level");
+        let inner = block.inner(area);
+        frame.render_widget(block, area);
+
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Length(1), Constraint::Length(1)])
+            .split(inner);
+        for (idx, (row, name)) in rows.iter().zip(&["L", "R"]).enumerate() {
+            let peak = self.status.peak[idx].load();
+            let db = (20.0 * peak.max(1e-6).log10()).max(METER_FLOOR_DB);
+            let color = if peak >= 1.0 {
+                Color::Red
+            } else {
+                Color::Green
+            };
+            let gauge = Gauge::default()
+                .gauge_style(Style::default().fg(color))
+                .ratio((1.0 - db / METER_FLOOR_DB).clamp(0.0, 1.0) as f64)
+                .label(format!("{} {:.1} dB", name, db));
+            frame.render_widget(gauge, *row);
+        }
+    }
+
+    fn draw_voices(&self, frame: &mut Frame, area: Rect) {
+        let title = format!(
+            "Voices {}  {:.1} bpm{}  load {:.0}%",
+            self.status.active_voices.load(Ordering::Relaxed),
+            self.status.tempo.load(),
+            if self.status.external_clock.load(Ordering::Relaxed) {
+                " (clock)"
+            } else {
+                ""
+            },
+            self.status.load.load() * 100.0
+        );
+        let lines = self
+            .status
+            .voices
+            .iter()
+            .filter(|voice| voice.stage() != Stage::Off)
+            .map(|voice| {
+                let note = Note::try_from(voice.note.load(Ordering::Relaxed))
+                    .map(Note::to_str)
+                    .unwrap_or("?");
+                let level = voice.level.load();
+                Line::from(format!(
+                    "{:<4} {:<8} {}",
+                    note,
+                    voice.stage().name(),
+                    "▮".repeat((level * 10.0).round() as usize)
+                ))
+            })
+            .collect::<Vec<Line>>();
+        let voices =
+            Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title(title));
+        frame.render_widget(voices, area);
+    }
+}
+
+fn format_value(parameter: Parameter, value: f32) -> String {
+    if let Some(choices) = parameter.choices() {
+        return choices.get(value as usize).unwrap_or(&"?").to_string();
+    }
+    match parameter {
+        Parameter::Cutoff => format!("{:.0} Hz", value),
+        Parameter::ChorusRate => format!("{:.2} Hz", value),
+        Parameter::Tempo => format!("{:.1} bpm", value),
+        Parameter::Attack
+        | Parameter::Decay
+        | Parameter::Release
+        | Parameter::FilterAttack
+        | Parameter::FilterDecay
+        | Parameter::FilterRelease
+        | Parameter::DelayTime => format!("{:.3} s", value),
+        Parameter::ModRate => format!("{:.2} Hz", value),
+        Parameter::BendRange => format!("{:.1} st", value),
+        // Only the exact divisions come out readable
+        Parameter::ArpRate => match format_note_division(value) {
+            division if division.starts_with("1/") => division,
+            _ => format!("{:.2} beats", value),
+        },
+        _ if parameter.is_integer() => format!("{}", value),
+        _ => format!("{:.3}", value),
+    }
+}
+
+/// Where presets go unless someone types somewhere else
+fn preset_dir() -> String {
+    match shapes::util::config_dir() {
+        Some(dir) => format!("{}/", dir.join("presets").display()),
+        None => String::new(),
+    }
+}
+
+/// Puts the terminal back however we leave
+struct TerminalGuard {
+    terminal: Terminal<CrosstermBackend<Stdout>>,
+    log_level: log::LevelFilter,
+}
+
+impl TerminalGuard {
+    fn new() -> Result<Self> {
+        let mut stdout = std::io::stdout();
+        terminal::enable_raw_mode()?;
+        crossterm::execute!(stdout, EnterAlternateScreen)?;
+        // Log lines would get drawn over the top of everything
+        let log_level = log::max_level();
+        log::set_max_level(log::LevelFilter::Off);
+        Ok(Self {
+            terminal: Terminal::new(CrosstermBackend::new(stdout))?,
+            log_level,
+        })
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = terminal::disable_raw_mode();
+        let _ = crossterm::execute!(self.terminal.backend_mut(), LeaveAlternateScreen);
+        let _ = self.terminal.show_cursor();
+        log::set_max_level(self.log_level);
+    }
+}
+
+/// Show the parameters and what's playing until someone quits or `should_stop` says to.
+/// `options` is what the current patch was made from
+pub fn run(
+    sender: &Sender,
+    status: &Status,
+    midi_map: &Mutex<MidiMap>,
+    options: Vec<String>,
+    mut should_stop: impl FnMut() -> bool,
+) -> Result<()> {
+    let mut guard = TerminalGuard::new()?;
+    let mut tui = Tui {
+        sender,
+        status,
+        midi_map,
+        options,
+        selected: 0,
+        prompt: None,
+        message: String::new(),
+    };
+
+    while !should_stop() {
+        guard.terminal.draw(|frame| tui.draw(frame))?;
+        if !event::poll(FRAME_INTERVAL)? {
+            continue;
+        }
+        if let Event::Key(key) = event::read()? {
+            if key.kind == KeyEventKind::Press && !tui.key(key) {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,441 @@
+use anyhow::{anyhow, Context, Result};
+use std::convert::TryFrom;
+use std::path::Path;
+use std::str::FromStr;
+use wmidi::Note;
+
+/// A Scala scale, the pitches of every degree after the first in cents.
+/// The last one is the period the scale repeats at, usually the octave
+#[derive(Debug, Clone)]
+pub struct Scale {
+    pub description: String,
+    pub cents: Vec<f32>,
+}
+
+impl Scale {
+    pub fn equal_temperament() -> Self {
+        Self {
+            description: String::from("12 tone equal temperament"),
+            cents: (1..=12).map(|degree| degree as f32 * 100.0).collect(),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Couldn't read scale file {}", path.display()))?;
+        Self::from_str(&contents).with_context(|| format!("Invalid scale file {}", path.display()))
+    }
+
+    /// Cents of any degree, including ones outside the first period
+    fn degree_cents(&self, degree: i32) -> f32 {
+        let size = self.cents.len() as i32;
+        let period = self.cents[self.cents.len() - 1];
+        let within = degree.rem_euclid(size);
+        let periods = degree.div_euclid(size);
+        let cents = if within == 0 {
+            0.0
+        } else {
+            self.cents[within as usize - 1]
+        };
+        periods as f32 * period + cents
+    }
+}
+
+impl FromStr for Scale {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Scale> {
+        let mut lines = s.lines().filter(|line| !line.starts_with('!'));
+
+        let description = lines
+            .next()
+            .ok_or(anyhow!("Missing description"))?
+            .trim()
+            .to_string();
+        let count = lines
+            .next()
+            .ok_or(anyhow!("Missing note count"))?
+            .trim()
+            .parse::<usize>()
+            .context("Invalid note count")?;
+
+        let cents = lines
+            .take(count)
+            .map(|line| parse_pitch(line.trim()))
+            .collect::<Result<Vec<f32>>>()?;
+
+        if cents.len() != count || count == 0 {
+            return Err(anyhow!("Expected {} pitches, found {}", count, cents.len()));
+        }
+
+        Ok(Scale { description, cents })
+    }
+}
+
+/// Anything with a period is in cents, otherwise it's a ratio like "3/2" or "2".
+/// Whatever comes after the first whitespace is ignored
+fn parse_pitch(line: &str) -> Result<f32> {
+    let pitch = line.split_whitespace().next().unwrap_or("");
+    if pitch.contains('.') {
+        return f32::from_str(pitch).with_context(|| format!("Invalid pitch \"{}\"", pitch));
+    }
+
+    let (num, denom) = match pitch.split_once('/') {
+        Some((num, denom)) => (f32::from_str(num)?, f32::from_str(denom)?),
+        None => (f32::from_str(pitch)?, 1.0),
+    };
+    if num <= 0.0 || denom <= 0.0 {
+        return Err(anyhow!("Invalid ratio \"{}\"", pitch));
+    }
+    Ok(1200.0 * f32::log2(num / denom))
+}
+
+/// A Scala keyboard mapping
+#[derive(Debug, Clone)]
+pub struct KeyboardMapping {
+    pub first_note: u8,
+    pub last_note: u8,
+    /// The note that gets scale degree 0
+    pub middle_note: u8,
+    pub reference_note: u8,
+    pub reference_freq: f32,
+    /// Scale degree to use as the period of the mapping
+    pub octave_degree: usize,
+    /// Scale degree for each key in the pattern, None for keys that don't sound.
+    /// Empty means every key gets the next degree
+    pub map: Vec<Option<usize>>,
+}
+
+impl KeyboardMapping {
+    /// Keys in order, middle C is degree 0 and A above it is at `reference_freq`
+    pub fn linear(reference_freq: f32) -> Self {
+        Self {
+            first_note: 0,
+            last_note: 127,
+            middle_note: 60,
+            reference_note: 69,
+            reference_freq,
+            octave_degree: 0,
+            map: Vec::new(),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Couldn't read keyboard mapping {}", path.display()))?;
+        Self::from_str(&contents)
+            .with_context(|| format!("Invalid keyboard mapping {}", path.display()))
+    }
+
+    /// The scale degree of a key, or None if it isn't mapped
+    fn degree(&self, key: u8, scale: &Scale) -> Option<i32> {
+        if key < self.first_note || key > self.last_note {
+            return None;
+        }
+
+        let offset = key as i32 - self.middle_note as i32;
+        if self.map.is_empty() {
+            return Some(offset);
+        }
+
+        let size = self.map.len() as i32;
+        let octave_degree = match self.octave_degree {
+            0 => scale.cents.len(),
+            degree => degree,
+        } as i32;
+        let degree = self.map[offset.rem_euclid(size) as usize]?;
+        Some(degree as i32 + offset.div_euclid(size) * octave_degree)
+    }
+}
+
+impl FromStr for KeyboardMapping {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<KeyboardMapping> {
+        let mut lines = s
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.starts_with('!') && !line.is_empty());
+        let mut next = |name: &str| {
+            lines
+                .next()
+                .map(|line| line.split_whitespace().next().unwrap_or(""))
+                .ok_or(anyhow!("Missing {}", name))
+        };
+
+        let size = usize::from_str(next("map size")?)?;
+        let first_note = u8::from_str(next("first note")?)?;
+        let last_note = u8::from_str(next("last note")?)?;
+        let middle_note = u8::from_str(next("middle note")?)?;
+        let reference_note = u8::from_str(next("reference note")?)?;
+        let reference_freq = f32::from_str(next("reference frequency")?)?;
+        let octave_degree = usize::from_str(next("octave degree")?)?;
+
+        let mut map = Vec::new();
+        for _ in 0..size {
+            // The map is allowed to be shorter than the size, the rest is unmapped
+            let entry = next("mapping").unwrap_or("x");
+            map.push(match entry {
+                "x" | "X" => None,
+                degree => Some(usize::from_str(degree)?),
+            });
+        }
+
+        Ok(KeyboardMapping {
+            first_note,
+            last_note,
+            middle_note,
+            reference_note,
+            reference_freq,
+            octave_degree,
+            map,
+        })
+    }
+}
+
+/// Frequency of every MIDI note, 0 for notes that aren't mapped
+#[derive(Debug, Clone)]
+pub struct Tuning {
+    /// Before transposing
+    table: [f32; 128],
+    /// Every key plays the note this many keys up
+    pub transpose: i32,
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Self::new(
+            &Scale::equal_temperament(),
+            &KeyboardMapping::linear(440.0),
+            0,
+        )
+    }
+}
+
+impl Tuning {
+    /// `transpose` shifts every key up by that many keys
+    pub fn new(scale: &Scale, mapping: &KeyboardMapping, transpose: i32) -> Self {
+        let reference = mapping
+            .degree(mapping.reference_note, scale)
+            .unwrap_or(mapping.reference_note as i32 - mapping.middle_note as i32);
+        let reference_cents = scale.degree_cents(reference);
+
+        let mut table = [0.0; 128];
+        for (key, freq) in table.iter_mut().enumerate() {
+            if let Some(degree) = mapping.degree(key as u8, scale) {
+                let cents = scale.degree_cents(degree) - reference_cents;
+                *freq = mapping.reference_freq * f32::powf(2.0, cents / 1200.0);
+            }
+        }
+
+        Self { table, transpose }
+    }
+
+    /// Load Scala files, anything left out defaults to 12-TET and a linear mapping.
+    /// `reference_pitch` overrides the reference frequency of the mapping
+    pub fn load(
+        scl: Option<&Path>,
+        kbm: Option<&Path>,
+        reference_pitch: Option<f32>,
+        transpose: i32,
+    ) -> Result<Self> {
+        let scale = match scl {
+            Some(path) => Scale::load(path)?,
+            None => Scale::equal_temperament(),
+        };
+        let mut mapping = match kbm {
+            Some(path) => KeyboardMapping::load(path)?,
+            None => KeyboardMapping::linear(440.0),
+        };
+        if let Some(freq) = reference_pitch {
+            mapping.reference_freq = freq;
+        }
+
+        log::debug!("Using scale \"{}\"", scale.description);
+        Ok(Self::new(&scale, &mapping, transpose))
+    }
+
+    /// Where a key is in the table once it's transposed, None if that's off the end
+    fn key(&self, note: Note) -> Option<usize> {
+        let key = u8::from(note) as i32 + self.transpose;
+        if (0..128).contains(&key) {
+            Some(key as usize)
+        } else {
+            None
+        }
+    }
+
+    pub fn freq(&self, note: Note) -> f32 {
+        self.key(note).map(|key| self.table[key]).unwrap_or(0.0)
+    }
+
+    pub fn is_mapped(&self, note: Note) -> bool {
+        self.freq(note) > 0.0
+    }
+
+    /// What `note` plays from now on, at the current transpose
+    pub fn retune(&mut self, note: Note, freq: f32) {
+        if let Some(key) = self.key(note) {
+            self.table[key] = freq;
+        }
+    }
+}
+
+/// Note retunings from a MIDI Tuning Standard SysEx message: bulk dumps and single note tuning
+/// changes, with or without a bank. `data` is everything between F0 and F7
+pub fn parse_mts(data: &[u8]) -> Option<Vec<(Note, f32)>> {
+    // universal non-realtime (7E) or realtime (7F), device id, MIDI tuning (08), sub-id
+    let (sub_id, rest) = match data {
+        [0x7E, _device, 0x08, sub_id, rest @ ..] | [0x7F, _device, 0x08, sub_id, rest @ ..] => {
+            (*sub_id, rest)
+        }
+        _ => return None,
+    };
+
+    match sub_id {
+        // Bulk dump: program, 16 byte name, 128 tunings, checksum
+        0x01 => {
+            let tunings = rest.get(17..17 + 128 * 3)?;
+            Some(
+                tunings
+                    .chunks(3)
+                    .enumerate()
+                    .filter_map(|(key, tuning)| {
+                        let note = Note::try_from(key as u8).ok()?;
+                        Some((note, mts_freq(tuning)?))
+                    })
+                    .collect(),
+            )
+        }
+        // Single note tuning change: program, count, then key and tuning for each
+        0x02 => parse_note_changes(rest.get(1..)?),
+        // Single note tuning change with bank: bank, program, count, ...
+        0x07 => parse_note_changes(rest.get(2..)?),
+        _ => None,
+    }
+}
+
+fn parse_note_changes(data: &[u8]) -> Option<Vec<(Note, f32)>> {
+    let (count, changes) = data.split_first()?;
+    Some(
+        changes
+            .chunks_exact(4)
+            .take(*count as usize)
+            .filter_map(|change| {
+                let note = Note::try_from(change[0]).ok()?;
+                Some((note, mts_freq(&change[1..])?))
+            })
+            .collect(),
+    )
+}
+
+/// Semitone, then 14 bits of fractions of a semitone. 7F 7F 7F means leave the note alone
+fn mts_freq(tuning: &[u8]) -> Option<f32> {
+    match tuning {
+        [0x7F, 0x7F, 0x7F] => None,
+        [semitone, msb, lsb] => {
+            let fraction = ((*msb as u32) << 7 | *lsb as u32) as f32 / 16384.0;
+            let semitones = *semitone as f32 + fraction;
+            Some(440.0 * f32::powf(2.0, (semitones - 69.0) / 12.0))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 0.01,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn cents_and_ratios() {
+        let scale = Scale::from_str(
+            "Just major triad and a cents step\n\
+             4\n\
+             193.157\n\
+             5/4 major third\n\
+             3/2\n\
+             2\n",
+        )
+        .unwrap();
+        assert_eq!(scale.description, "Just major triad and a cents step");
+        assert_close(scale.cents[0], 193.157);
+        assert_close(scale.cents[1], 386.314);
+        assert_close(scale.cents[2], 701.955);
+        assert_close(scale.cents[3], 1200.0);
+    }
+
+    #[test]
+    fn comments() {
+        let scale = Scale::from_str(
+            "! pentatonic.scl\n\
+             !\n\
+             Pentatonic\n\
+             ! the count\n\
+             5\n\
+             !\n\
+             200.0\n\
+             400.0\n\
+             ! a comment between pitches\n\
+             700.0\n\
+             900.0\n\
+             2/1\n",
+        )
+        .unwrap();
+        assert_eq!(scale.description, "Pentatonic");
+        assert_eq!(scale.cents.len(), 5);
+        assert_close(scale.cents[2], 700.0);
+        assert!(Scale::from_str("Too short\n3\n100.0\n200.0\n").is_err());
+    }
+
+    #[test]
+    fn unmapped_keys() {
+        // Only the white keys sound, the same pitches as usual
+        let mapping = KeyboardMapping::from_str(
+            "! white.kbm\n\
+             12\n0\n127\n60\n69\n440.0\n12\n\
+             0\nx\n2\nx\n4\n5\nx\n7\nx\n9\nx\n11\n",
+        )
+        .unwrap();
+        assert_eq!(mapping.map[1], None);
+        let tuning = Tuning::new(&Scale::equal_temperament(), &mapping, 0);
+        assert!(!tuning.is_mapped(Note::Db4));
+        assert!(!tuning.is_mapped(Note::Bb3));
+        assert_close(tuning.freq(Note::A4), 440.0);
+        assert_close(tuning.freq(Note::C4), 261.626);
+        assert_close(tuning.freq(Note::C5), 523.251);
+    }
+
+    #[test]
+    fn transpose() {
+        let tuning = Tuning::new(
+            &Scale::equal_temperament(),
+            &KeyboardMapping::linear(440.0),
+            12,
+        );
+        assert_close(tuning.freq(Note::A4), 880.0);
+        assert!(!tuning.is_mapped(Note::G9));
+    }
+
+    #[test]
+    fn single_note_retune() {
+        // Realtime, all devices, one change: key 60 to 61 and a half semitones
+        let data = [0x7F, 0x7F, 0x08, 0x02, 0x00, 0x01, 60, 61, 0x40, 0x00];
+        let retunings = parse_mts(&data).unwrap();
+        assert_eq!(retunings.len(), 1);
+        assert_eq!(retunings[0].0, Note::C4);
+        assert_close(retunings[0].1, 440.0 * f32::powf(2.0, (61.5 - 69.0) / 12.0));
+
+        // 7F 7F 7F leaves the note alone
+        let data = [0x7F, 0x7F, 0x08, 0x02, 0x00, 0x01, 60, 0x7F, 0x7F, 0x7F];
+        assert!(parse_mts(&data).unwrap().is_empty());
+        // Not a tuning message at all
+        assert!(parse_mts(&[0x7E, 0x7F, 0x06, 0x01]).is_none());
+    }
+}
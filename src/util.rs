@@ -1,3 +1,8 @@
+use anyhow::{anyhow, Result};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
 pub struct SampleTimer {
     sample: u64,
     samplerate: u32,
@@ -40,3 +45,107 @@ impl std::ops::AddAssign<u64> for SampleTimer {
         self.inc(amt)
     }
 }
+
+/// Length in beats of a note division like "1/4", "1/8d" (dotted) or "1/16t" (triplet).
+/// None if it isn't a division at all
+pub fn parse_note_division(s: &str) -> Result<Option<f32>> {
+    let (division, modifier) = match s.strip_suffix('d') {
+        Some(division) => (division, 1.5),
+        None => match s.strip_suffix('t') {
+            Some(division) => (division, 2.0 / 3.0),
+            None => (s, 1.0),
+        },
+    };
+
+    match division.split_once('/') {
+        Some((num, denom)) => {
+            let num = f32::from_str(num)?;
+            let denom = f32::from_str(denom)?;
+            if num <= 0.0 || denom <= 0.0 {
+                return Err(anyhow!("Invalid note division \"{}\"", s));
+            }
+            // a whole note is four beats
+            Ok(Some(4.0 * num / denom * modifier))
+        }
+        None => Ok(None),
+    }
+}
+
+/// The other way around from `parse_note_division`, e.g. "1/8d" for 0.75 beats
+pub fn format_note_division(beats: f32) -> String {
+    let whole = |modifier: f32| {
+        let denom = 4.0 * modifier / beats;
+        if denom >= 1.0 && (denom - denom.round()).abs() < 1e-3 {
+            Some(denom.round() as u32)
+        } else {
+            None
+        }
+    };
+    for (suffix, modifier) in &[("", 1.0), ("d", 1.5), ("t", 2.0 / 3.0)] {
+        match whole(*modifier) {
+            Some(denom) if denom.is_power_of_two() => return format!("1/{}{}", denom, suffix),
+            _ => {}
+        }
+    }
+    match whole(1.0) {
+        Some(denom) => format!("1/{}", denom),
+        None => format!("{}/4", beats),
+    }
+}
+
+/// Xorshift random numbers, for the audio thread. `rand`'s thread local generator allocates
+/// the first time it's used on a thread and reseeds itself from the OS
+pub struct Rng(u32);
+
+impl Rng {
+    pub fn new(seed: u32) -> Self {
+        // All zeros would stay that way
+        Self(seed.max(1))
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        x
+    }
+
+    /// -1 to 1
+    pub fn bipolar(&mut self) -> f32 {
+        (self.next_u32() >> 8) as f32 / (1 << 23) as f32 - 1.0
+    }
+
+    /// 0 up to but not including `n`
+    pub fn below(&mut self, n: usize) -> usize {
+        self.next_u32() as usize % n
+    }
+}
+
+/// An f32 that can be shared between threads, stored as its bits
+#[derive(Default)]
+pub struct AtomicF32(AtomicU32);
+
+impl AtomicF32 {
+    pub fn new(value: f32) -> Self {
+        Self(AtomicU32::new(value.to_bits()))
+    }
+
+    pub fn load(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    pub fn store(&self, value: f32) {
+        self.0.store(value.to_bits(), Ordering::Relaxed)
+    }
+}
+
+/// $XDG_CONFIG_HOME/shapes, or under ~/.config if that isn't set
+pub fn config_dir() -> Option<PathBuf> {
+    let config = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(config.join(env!("CARGO_PKG_NAME")))
+}
@@ -0,0 +1,295 @@
+use crate::maths::vec3::{self, Vec3};
+use crate::synthesis::Shape;
+use crate::vec2::Vec2;
+
+use core::f32::consts::PI;
+use std::collections::VecDeque;
+
+/// The edges of a solid traced as one closed path, visiting every edge at least once
+pub struct Wireframe {
+    vertices: Vec<Vec3>,
+    path: Vec<usize>,
+}
+
+impl Wireframe {
+    pub fn tetrahedron() -> Self {
+        Self::from_vertices(vec![
+            (1.0, 1.0, 1.0),
+            (1.0, -1.0, -1.0),
+            (-1.0, 1.0, -1.0),
+            (-1.0, -1.0, 1.0),
+        ])
+    }
+
+    pub fn cube() -> Self {
+        let mut vertices = Vec::new();
+        for &x in &[-1.0, 1.0] {
+            for &y in &[-1.0, 1.0] {
+                for &z in &[-1.0, 1.0] {
+                    vertices.push((x, y, z));
+                }
+            }
+        }
+        Self::from_vertices(vertices)
+    }
+
+    pub fn octahedron() -> Self {
+        Self::from_vertices(vec![
+            (1.0, 0.0, 0.0),
+            (-1.0, 0.0, 0.0),
+            (0.0, 1.0, 0.0),
+            (0.0, -1.0, 0.0),
+            (0.0, 0.0, 1.0),
+            (0.0, 0.0, -1.0),
+        ])
+    }
+
+    pub fn icosahedron() -> Self {
+        let phi = (1.0 + 5.0f32.sqrt()) / 2.0;
+        let mut vertices = Vec::new();
+        for &a in &[-1.0, 1.0] {
+            for &b in &[-phi, phi] {
+                vertices.push((0.0, a, b));
+                vertices.push((a, b, 0.0));
+                vertices.push((b, 0.0, a));
+            }
+        }
+        Self::from_vertices(vertices)
+    }
+
+    /// For the regular solids every edge is the same length and no two vertices are closer
+    /// than that, so the edges can just be found from the distances
+    fn from_vertices(vertices: Vec<Vec3>) -> Self {
+        let radius = vertices
+            .iter()
+            .map(|v| vec3::length(*v))
+            .fold(0.0, f32::max);
+        let vertices = vertices
+            .into_iter()
+            .map(|v| vec3::scale(v, 1.0 / radius))
+            .collect::<Vec<Vec3>>();
+
+        let distance = |a: usize, b: usize| vec3::length(vec3::sub(vertices[a], vertices[b]));
+        let mut edge_length = f32::MAX;
+        for a in 0..vertices.len() {
+            for b in (a + 1)..vertices.len() {
+                edge_length = edge_length.min(distance(a, b));
+            }
+        }
+
+        let mut edges = Vec::new();
+        for a in 0..vertices.len() {
+            for b in (a + 1)..vertices.len() {
+                if distance(a, b) < edge_length * 1.01 {
+                    edges.push((a, b));
+                }
+            }
+        }
+
+        let path = trace(vertices.len(), &edges);
+        Self { vertices, path }
+    }
+
+    /// Position along the path for a phase from 0 to 1, every edge takes the same time
+    pub fn point(&self, p: f32) -> Vec3 {
+        let steps = p.rem_euclid(1.0) * self.path.len() as f32;
+        let current = (steps.floor() as usize).min(self.path.len() - 1);
+        let next = (current + 1) % self.path.len();
+        vec3::lerp(
+            self.vertices[self.path[current]],
+            self.vertices[self.path[next]],
+            steps - current as f32,
+        )
+    }
+}
+
+/// Walk the edges, taking an unused edge wherever possible and otherwise going back over used
+/// ones to the nearest vertex that still has unused edges. Ends back at the starting vertex
+fn trace(vertex_count: usize, edges: &[(usize, usize)]) -> Vec<usize> {
+    let mut used = vec![false; edges.len()];
+    let mut path = vec![0];
+    let mut current = 0;
+
+    loop {
+        let unused = edges
+            .iter()
+            .enumerate()
+            .find(|(idx, (a, b))| !used[*idx] && (*a == current || *b == current));
+
+        match unused {
+            Some((idx, &(a, b))) => {
+                used[idx] = true;
+                current = if a == current { b } else { a };
+                path.push(current);
+            }
+            None => {
+                let has_unused = |v: usize| {
+                    edges
+                        .iter()
+                        .enumerate()
+                        .any(|(idx, (a, b))| !used[idx] && (*a == v || *b == v))
+                };
+                let route = match shortest_route(vertex_count, edges, current, has_unused) {
+                    Some(route) => route,
+                    None => break,
+                };
+                path.extend(route);
+                current = *path.last().unwrap();
+            }
+        }
+    }
+
+    if let Some(route) = shortest_route(vertex_count, edges, current, |v| v == 0) {
+        path.extend(route);
+    }
+    // The path is closed, so the start doesn't need to be in there twice
+    if path.len() > 1 && path.last() == Some(&0) {
+        path.pop();
+    }
+    path
+}
+
+/// Breadth first search for the closest vertex matching `target`, returns the vertices along
+/// the way not including `from`
+fn shortest_route(
+    vertex_count: usize,
+    edges: &[(usize, usize)],
+    from: usize,
+    target: impl Fn(usize) -> bool,
+) -> Option<Vec<usize>> {
+    let mut previous = vec![None; vertex_count];
+    let mut queue = VecDeque::new();
+    previous[from] = Some(from);
+    queue.push_back(from);
+
+    while let Some(v) = queue.pop_front() {
+        if v != from && target(v) {
+            let mut route = vec![v];
+            let mut step = v;
+            while let Some(prev) = previous[step] {
+                if prev == from {
+                    break;
+                }
+                route.push(prev);
+                step = prev;
+            }
+            route.reverse();
+            return Some(route);
+        }
+        for &(a, b) in edges {
+            let neighbour = if a == v {
+                b
+            } else if b == v {
+                a
+            } else {
+                continue;
+            };
+            if previous[neighbour].is_none() {
+                previous[neighbour] = Some(v);
+                queue.push_back(neighbour);
+            }
+        }
+    }
+
+    None
+}
+
+/// Parametric (p, q) torus knot, p windings around the axis and q through the hole
+pub fn torus_knot(p: f32, q: f32, t: f32) -> Vec3 {
+    let phi = 2.0 * PI * t;
+    let r = f32::cos(q * phi) + 2.0;
+    // Scaled down to fit in the unit sphere
+    vec3::scale(
+        (
+            r * f32::cos(p * phi),
+            r * f32::sin(p * phi),
+            -f32::sin(q * phi),
+        ),
+        1.0 / 3.0,
+    )
+}
+
+/// Every 3D shape, built up front so the shape can change while playing
+pub struct Solids {
+    tetrahedron: Wireframe,
+    cube: Wireframe,
+    octahedron: Wireframe,
+    icosahedron: Wireframe,
+}
+
+impl Solids {
+    pub fn new() -> Self {
+        Self {
+            tetrahedron: Wireframe::tetrahedron(),
+            cube: Wireframe::cube(),
+            octahedron: Wireframe::octahedron(),
+            icosahedron: Wireframe::icosahedron(),
+        }
+    }
+
+    /// None for the flat shapes
+    pub fn point(&self, shape: Shape, knot: Vec2, p: f32) -> Option<Vec3> {
+        match shape {
+            Shape::Tetrahedron => Some(self.tetrahedron.point(p)),
+            Shape::Cube => Some(self.cube.point(p)),
+            Shape::Octahedron => Some(self.octahedron.point(p)),
+            Shape::Icosahedron => Some(self.icosahedron.point(p)),
+            Shape::TorusKnot => Some(torus_knot(knot.0, knot.1, p)),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Solids {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Rotation of a solid accumulated from per-axis speeds
+pub struct Spin {
+    angles: Vec3,
+}
+
+impl Spin {
+    pub fn new() -> Self {
+        Self {
+            angles: (0.0, 0.0, 0.0),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.angles = (0.0, 0.0, 0.0);
+    }
+
+    /// Rotate `v` by the current angles then advance by one sample. Speeds in turns per second
+    pub fn process(&mut self, v: Vec3, speeds: Vec3, samplerate: f32) -> Vec3 {
+        let rotated = vec3::rotate(v, vec3::scale(self.angles, 2.0 * PI));
+        let advance = |angle: f32, speed: f32| (angle + speed / samplerate).rem_euclid(1.0);
+        self.angles = (
+            advance(self.angles.0, speeds.0),
+            advance(self.angles.1, speeds.1),
+            advance(self.angles.2, speeds.2),
+        );
+        rotated
+    }
+}
+
+impl Default for Spin {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Perspective projection looking down the z axis from `distance` away, in units of the
+/// solid's radius. 0 gives an orthographic projection
+pub fn project(v: Vec3, distance: f32) -> Vec2 {
+    if distance <= 0.0 {
+        return (v.0, v.1);
+    }
+    // Keep the camera well outside the solid, the near side gets huge otherwise
+    let distance = distance.max(1.5);
+    // Points on the z = 0 plane keep their size
+    let scale = distance / (distance + v.2);
+    (v.0 * scale, v.1 * scale)
+}
@@ -0,0 +1,342 @@
+//! The audio thread can't allocate, free, lock or log, any of those can make it wait on
+//! another thread and miss its deadline. These play the synth through scripted MIDI the same
+//! way the program and the plugins do, with an allocator and a logger that count what happens
+//! on the audio thread, and fail if anything does once it's warmed up. Waiting on a lock
+//! shows up as a voluntary context switch, so those are counted too.
+
+use shapes::channel;
+use shapes::engine::{self, Config, Message, Synth};
+use shapes::parameter::PARAMETERS;
+use shapes::patch::Patch;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const SAMPLE_RATE: u32 = 48000;
+const BUFFER_SIZE: usize = 256;
+/// Buffers to play before watching, so everything has been through once
+const WARM_UP: usize = 64;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static FREES: AtomicUsize = AtomicUsize::new(0);
+static LOG_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// Only the thread playing the synth is watched, tests run alongside each other
+    static WATCHING: Cell<bool> = const { Cell::new(false) };
+}
+
+fn watching() -> bool {
+    WATCHING.try_with(Cell::get).unwrap_or(false)
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if watching() {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if watching() {
+            FREES.fetch_add(1, Ordering::Relaxed);
+        }
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if watching() {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Real loggers lock and format, this one just counts
+struct CountingLogger;
+
+impl log::Log for CountingLogger {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, _record: &log::Record) {
+        if watching() {
+            LOG_CALLS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: CountingLogger = CountingLogger;
+
+/// Times this thread has given up the CPU to wait for something
+fn voluntary_switches() -> i64 {
+    let mut usage = unsafe { std::mem::zeroed::<libc::rusage>() };
+    unsafe { libc::getrusage(libc::RUSAGE_THREAD, &mut usage) };
+    usage.ru_nvcsw
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Report {
+    allocations: usize,
+    frees: usize,
+    log_calls: usize,
+    switches: i64,
+}
+
+/// Everything `f` does on this thread that an audio thread shouldn't
+fn watch(f: impl FnOnce()) -> Report {
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(log::LevelFilter::Trace);
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let frees = FREES.load(Ordering::Relaxed);
+    let log_calls = LOG_CALLS.load(Ordering::Relaxed);
+    let switches = voluntary_switches();
+    WATCHING.with(|watching| watching.set(true));
+    f();
+    WATCHING.with(|watching| watching.set(false));
+    // Other tests count too, but never while this thread is watching
+    Report {
+        allocations: ALLOCATIONS.load(Ordering::Relaxed) - allocations,
+        frees: FREES.load(Ordering::Relaxed) - frees,
+        log_calls: LOG_CALLS.load(Ordering::Relaxed) - log_calls,
+        switches: voluntary_switches() - switches,
+    }
+}
+
+/// What comes in during one buffer, MIDI at a frame offset or a message from another thread
+enum Event {
+    Midi(usize, Vec<u8>),
+    Sent(Message),
+}
+
+/// Microseconds each buffer lasts
+const BUFFER_MICROS: u64 = BUFFER_SIZE as u64 * 1_000_000 / SAMPLE_RATE as u64;
+
+/// Chords, more notes than there are voices, every controller the synth listens to and
+/// every parameter swept from one end to the other. MIDI clock for the first half, then
+/// long enough without it for the synth to give up and go back to its own. The patch gets
+/// loaded again halfway through
+fn script(options: &[String]) -> Vec<Vec<Event>> {
+    let mut buffers: Vec<Vec<Event>> = Vec::new();
+    for step in 0..256usize {
+        let mut events = Vec::new();
+        if step == 0 {
+            events.push(Event::Sent(Message::Start));
+        }
+        if step == 128 {
+            let patch = Patch::from_options(options).unwrap();
+            events.push(Event::Sent(Message::load_patch(patch)));
+        }
+        if step < 128 && step % 4 == 0 {
+            events.push(Event::Sent(Message::Clock(step as u64 * BUFFER_MICROS)));
+        }
+        let offset = (step * 37) % BUFFER_SIZE;
+        let note = 36 + (step * 7 % 48) as u8;
+        if step % 2 == 0 {
+            events.push(Event::Midi(offset, vec![0x90, note, 100]));
+            events.push(Event::Midi(offset, vec![0x90, note + 4, 90]));
+            events.push(Event::Midi(offset, vec![0x90, note + 7, 80]));
+        } else {
+            let previous = 36 + ((step - 1) * 7 % 48) as u8;
+            events.push(Event::Midi(offset, vec![0x80, previous, 0]));
+            // Note on with no velocity, which some keyboards send instead
+            events.push(Event::Midi(offset, vec![0x90, previous + 4, 0]));
+        }
+        let bend = (step * 64 % 16384) as u16;
+        events.push(Event::Midi(
+            BUFFER_SIZE - 1 - offset,
+            vec![0xe0, (bend & 0x7f) as u8, (bend >> 7) as u8],
+        ));
+        events.push(Event::Midi(offset / 2, vec![0xb0, 1, (step % 128) as u8]));
+        events.push(Event::Midi(offset / 3, vec![0xd0, (step * 3 % 128) as u8]));
+        events.push(Event::Midi(
+            offset / 4,
+            vec![0xa0, note, (step % 128) as u8],
+        ));
+
+        let parameter = step % PARAMETERS.len();
+        let value = (step / PARAMETERS.len()) as f32 / (255 / PARAMETERS.len()) as f32;
+        events.push(Event::Sent(Message::SetParameter(
+            PARAMETERS[parameter],
+            value,
+        )));
+        buffers.push(events);
+    }
+    let panic = vec![
+        Event::Midi(0, vec![0xb0, 123, 0]),
+        Event::Midi(10, vec![0xb0, 120, 0]),
+        Event::Midi(20, vec![0xb0, 121, 0]),
+    ];
+    buffers.push(panic);
+    buffers
+}
+
+/// Play through the script twice, watching the second time. Everything but the audio
+/// thread's part, like turning MIDI into messages and sending parameters, happens outside
+fn play(options: &[&str], planar: bool) -> Report {
+    let options = options
+        .iter()
+        .map(|s| s.to_string())
+        .collect::<Vec<String>>();
+    let patch = Patch::from_options(&options).unwrap();
+    let mut synth = Synth::new(Config {
+        sample_rate: SAMPLE_RATE,
+        voices: 8,
+        patch,
+    });
+    let (sender, receiver) = channel::channel();
+    let mut interleaved = vec![0.0f32; BUFFER_SIZE * 2];
+    let mut left = vec![0.0f32; BUFFER_SIZE];
+    let mut right = vec![0.0f32; BUFFER_SIZE];
+    let mut messages: Vec<(usize, Message)> = Vec::with_capacity(64);
+
+    // The same as the output stream's callback and a plugin's process
+    let mut render = |synth: &mut Synth, messages: &mut Vec<(usize, Message)>| {
+        receiver.receive(|message| synth.handle_event(message, 0));
+        for (offset, message) in messages.drain(..) {
+            synth.handle_event(message, offset);
+        }
+        if planar {
+            synth.process_planar(&mut left, &mut right);
+        } else {
+            synth.process(&mut interleaved);
+        }
+    };
+
+    let mut report = Report::default();
+    for watched in &[false, true] {
+        if !watched {
+            for _ in 0..WARM_UP {
+                render(&mut synth, &mut messages);
+            }
+        }
+        for events in script(&options) {
+            for event in events {
+                match event {
+                    Event::Midi(offset, bytes) => {
+                        if let Some(message) = engine::plugin_midi(&bytes) {
+                            messages.push((offset, message));
+                        }
+                    }
+                    Event::Sent(message) => sender.send(message).unwrap(),
+                }
+            }
+            if *watched {
+                let buffer = watch(|| render(&mut synth, &mut messages));
+                report.allocations += buffer.allocations;
+                report.frees += buffer.frees;
+                report.log_calls += buffer.log_calls;
+                report.switches += buffer.switches;
+            } else {
+                render(&mut synth, &mut messages);
+            }
+        }
+    }
+    report
+}
+
+#[test]
+fn default_patch_is_realtime_safe() {
+    assert_eq!(play(&[], false), Report::default());
+}
+
+#[test]
+fn planar_output_is_realtime_safe() {
+    assert_eq!(play(&[], true), Report::default());
+}
+
+#[test]
+fn busy_patch_is_realtime_safe() {
+    let options = [
+        "--shape=torus-knot",
+        "--spin=0.1:0.2:0",
+        "--filter=lowpass",
+        "--effects=chorus,delay,reverb",
+        "--mod-rate=3",
+        "--mod-amount=0.5",
+        "--lfo=sine,rate=1/8,scope=global",
+        "--mod=env:corners:2",
+        "--arp=up-down",
+        "--arp-octaves=2",
+        "--arp-latch",
+    ];
+    assert_eq!(play(&options, false), Report::default());
+}
+
+#[test]
+fn too_many_events_get_dropped() {
+    let mut synth = Synth::new(Config {
+        sample_rate: SAMPLE_RATE,
+        voices: 0,
+        patch: Patch::from_options(&[]).unwrap(),
+    });
+    let mut output = vec![0.0f32; BUFFER_SIZE * 2];
+    synth.process(&mut output);
+    let report = watch(|| {
+        for idx in 0..shapes::constants::MAX_EVENTS * 2 {
+            synth.handle_event(Message::ModWheel(0.5), idx % BUFFER_SIZE);
+        }
+        synth.process(&mut output);
+    });
+    assert_eq!(report, Report::default());
+}
+
+#[test]
+fn highest_note_bent_past_the_sample_rate() {
+    // 12.5 kHz bent up two octaves is 50 kHz
+    let options = vec![String::from("--bend-range=24")];
+    let mut synth = Synth::new(Config {
+        sample_rate: 44100,
+        voices: 1,
+        patch: Patch::from_options(&options).unwrap(),
+    });
+    let mut output = vec![0.0f32; BUFFER_SIZE * 2];
+    for bytes in &[[0xe0, 0x7f, 0x7f], [0x90, 127, 127]] {
+        synth.handle_event(engine::plugin_midi(bytes).unwrap(), 0);
+    }
+    synth.process(&mut output);
+    let report = watch(|| {
+        for _ in 0..16 {
+            synth.process(&mut output);
+        }
+    });
+    assert_eq!(report, Report::default());
+    assert!(output.iter().all(|sample| sample.is_finite()));
+    assert!(synth.status().active_voices.load(Ordering::Relaxed) == 1);
+}
+
+#[test]
+fn events_past_the_buffer_get_dropped_too() {
+    let mut synth = Synth::new(Config {
+        sample_rate: SAMPLE_RATE,
+        voices: 0,
+        patch: Patch::from_options(&[]).unwrap(),
+    });
+    let mut output = vec![0.0f32; BUFFER_SIZE * 2];
+    synth.process(&mut output);
+    // Each burst waits for the next buffer, where the one after it piles on at frame 0
+    let report = watch(|| {
+        for _ in 0..4 {
+            for idx in 0..shapes::constants::MAX_EVENTS - 1 {
+                synth.handle_event(Message::ModWheel(0.5), BUFFER_SIZE + idx % 4);
+            }
+            synth.process(&mut output);
+            for _ in 0..shapes::constants::MAX_EVENTS - 1 {
+                synth.handle_event(Message::ModWheel(0.5), 0);
+            }
+        }
+        synth.process(&mut output);
+    });
+    assert_eq!(report, Report::default());
+}